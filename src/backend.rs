@@ -0,0 +1,400 @@
+//! Packaging toolchains of the target distributions.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use walkdir::WalkDir;
+
+use crate::bloom::BloomConfig;
+use crate::config::LayerInstall;
+use crate::error::{categorize, BloomError};
+use crate::index::RepoLayout;
+use crate::manifest::Package;
+use crate::runner::{command_error, CommandRunner};
+
+pub(crate) mod deb;
+mod rpm;
+
+pub use deb::Deb;
+pub use rpm::Rpm;
+
+/// Install `pkgs` in order, at most `batch` per installer invocation.
+pub(crate) fn install_batched(
+    backend: &dyn Backend,
+    pkgs: &[PathBuf],
+    batch: usize,
+    with: LayerInstall,
+    prefix: &[&str],
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    // Source packages only get published
+    let pkgs = pkgs
+        .iter()
+        .filter(|p| !backend.is_source_artifact(p))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for chunk in pkgs.chunks(batch) {
+        match with {
+            LayerInstall::Dpkg => backend.install(chunk, prefix, runner),
+            LayerInstall::Apt => backend.install_resolving(chunk, prefix, runner),
+        }
+        .map_err(|e| {
+            categorize(
+                e,
+                BloomError::Install,
+                format!("Failed to install {} package(s)", chunk.len()),
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PackageFormat {
+    Deb,
+    Rpm,
+}
+
+impl std::str::FromStr for PackageFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "deb" => Ok(Self::Deb),
+            "rpm" => Ok(Self::Rpm),
+            _ => Err(anyhow!("Invalid package format '{s}'")),
+        }
+    }
+}
+
+impl PackageFormat {
+    pub(crate) fn backend(self) -> &'static dyn Backend {
+        match self {
+            Self::Deb => &Deb,
+            Self::Rpm => &Rpm,
+        }
+    }
+}
+
+/// Packaging toolchain of the target distribution.
+pub trait Backend: Sync + std::fmt::Debug {
+    /// bloom generator producing the packaging metadata.
+    fn generator(&self) -> &'static str;
+
+    /// Directory of the packaging metadata generated by bloom.
+    fn metadata_dir(&self) -> &'static str;
+
+    /// File extension of built packages.
+    fn extension(&self) -> &'static str;
+
+    /// File extensions of everything a build may produce, including debug symbol packages.
+    fn artifact_extensions(&self) -> &'static [&'static str];
+
+    /// Whether a built package only carries debug symbols.
+    fn is_debug(&self, pkg: &Path) -> bool;
+
+    /// Whether a built file belongs to a source package.
+    fn is_source_artifact(&self, _path: &Path) -> bool {
+        false
+    }
+
+    /// External command of the system package manager.
+    fn installer(&self) -> &'static str;
+
+    /// rosdep installer keys of packages the system package manager installs.
+    fn installer_keys(&self) -> &'static [&'static str];
+
+    /// External commands used to build and install packages.
+    fn tools(&self) -> &'static [&'static str];
+
+    /// External command generating the package index.
+    fn index_tool(&self) -> &'static str;
+
+    /// Path under `sources_dir` and contents of the file registering `repo` as a package source.
+    fn source_list(
+        &self,
+        sources_dir: &Path,
+        name: &str,
+        repo: &Path,
+        layout: RepoLayout,
+    ) -> (PathBuf, String);
+
+    /// Path under `sources_dir` and contents of the file pinning the packages of the generated
+    /// repos to `priority`, if the package manager supports pinning. Repos with a release file
+    /// are told apart by its origin.
+    fn preferences(
+        &self,
+        _sources_dir: &Path,
+        _priority: i32,
+        _with_release: bool,
+    ) -> Option<(PathBuf, String)> {
+        None
+    }
+
+    /// Paths under `sources_dir` and contents of the files registering the upstream snapshot at
+    /// `url` and pinning its packages to `priority`, if the package manager supports it.
+    fn snapshot_sources(
+        &self,
+        _sources_dir: &Path,
+        _url: &str,
+        _os_version: &str,
+        _priority: i32,
+    ) -> Option<Vec<(PathBuf, String)>> {
+        None
+    }
+
+    /// Refresh the package lists of the system package manager.
+    fn update(&self, prefix: &[&str], runner: &dyn CommandRunner) -> Result<()>;
+
+    /// Install the system dependencies resolved by `rosdep check`, along with the packages they
+    /// recommend if `recommends` is set.
+    fn install_system(
+        &self,
+        check: &str,
+        recommends: bool,
+        prefix: &[&str],
+        runner: &dyn CommandRunner,
+    ) -> Result<()>;
+
+    /// Adapt the packaging metadata generated in `build_dir` to build from `src`.
+    fn patch(
+        &self,
+        src: &Path,
+        build_dir: &Path,
+        package: &Package,
+        config: &BloomConfig,
+        log: &mut String,
+    ) -> Result<()>;
+
+    /// Build the patched packaging metadata in `build_dir`, returning the built packages.
+    fn build(
+        &self,
+        pkg: &str,
+        build_dir: &Path,
+        config: &BloomConfig,
+        log: &mut String,
+    ) -> Result<Vec<PathBuf>>;
+
+    /// Build a source package out of the patched packaging metadata in `build_dir`, returning
+    /// its files.
+    fn build_source(
+        &self,
+        pkg: &str,
+        _build_dir: &Path,
+        _config: &BloomConfig,
+        _log: &mut String,
+    ) -> Result<Vec<PathBuf>> {
+        Err(anyhow!(
+            "Cannot build a source package of {pkg} as {}",
+            self.extension()
+        ))
+    }
+
+    /// Install built packages.
+    fn install(&self, pkgs: &[PathBuf], prefix: &[&str], runner: &dyn CommandRunner) -> Result<()>;
+
+    /// Install built packages through the package manager, fetching their missing dependencies.
+    fn install_resolving(
+        &self,
+        pkgs: &[PathBuf],
+        prefix: &[&str],
+        runner: &dyn CommandRunner,
+    ) -> Result<()> {
+        self.install(pkgs, prefix, runner)
+    }
+
+    /// Generate the package index of `repo` for the packages in `packages`, along with a
+    /// compressed copy if `compress` is set.
+    fn index(
+        &self,
+        repo: &Path,
+        packages: &Path,
+        layout: RepoLayout,
+        compress: bool,
+        runner: &dyn CommandRunner,
+    ) -> Result<()>;
+
+    /// Generate the repository metadata describing the index, if the format needs one besides it.
+    fn release(
+        &self,
+        _repo: &Path,
+        _codename: &str,
+        _layout: RepoLayout,
+        _runner: &dyn CommandRunner,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Check that a built package would install from the configured sources.
+    fn verify(&self, pkg: &Path, prefix: &[&str], runner: &dyn CommandRunner) -> Result<()>;
+}
+
+/// All packages built under `dir`, sorted by path. Debug symbol packages are left out unless
+/// `debug` is set.
+pub(crate) fn find_artifacts(dir: &Path, backend: &dyn Backend, debug: bool) -> Vec<PathBuf> {
+    let mut found = WalkDir::new(dir)
+        .into_iter()
+        .flatten()
+        .filter(|e| e.file_type().is_file())
+        .map(|e| e.into_path())
+        .filter(|p| {
+            p.extension()
+                .and_then(OsStr::to_str)
+                .is_some_and(|e| backend.artifact_extensions().contains(&e))
+        })
+        .filter(|p| debug || !backend.is_debug(p))
+        .collect::<Vec<_>>();
+    found.sort();
+    found
+}
+
+pub(crate) fn file_name(path: &Path) -> Option<&str> {
+    path.file_name().and_then(OsStr::to_str)
+}
+
+/// Name of the binary package built out of a ROS package. Both bloom generators use the same scheme.
+pub(crate) fn deb_name(ros_distro: &str, pkg: &str) -> String {
+    format!("ros-{ros_distro}-{}", pkg.replace('_', "-"))
+}
+
+/// Name of the binary package built out of a ROS package, as given by `--name-map` if listed.
+pub(crate) fn mapped_deb_name(
+    ros_distro: &str,
+    pkg: &str,
+    name_map: &BTreeMap<&str, &str>,
+) -> String {
+    match name_map.get(pkg) {
+        Some(name) => name.to_string(),
+        None => deb_name(ros_distro, pkg),
+    }
+}
+
+/// Packages listed for any of the given installer keys in `rosdep check` output.
+fn installer_packages<'a>(check: &'a str, installers: &[&str]) -> Vec<&'a str> {
+    check
+        .lines()
+        .filter_map(|l| {
+            let (key, pkg) = l.trim().split_once(char::is_whitespace)?;
+            installers.contains(&key).then(|| pkg.trim())
+        })
+        .filter(|pkg| !pkg.is_empty())
+        .collect()
+}
+
+/// Errors reported by `rosdep check`, such as keys without a definition for the target.
+pub(crate) fn rosdep_problems(stderr: &str) -> Vec<&str> {
+    stderr
+        .lines()
+        .map(str::trim)
+        .filter(|l| l.starts_with("ERROR") || l.contains("Cannot locate rosdep definition"))
+        // Header of the list of unresolved keys
+        .filter(|l| !l.contains("could not have their rosdep keys resolved"))
+        .collect()
+}
+
+fn check_build_output(o: &Output, pkg: &str) -> Result<()> {
+    if o.status.code().unwrap_or_default() != 0 {
+        error!(
+            "stdout:\n{}\n\nstderr:\n{}",
+            String::from_utf8_lossy(&o.stdout),
+            String::from_utf8_lossy(&o.stderr)
+        );
+        return Err(command_error(o, &format!("Failed to do {pkg}")));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn installer_lines() {
+        let check = "All system dependencies have been satisfied\n\
+            apt\tlibboost-dev\n\
+            dnf\tboost-devel\n\
+            \x20 apt\tpython3-yaml \n\
+            apt  libeigen3-dev\n\
+            yum\tcmake\n\
+            aptitude\tfoo\n\
+            apt\t\n\
+            pip\tnumpy\n";
+
+        assert_eq!(
+            installer_packages(check, Deb.installer_keys()),
+            ["libboost-dev", "python3-yaml", "libeigen3-dev"]
+        );
+        assert_eq!(
+            installer_packages(check, Rpm.installer_keys()),
+            ["boost-devel", "cmake"]
+        );
+    }
+
+    #[test]
+    fn rosdep_errors() {
+        let stderr =
+            "ERROR: the following packages/stacks could not have their rosdep keys resolved\n\
+            to system dependencies:\n\
+            foo: Cannot locate rosdep definition for [bar]\n\
+            ERROR[baz]: Cannot locate rosdep definition for [qux]\n\
+            WARNING: some warning\n";
+
+        assert_eq!(
+            rosdep_problems(stderr),
+            [
+                "foo: Cannot locate rosdep definition for [bar]",
+                "ERROR[baz]: Cannot locate rosdep definition for [qux]",
+            ]
+        );
+        assert!(rosdep_problems("").is_empty());
+    }
+
+    #[test]
+    fn artifacts() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("build/debian/tmp")).unwrap();
+        for f in [
+            "foo_1_amd64.deb",
+            "foo-dbgsym_1_amd64.ddeb",
+            "foo-dbgsym_1_amd64.deb",
+            "build/debian/tmp/bar_1_all.deb",
+            "build/debian/rules",
+            "foo_1.changes",
+        ] {
+            fs::write(root.join(f), "").unwrap();
+        }
+
+        let found = |debug| {
+            find_artifacts(root, &Deb, debug)
+                .into_iter()
+                .map(|p| p.strip_prefix(root).unwrap().to_path_buf())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            found(true),
+            [
+                "build/debian/tmp/bar_1_all.deb",
+                "foo-dbgsym_1_amd64.ddeb",
+                "foo-dbgsym_1_amd64.deb",
+                "foo_1_amd64.deb",
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(
+            found(false),
+            ["build/debian/tmp/bar_1_all.deb", "foo_1_amd64.deb"].map(PathBuf::from)
+        );
+
+        assert!(Rpm.is_debug(Path::new("foo-debuginfo-1-1.x86_64.rpm")));
+        assert!(!Rpm.is_debug(Path::new("foo-1-1.x86_64.rpm")));
+    }
+}
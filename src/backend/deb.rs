@@ -0,0 +1,1124 @@
+//! Debian packages, built through `debian/rules`.
+
+use anyhow::{anyhow, Result};
+use clap::*;
+use log::*;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use walkdir::WalkDir;
+
+use crate::backend::{
+    check_build_output, file_name, find_artifacts, installer_packages, mapped_deb_name, Backend,
+};
+use crate::bloom::{log_output, log_patch, BloomConfig};
+use crate::index::{
+    deb_architectures, indexed_architectures, relative_path, release_file, split_index,
+    write_index, RepoLayout, DEB_COMPONENT, RELEASE_ORIGIN,
+};
+use crate::manifest::{Dependency, Package};
+use crate::runner::{
+    command_error, output_with_timeout, privileged, run_logged, CommandRunner, NONINTERACTIVE,
+};
+
+/// Output of dpkg when reinstalling packages that are already in place, which it may report with a
+/// nonzero exit status despite leaving them installed.
+const BENIGN_DPKG_SIGNATURES: &[&str] = &[
+    "is already installed",
+    "already the newest version",
+    ") over (",
+];
+
+/// Whether a failed dpkg run only reported packages being already installed, without errors.
+fn is_benign_dpkg(o: &Output) -> bool {
+    let stdout = String::from_utf8_lossy(&o.stdout);
+    let stderr = String::from_utf8_lossy(&o.stderr);
+    let errors = stderr
+        .lines()
+        .chain(stdout.lines())
+        .any(|l| l.starts_with("dpkg: error") || l.starts_with("Errors were encountered"));
+    !errors
+        && BENIGN_DPKG_SIGNATURES
+            .iter()
+            .any(|s| stdout.contains(s) || stderr.contains(s))
+}
+
+/// Extract packages listed after dpkg's "Errors were encountered while processing:" summary.
+fn dpkg_failed_packages(stderr: &str) -> Vec<String> {
+    stderr
+        .lines()
+        .skip_while(|l| !l.starts_with("Errors were encountered while processing:"))
+        .skip(1)
+        .take_while(|l| l.starts_with(char::is_whitespace))
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+/// Debian packages built through `debian/rules` and installed with dpkg.
+#[derive(Debug)]
+pub struct Deb;
+
+impl Backend for Deb {
+    fn generator(&self) -> &'static str {
+        "rosdebian"
+    }
+
+    fn metadata_dir(&self) -> &'static str {
+        "debian"
+    }
+
+    fn extension(&self) -> &'static str {
+        "deb"
+    }
+
+    fn artifact_extensions(&self) -> &'static [&'static str] {
+        &["deb", "ddeb"]
+    }
+
+    fn is_debug(&self, pkg: &Path) -> bool {
+        pkg.extension() == Some(OsStr::new("ddeb"))
+            || file_name(pkg).is_some_and(|n| n.contains("-dbgsym_"))
+    }
+
+    fn is_source_artifact(&self, path: &Path) -> bool {
+        file_name(path).is_some_and(|n| {
+            [".dsc", "_source.changes", "_source.buildinfo"]
+                .iter()
+                .any(|s| n.ends_with(s))
+                || [".orig.tar.", ".debian.tar.", ".diff."]
+                    .iter()
+                    .any(|s| n.contains(s))
+        })
+    }
+
+    fn installer(&self) -> &'static str {
+        "apt-get"
+    }
+
+    fn installer_keys(&self) -> &'static [&'static str] {
+        &["apt"]
+    }
+
+    fn tools(&self) -> &'static [&'static str] {
+        &["fakeroot", "dpkg"]
+    }
+
+    fn index_tool(&self) -> &'static str {
+        "dpkg-scanpackages"
+    }
+
+    fn source_list(
+        &self,
+        sources_dir: &Path,
+        name: &str,
+        repo: &Path,
+        layout: RepoLayout,
+    ) -> (PathBuf, String) {
+        let suite = match layout {
+            RepoLayout::Flat => "/".to_string(),
+            RepoLayout::Suite(suite, _) => format!("{suite} {DEB_COMPONENT}"),
+        };
+
+        (
+            sources_dir
+                .join("apt/sources.list.d")
+                .join(format!("{name}.list")),
+            format!("deb [trusted=yes] file://{} {suite}\n", repo.display()),
+        )
+    }
+
+    fn preferences(
+        &self,
+        sources_dir: &Path,
+        priority: i32,
+        with_release: bool,
+    ) -> Option<(PathBuf, String)> {
+        // Without a release file apt only knows that the repo is local
+        let pin = if with_release {
+            format!("release o={RELEASE_ORIGIN}")
+        } else {
+            "origin \"\"".to_string()
+        };
+
+        Some((
+            sources_dir.join("apt/preferences.d/99-catkin-bloom"),
+            format!("Package: *\nPin: {pin}\nPin-Priority: {priority}\n"),
+        ))
+    }
+
+    fn snapshot_sources(
+        &self,
+        sources_dir: &Path,
+        url: &str,
+        os_version: &str,
+        priority: i32,
+    ) -> Option<Vec<(PathBuf, String)>> {
+        let host = url
+            .split_once("://")
+            .map_or(url, |(_, rest)| rest)
+            .split(['/', ':'])
+            .next()
+            .unwrap_or_default();
+
+        Some(vec![
+            (
+                sources_dir.join("apt/sources.list.d/99-catkin-bloom-snapshot.list"),
+                format!("deb {url} {os_version} main\n"),
+            ),
+            (
+                sources_dir.join("apt/preferences.d/99-catkin-bloom-snapshot"),
+                format!("Package: *\nPin: origin {host}\nPin-Priority: {priority}\n"),
+            ),
+        ])
+    }
+
+    fn update(&self, prefix: &[&str], runner: &dyn CommandRunner) -> Result<()> {
+        let o = run_logged(
+            runner,
+            privileged("apt-get", &[NONINTERACTIVE], prefix).args(["-q", "update"]),
+        )?;
+
+        if !o.status.success() {
+            return Err(command_error(
+                &o,
+                &format!(
+                    "Failed to do apt-get update: {}",
+                    String::from_utf8_lossy(&o.stderr).trim()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn install_system(
+        &self,
+        check: &str,
+        recommends: bool,
+        prefix: &[&str],
+        runner: &dyn CommandRunner,
+    ) -> Result<()> {
+        let o = run_logged(
+            runner,
+            privileged("apt-get", &[NONINTERACTIVE], prefix)
+                // Keep existing configuration files instead of prompting about them
+                .args([
+                    "-q",
+                    "-y",
+                    "-o",
+                    "Dpkg::Options::=--force-confdef",
+                    "-o",
+                    "Dpkg::Options::=--force-confold",
+                    "install",
+                ])
+                .args((!recommends).then_some("--no-install-recommends"))
+                .args(installer_packages(check, self.installer_keys())),
+        )?;
+
+        if o.status.code().unwrap_or_default() != 0 {
+            return Err(command_error(
+                &o,
+                &format!(
+                    "Failed to do apt-get install '{}' | '{}'",
+                    String::from_utf8_lossy(&o.stdout),
+                    String::from_utf8_lossy(&o.stderr),
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn patch(
+        &self,
+        src: &Path,
+        build_dir: &Path,
+        package: &Package,
+        config: &BloomConfig,
+        log: &mut String,
+    ) -> Result<()> {
+        // Patch debian/rules to use the correct package path
+
+        let rules_path = build_dir.join("debian/rules");
+        let orig_rules = fs::read_to_string(&rules_path)?;
+        let rules = patch_rules(&orig_rules, src, config.testing)?;
+        log_patch(log, "debian/rules", &orig_rules, &rules);
+
+        let mut f = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(rules_path)?;
+        f.write_all(rules.as_bytes())?;
+        std::mem::drop(f);
+
+        // Patch debian/control to carry version constraints of workspace dependencies
+
+        let control_path = build_dir.join("debian/control");
+        let orig_control = fs::read_to_string(&control_path)?;
+        let mut control = patch_control(&orig_control, package, config.ros_distro, config.name_map);
+        log_patch(log, "debian/control", &orig_control, &control);
+
+        if let Some((name, email)) = config.maintainer {
+            control = set_source_field(&control, "Maintainer", &format!("{name} <{email}>"));
+        }
+
+        if config.provenance {
+            let value = provenance(src, config.runner);
+            writeln!(log, "==> provenance {value}\n")?;
+            control = add_binary_field(&control, "XB-Catkin-Bloom-Source", &value);
+        }
+
+        fs::write(control_path, control)?;
+
+        Ok(())
+    }
+
+    fn build(
+        &self,
+        pkg: &str,
+        build_dir: &Path,
+        config: &BloomConfig,
+        log: &mut String,
+    ) -> Result<Vec<PathBuf>> {
+        // dpkg-architecture sets up the whole cross build environment, not only the host arch
+        let mut cmd = match config.deb_arch {
+            Some(arch) => {
+                let mut cmd = Command::new("dpkg-architecture");
+                cmd.arg(format!("-a{arch}")).args(["-c", "fakeroot"]);
+                cmd
+            }
+            None => Command::new("fakeroot"),
+        };
+
+        let o = output_with_timeout(
+            config.runner,
+            cmd.args(["debian/rules", "binary"])
+                .envs(deb_build_options(config).map(|o| ("DEB_BUILD_OPTIONS", o)))
+                .envs(deb_env(config))
+                .envs(config.env)
+                .current_dir(build_dir),
+            config.build_timeout,
+        )?;
+
+        log_output(log, "debian/rules binary", &o);
+
+        check_build_output(&o, pkg)?;
+
+        // The debs are usually placed next to the build directory, but some packages put them
+        // further down
+        Ok(find_artifacts(
+            build_dir.parent().unwrap(),
+            self,
+            config.debug_packages,
+        ))
+    }
+
+    /// bloom leaves the upstream tarball to the release repository, so it is made out of the
+    /// sources, without the packaging metadata.
+    fn build_source(
+        &self,
+        pkg: &str,
+        build_dir: &Path,
+        config: &BloomConfig,
+        log: &mut String,
+    ) -> Result<Vec<PathBuf>> {
+        let changelog = fs::read_to_string(build_dir.join("debian/changelog"))?;
+        let (source, version) = changelog_version(&changelog)
+            .ok_or_else(|| anyhow!("No version found in debian/changelog of {pkg}"))?;
+
+        let parent = build_dir.parent().unwrap();
+        let dir = Path::new(build_dir.file_name().unwrap());
+
+        let o = output_with_timeout(
+            config.runner,
+            Command::new("tar")
+                .arg("-czf")
+                .arg(format!(
+                    "{source}_{}.orig.tar.gz",
+                    upstream_version(version)
+                ))
+                .arg("--exclude")
+                .arg(dir.join("debian"))
+                .arg(dir)
+                .current_dir(parent),
+            config.build_timeout,
+        )?;
+
+        log_output(log, "tar", &o);
+
+        check_build_output(&o, pkg)?;
+
+        let o = output_with_timeout(
+            config.runner,
+            Command::new("dpkg-buildpackage")
+                .args(["-S", "-us", "-uc", "-d"])
+                .envs(deb_env(config))
+                .envs(config.env)
+                .current_dir(build_dir),
+            config.build_timeout,
+        )?;
+
+        log_output(log, "dpkg-buildpackage -S", &o);
+
+        check_build_output(&o, pkg)?;
+
+        let mut found = fs::read_dir(parent)?
+            .map(|e| Ok(e?.path()))
+            .filter(|p| {
+                p.as_ref()
+                    .map(|p| self.is_source_artifact(p))
+                    .unwrap_or(true)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        found.sort();
+        Ok(found)
+    }
+
+    /// If dpkg fails because some packages could not be configured in the order they were unpacked,
+    /// configuring all pending packages afterwards completes the installation.
+    fn install(&self, debs: &[PathBuf], prefix: &[&str], runner: &dyn CommandRunner) -> Result<()> {
+        if debs.is_empty() {
+            return Ok(());
+        }
+
+        let o = run_logged(
+            runner,
+            privileged("dpkg", &[], prefix).args(["-i"]).args(debs),
+        )?;
+
+        trace!(
+            "stdout:\n{}\n\nstderr:\n{}",
+            String::from_utf8_lossy(&o.stdout),
+            String::from_utf8_lossy(&o.stderr)
+        );
+
+        if o.status.success() {
+            return Ok(());
+        }
+
+        if is_benign_dpkg(&o) {
+            debug!(
+                "dpkg -i exited with {} for already installed packages",
+                o.status
+            );
+            return Ok(());
+        }
+
+        debug!("dpkg -i failed, configuring pending packages");
+
+        let c = run_logged(
+            runner,
+            privileged("dpkg", &[], prefix).args(["--configure", "--pending"]),
+        )?;
+
+        trace!(
+            "stdout:\n{}\n\nstderr:\n{}",
+            String::from_utf8_lossy(&c.stdout),
+            String::from_utf8_lossy(&c.stderr)
+        );
+
+        if c.status.success() {
+            return Ok(());
+        }
+
+        let stderr = String::from_utf8_lossy(&o.stderr);
+
+        let mut failed = dpkg_failed_packages(&String::from_utf8_lossy(&c.stderr));
+        if failed.is_empty() {
+            failed = dpkg_failed_packages(&stderr);
+        }
+        if failed.is_empty() {
+            failed = debs
+                .iter()
+                .filter_map(|d| d.file_name()?.to_str()?.split('_').next())
+                .map(str::to_string)
+                .collect();
+        }
+
+        Err(anyhow!(
+            "Failed to install {}: {}",
+            failed.join(", "),
+            stderr.trim()
+        ))
+    }
+
+    /// apt-get only takes arguments containing a slash as paths to local debs.
+    fn install_resolving(
+        &self,
+        debs: &[PathBuf],
+        prefix: &[&str],
+        runner: &dyn CommandRunner,
+    ) -> Result<()> {
+        if debs.is_empty() {
+            return Ok(());
+        }
+
+        let o = run_logged(
+            runner,
+            privileged("apt-get", &[NONINTERACTIVE], prefix)
+                .args([
+                    "-q",
+                    "-y",
+                    "-o",
+                    "Dpkg::Options::=--force-confdef",
+                    "-o",
+                    "Dpkg::Options::=--force-confold",
+                    "install",
+                ])
+                .args(debs.iter().map(|d| {
+                    if d.is_absolute() {
+                        d.clone()
+                    } else {
+                        Path::new(".").join(d)
+                    }
+                })),
+        )?;
+
+        trace!(
+            "stdout:\n{}\n\nstderr:\n{}",
+            String::from_utf8_lossy(&o.stdout),
+            String::from_utf8_lossy(&o.stderr)
+        );
+
+        if !o.status.success() {
+            return Err(command_error(
+                &o,
+                &format!(
+                    "Failed to do apt-get install of {} debs: {}",
+                    debs.len(),
+                    String::from_utf8_lossy(&o.stderr).trim()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn index(
+        &self,
+        repo: &Path,
+        packages: &Path,
+        layout: RepoLayout,
+        compress: bool,
+        runner: &dyn CommandRunner,
+    ) -> Result<()> {
+        // The indices refer to the packages relative to the root of the repo
+        let packages = relative_path(&repo.canonicalize()?, &packages.canonicalize()?);
+
+        let o = run_logged(
+            runner,
+            Command::new("dpkg-scanpackages")
+                .arg("-m")
+                .arg(&packages)
+                .current_dir(repo),
+        )?;
+
+        match layout {
+            // apt picks the packages of its architecture out of a flat index itself
+            RepoLayout::Flat => write_index(repo, "Packages", &o.stdout, compress)?,
+            RepoLayout::Suite(suite, arch) => {
+                let component = repo.join("dists").join(suite).join(DEB_COMPONENT);
+
+                // Architectures indexed by earlier runs keep getting the independent packages
+                let mut targets = indexed_architectures(&component)?;
+                targets.insert(match arch {
+                    Some(arch) => arch.to_string(),
+                    None => deb_architecture(runner)?,
+                });
+
+                let architectures = deb_architectures(&repo.join(&packages), self, runner)?;
+                let index = String::from_utf8_lossy(&o.stdout);
+
+                for (arch, index) in split_index(&index, targets, &architectures) {
+                    let dir = component.join(format!("binary-{arch}"));
+                    fs::create_dir_all(&dir)?;
+                    write_index(&dir, "Packages", index.as_bytes(), compress)?;
+                }
+            }
+        }
+
+        let has_sources = WalkDir::new(repo.join(&packages))
+            .into_iter()
+            .flatten()
+            .any(|e| e.path().extension() == Some(OsStr::new("dsc")));
+        if !has_sources {
+            return Ok(());
+        }
+
+        let dir = match layout {
+            RepoLayout::Flat => repo.to_path_buf(),
+            RepoLayout::Suite(suite, _) => repo
+                .join("dists")
+                .join(suite)
+                .join(DEB_COMPONENT)
+                .join("source"),
+        };
+        fs::create_dir_all(&dir)?;
+
+        let o = run_logged(
+            runner,
+            Command::new("dpkg-scansources")
+                .arg(&packages)
+                .current_dir(repo),
+        )?;
+
+        write_index(&dir, "Sources", &o.stdout, compress)
+    }
+
+    fn verify(&self, deb: &Path, prefix: &[&str], runner: &dyn CommandRunner) -> Result<()> {
+        let name = deb
+            .file_name()
+            .and_then(|f| f.to_str()?.split('_').next())
+            .ok_or_else(|| anyhow!("Invalid deb name {}", deb.display()))?;
+
+        let o = run_logged(
+            runner,
+            privileged("apt-get", &[NONINTERACTIVE], prefix).args([
+                "install",
+                "--simulate",
+                "-y",
+                name,
+            ]),
+        )?;
+
+        if !o.status.success() {
+            return Err(command_error(
+                &o,
+                &format!(
+                    "{name} is not installable: {}",
+                    String::from_utf8_lossy(&o.stdout).trim()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn release(
+        &self,
+        repo: &Path,
+        codename: &str,
+        layout: RepoLayout,
+        runner: &dyn CommandRunner,
+    ) -> Result<()> {
+        let (dir, arches) = match layout {
+            RepoLayout::Flat => (repo.to_path_buf(), None),
+            RepoLayout::Suite(suite, arch) => {
+                let dir = repo.join("dists").join(suite);
+                let mut arches = indexed_architectures(&dir.join(DEB_COMPONENT))?;
+                arches.insert(match arch {
+                    Some(arch) => arch.to_string(),
+                    None => deb_architecture(runner)?,
+                });
+                (dir, Some(arches.into_iter().collect::<Vec<_>>()))
+            }
+        };
+
+        let release = release_file(
+            &dir,
+            codename,
+            arches.as_deref(),
+            std::time::SystemTime::now(),
+        )?;
+        fs::write(dir.join("Release"), release)?;
+        Ok(())
+    }
+}
+
+/// Architecture of the debs built on this machine.
+fn deb_architecture(runner: &dyn CommandRunner) -> Result<String> {
+    let o = run_logged(runner, Command::new("dpkg").arg("--print-architecture"))?;
+
+    if !o.status.success() {
+        return Err(anyhow!(
+            "Failed to do dpkg --print-architecture: {}",
+            String::from_utf8_lossy(&o.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&o.stdout).trim().to_string())
+}
+
+/// `DEB_BUILD_OPTIONS` for the binary build, if any.
+fn deb_build_options(config: &BloomConfig) -> Option<String> {
+    let options = config
+        .build_jobs
+        .map(|n| format!("parallel={n}"))
+        .into_iter()
+        .chain((config.testing == Some(false)).then(|| "nocheck".to_string()))
+        .collect::<Vec<_>>();
+
+    (!options.is_empty()).then(|| options.join(" "))
+}
+
+/// Environment of the packaging tools overriding the maintainer and host architecture.
+pub(crate) fn deb_env<'a>(config: &BloomConfig<'a>) -> Vec<(&'static str, &'a str)> {
+    let mut env = vec![];
+
+    if let Some((name, email)) = config.maintainer {
+        env.extend([("DEBFULLNAME", name), ("DEBEMAIL", email)]);
+    }
+
+    env.extend(config.deb_arch.map(|arch| ("DEB_HOST_ARCH", arch)));
+
+    env
+}
+
+/// Source package name and version of the latest entry of a `debian/changelog`.
+fn changelog_version(changelog: &str) -> Option<(&str, &str)> {
+    let (source, rest) = changelog.lines().next()?.split_once(" (")?;
+    let (version, _) = rest.split_once(')')?;
+    Some((source.trim(), version))
+}
+
+/// Version of the upstream sources of a debian version, without epoch and revision.
+pub(crate) fn upstream_version(version: &str) -> &str {
+    let version = version.split_once(':').map_or(version, |(_, v)| v);
+    version.rsplit_once('-').map_or(version, |(v, _)| v)
+}
+
+/// Split a maintainer given as `Name <email>`.
+pub(crate) fn parse_maintainer(maintainer: &str) -> Result<(&str, &str)> {
+    maintainer
+        .trim()
+        .strip_suffix('>')
+        .and_then(|m| m.rsplit_once('<'))
+        .map(|(name, email)| (name.trim(), email.trim()))
+        .filter(|(name, email)| !name.is_empty() && email.contains('@'))
+        .ok_or_else(|| anyhow!("Invalid maintainer '{maintainer}', expected 'Name <email>'"))
+}
+
+/// Pass the package source path to CMake in a `debian/rules` file, next to the
+/// `$(BUILD_TESTING_ARG)` token of bloom's template, or else right after `dh_auto_configure --`.
+/// `testing` forces BUILD_TESTING on or off.
+fn patch_rules(rules: &str, src: &Path, testing: Option<bool>) -> Result<String> {
+    const TOKEN: &str = "$(BUILD_TESTING_ARG)";
+    const CONFIGURE: &str = "dh_auto_configure --";
+
+    let src = match testing {
+        Some(true) => format!("{} -DBUILD_TESTING=ON", src.display()),
+        Some(false) => format!("{} -DBUILD_TESTING=OFF", src.display()),
+        None => src.display().to_string(),
+    };
+
+    match rules.matches(TOKEN).count() {
+        1 => return Ok(rules.replacen(TOKEN, &format!("{src} {TOKEN}"), 1)),
+        0 => {}
+        n => {
+            return Err(anyhow!(
+                "debian/rules contains {TOKEN} {n} times, cannot tell where to pass the source path"
+            ))
+        }
+    }
+
+    match rules.matches(CONFIGURE).count() {
+        1 => Ok(rules.replacen(CONFIGURE, &format!("{CONFIGURE} {src}"), 1)),
+        n => Err(anyhow!(
+            "debian/rules contains {TOKEN} 0 times and '{CONFIGURE}' {n} times, \
+            cannot tell where to pass the source path"
+        )),
+    }
+}
+
+/// Describe where a package was built from: its source path, the git commit checked out there,
+/// if any, and the catkin-bloom version.
+fn provenance(src: &Path, runner: &dyn CommandRunner) -> String {
+    let commit = run_logged(
+        runner,
+        Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .current_dir(src)
+            .stderr(Stdio::null()),
+    )
+    .ok()
+    .filter(|o| o.status.success())
+    .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string());
+
+    format!(
+        "{}; commit {}; catkin-bloom {}",
+        src.display(),
+        commit.as_deref().unwrap_or("unknown"),
+        crate_version!()
+    )
+}
+
+/// Set a field of the source stanza of a `debian/control` file, replacing any value it had.
+fn set_source_field(control: &str, field: &str, value: &str) -> String {
+    let prefix = format!("{field}:");
+    let mut out = String::new();
+    let mut in_source = true;
+    let mut in_field = false;
+    let mut set = false;
+
+    for line in control.lines() {
+        if in_source {
+            if line.trim().is_empty() {
+                in_source = false;
+                in_field = false;
+            } else if line.starts_with(&prefix) {
+                in_field = true;
+            } else if !(in_field && line.starts_with([' ', '\t'])) {
+                in_field = false;
+            }
+
+            if (in_field || !in_source) && !set {
+                writeln!(out, "{field}: {value}").unwrap();
+                set = true;
+            }
+
+            // The old value, including its continuation lines
+            if in_field {
+                continue;
+            }
+        }
+
+        writeln!(out, "{line}").unwrap();
+    }
+
+    if !set {
+        writeln!(out, "{field}: {value}").unwrap();
+    }
+
+    out
+}
+
+/// Append a field to every binary package paragraph of a `debian/control` file.
+fn add_binary_field(control: &str, field: &str, value: &str) -> String {
+    let mut out = String::new();
+    let mut in_binary = false;
+
+    for line in control.lines() {
+        if line.trim().is_empty() {
+            if in_binary {
+                writeln!(out, "{field}: {value}").unwrap();
+            }
+            in_binary = false;
+        } else if line.starts_with("Package:") {
+            in_binary = true;
+        }
+
+        writeln!(out, "{line}").unwrap();
+    }
+
+    if in_binary {
+        writeln!(out, "{field}: {value}").unwrap();
+    }
+
+    out
+}
+
+/// Sorted version constraints of `depends` by deb name.
+fn deb_constraints<'a>(
+    depends: impl Iterator<Item = &'a Dependency>,
+    ros_distro: &str,
+    name_map: &BTreeMap<&str, &str>,
+) -> HashMap<String, Vec<String>> {
+    let mut constraints = HashMap::<String, Vec<String>>::new();
+
+    for dep in depends {
+        let c = constraints
+            .entry(mapped_deb_name(ros_distro, &dep.name, name_map))
+            .or_default();
+        for (op, v) in dep.constraints() {
+            let v = format!("({op} {v})");
+            if !c.contains(&v) {
+                c.push(v);
+            }
+        }
+    }
+
+    for c in constraints.values_mut() {
+        c.sort_unstable();
+    }
+
+    constraints
+}
+
+/// Add version constraints of workspace dependencies to the dependency fields of a
+/// `debian/control` file. They replace the constraints generated for the dependency, as the
+/// package.xml of the workspace is authoritative. Build and test dependencies constrain the
+/// `Build-Depends` fields, run dependencies the others.
+pub(crate) fn patch_control(
+    control: &str,
+    package: &Package,
+    ros_distro: &str,
+    name_map: &BTreeMap<&str, &str>,
+) -> String {
+    let build = deb_constraints(package.build_test_depends(), ros_distro, name_map);
+    let run = deb_constraints(package.run_depends(), ros_distro, name_map);
+
+    let mut field = None;
+    let mut out = String::new();
+
+    for line in control.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            field = line
+                .split_once(':')
+                .map(|(k, _)| k)
+                .filter(|k| k.ends_with("Depends"))
+                .map(|k| {
+                    if k.starts_with("Build-") {
+                        &build
+                    } else {
+                        &run
+                    }
+                });
+        }
+
+        let line = if let Some(constraints) = field {
+            let (prefix, items) = match line.split_once(':') {
+                Some((k, v)) if !line.starts_with(char::is_whitespace) => (format!("{k}:"), v),
+                _ => (String::new(), line),
+            };
+
+            let items = items
+                .split(',')
+                // Alternatives are left as generated
+                .map(|item| {
+                    let name = if item.contains('|') {
+                        ""
+                    } else {
+                        item.trim().split([' ', '(']).next().unwrap_or_default()
+                    };
+                    (item, name)
+                })
+                .map(|(item, name)| match constraints.get(name) {
+                    Some(c) if !c.is_empty() => {
+                        let ws = &item[..item.find(name).unwrap_or(0)];
+                        let deps = c
+                            .iter()
+                            .map(|c| format!("{name} {c}"))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        format!("{ws}{deps}")
+                    }
+                    _ => item.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+
+            format!("{prefix}{items}")
+        } else {
+            line.to_string()
+        };
+
+        writeln!(out, "{line}").unwrap();
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{mock_output, SystemRunner};
+    use tempfile::tempdir;
+
+    #[test]
+    fn control_constraints() {
+        let control = "Source: ros-melodic-foo\n\
+            Build-Depends: debhelper (>= 9.0.0), ros-melodic-bar, ros-melodic-catkin, ros-melodic-qux\n\
+            \n\
+            Package: ros-melodic-foo\n\
+            Depends: ${shlibs:Depends}, ${misc:Depends},\n \
+            ros-melodic-bar, ros-melodic-baz (>= 0.1), ros-melodic-qux\n\
+            Description: foo\n";
+
+        let mut package = Package::default();
+        package.insert(
+            b"depend",
+            Dependency {
+                name: "bar".into(),
+                version_gte: Some("1.2".into()),
+                version_lt: Some("2.0".into()),
+                ..Default::default()
+            },
+        );
+        package.insert(
+            b"exec_depend",
+            Dependency {
+                name: "baz".into(),
+                version_gte: Some("0.2".into()),
+                ..Default::default()
+            },
+        );
+        // Constraints of test dependencies only apply to the build
+        package.insert(
+            b"test_depend",
+            Dependency {
+                name: "qux".into(),
+                version_gte: Some("3.0".into()),
+                ..Default::default()
+            },
+        );
+        package.insert(
+            b"exec_depend",
+            Dependency {
+                name: "qux".into(),
+                ..Default::default()
+            },
+        );
+
+        let patched = patch_control(control, &package, "melodic", &BTreeMap::new());
+
+        assert_eq!(
+            patched,
+            "Source: ros-melodic-foo\n\
+            Build-Depends: debhelper (>= 9.0.0), ros-melodic-bar (<< 2.0), ros-melodic-bar (>= 1.2), ros-melodic-catkin, ros-melodic-qux (>= 3.0)\n\
+            \n\
+            Package: ros-melodic-foo\n\
+            Depends: ${shlibs:Depends}, ${misc:Depends},\n \
+            ros-melodic-bar (<< 2.0), ros-melodic-bar (>= 1.2), ros-melodic-baz (>= 0.2), ros-melodic-qux\n\
+            Description: foo\n"
+        );
+    }
+
+    #[test]
+    fn rules_patching() {
+        let rules = "#!/usr/bin/make -f\n\
+            ifneq ($(filter nocheck,$(DEB_BUILD_OPTIONS)),)\n\
+            \tBUILD_TESTING_ARG=-DBUILD_TESTING=OFF -DCATKIN_ENABLE_TESTING=OFF\n\
+            endif\n\
+            \n\
+            override_dh_auto_configure:\n\
+            \tif [ -f \"/opt/ros/melodic/setup.sh\" ]; then . \"/opt/ros/melodic/setup.sh\"; fi && \\\n\
+            \tdh_auto_configure -- \\\n\
+            \t\t-DCATKIN_BUILD_BINARY_PACKAGE=\"1\" \\\n\
+            \t\t-DCMAKE_INSTALL_PREFIX=\"/opt/ros/melodic\" \\\n\
+            \t\t$(BUILD_TESTING_ARG)\n";
+        let src = Path::new("/ws/src/foo");
+
+        let patched = patch_rules(rules, src, None).unwrap();
+        assert_eq!(
+            patched,
+            rules.replace(
+                "\t\t$(BUILD_TESTING_ARG)",
+                "\t\t/ws/src/foo $(BUILD_TESTING_ARG)"
+            )
+        );
+
+        let old_template = rules.replace("\t\t$(BUILD_TESTING_ARG)", "\t\t-DFOO=1");
+        let patched = patch_rules(&old_template, src, None).unwrap();
+        assert!(
+            patched.contains("\tdh_auto_configure -- /ws/src/foo \\\n"),
+            "{patched}"
+        );
+
+        let patched = patch_rules(rules, src, Some(false)).unwrap();
+        assert!(
+            patched.contains("\t\t/ws/src/foo -DBUILD_TESTING=OFF $(BUILD_TESTING_ARG)\n"),
+            "{patched}"
+        );
+        let patched = patch_rules(&old_template, src, Some(true)).unwrap();
+        assert!(
+            patched.contains("dh_auto_configure -- /ws/src/foo -DBUILD_TESTING=ON \\\n"),
+            "{patched}"
+        );
+
+        let twice = format!("{rules}\t\t$(BUILD_TESTING_ARG)\n");
+        assert!(patch_rules(&twice, src, None).is_err());
+        assert!(patch_rules("override_dh_auto_build:\n\tdh_auto_build\n", src, None).is_err());
+    }
+
+    #[test]
+    fn binary_fields() {
+        let control = "Source: ros-melodic-foo\nMaintainer: me\n\n\
+            Package: ros-melodic-foo\nArchitecture: any\nDescription: Foo\n  more\n\n\
+            Package: ros-melodic-foo-dbgsym\nArchitecture: any\n";
+
+        assert_eq!(
+            add_binary_field(control, "XB-Origin", "here"),
+            "Source: ros-melodic-foo\nMaintainer: me\n\n\
+            Package: ros-melodic-foo\nArchitecture: any\nDescription: Foo\n  more\n\
+            XB-Origin: here\n\n\
+            Package: ros-melodic-foo-dbgsym\nArchitecture: any\nXB-Origin: here\n"
+        );
+
+        assert_eq!(
+            set_source_field(control, "Maintainer", "Jane Doe <jane@example.com>"),
+            control.replace("Maintainer: me", "Maintainer: Jane Doe <jane@example.com>")
+        );
+        assert_eq!(
+            set_source_field(
+                "Source: foo\nMaintainer: a,\n b\nSection: misc\n",
+                "Maintainer",
+                "c"
+            ),
+            "Source: foo\nMaintainer: c\nSection: misc\n"
+        );
+        assert_eq!(
+            set_source_field("Source: foo\n\nPackage: foo\n", "Maintainer", "c"),
+            "Source: foo\nMaintainer: c\n\nPackage: foo\n"
+        );
+
+        assert_eq!(
+            parse_maintainer(" Jane Doe <jane@example.com>").unwrap(),
+            ("Jane Doe", "jane@example.com")
+        );
+        assert!(parse_maintainer("jane@example.com").is_err());
+        assert!(parse_maintainer("<jane@example.com>").is_err());
+
+        let dir = tempdir().unwrap();
+        let value = provenance(dir.path(), &SystemRunner);
+        assert!(value.starts_with(&format!("{}; commit ", dir.path().display())));
+        assert!(value.ends_with(&format!("; catkin-bloom {}", crate_version!())));
+    }
+
+    #[test]
+    fn source_versions() {
+        let changelog =
+            "ros-melodic-foo (1:1.2.3-0bionic) bionic; urgency=high\n\n  * Autogenerated\n";
+        assert_eq!(
+            changelog_version(changelog),
+            Some(("ros-melodic-foo", "1:1.2.3-0bionic"))
+        );
+        assert_eq!(changelog_version(""), None);
+
+        assert_eq!(upstream_version("1:1.2.3-0bionic"), "1.2.3");
+        assert_eq!(upstream_version("1.2.3-1-0bionic"), "1.2.3-1");
+        assert_eq!(upstream_version("1.2.3"), "1.2.3");
+
+        for f in [
+            "foo_1.2.3-0bionic.dsc",
+            "foo_1.2.3.orig.tar.gz",
+            "foo_1.2.3-0bionic.debian.tar.xz",
+            "foo_1.2.3-0bionic_source.changes",
+        ] {
+            assert!(Deb.is_source_artifact(Path::new(f)), "{f}");
+        }
+        assert!(!Deb.is_source_artifact(Path::new("foo_1.2.3-0bionic_amd64.deb")));
+    }
+
+    #[test]
+    fn dpkg_failures() {
+        let stderr = "dpkg: dependency problems prevent configuration of ros-melodic-b:\n \
+            ros-melodic-b depends on ros-melodic-a; however:\n  \
+            Package ros-melodic-a is not installed.\n\n\
+            dpkg: error processing package ros-melodic-b (--install):\n \
+            dependency problems - leaving unconfigured\n\
+            Errors were encountered while processing:\n \
+            ros-melodic-b\n \
+            ros-melodic-c\n";
+
+        assert_eq!(
+            dpkg_failed_packages(stderr),
+            ["ros-melodic-b", "ros-melodic-c"]
+        );
+        assert!(dpkg_failed_packages("").is_empty());
+
+        let mut o = mock_output(1, "");
+        o.stderr = stderr.into();
+        assert!(!is_benign_dpkg(&o));
+
+        let reinstall = "Preparing to unpack .../ros-melodic-a_1.0.0-0bionic_amd64.deb ...\n\
+            Unpacking ros-melodic-a (1.0.0-0bionic) over (1.0.0-0bionic) ...\n";
+        assert!(is_benign_dpkg(&mock_output(1, reinstall)));
+        assert!(!is_benign_dpkg(&mock_output(1, "")));
+    }
+}
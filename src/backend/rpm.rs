@@ -0,0 +1,247 @@
+//! RPM packages, built with rpmbuild.
+
+use anyhow::{anyhow, Result};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::backend::{check_build_output, file_name, find_artifacts, installer_packages, Backend};
+use crate::bloom::{copy_dir, log_output, BloomConfig};
+use crate::index::RepoLayout;
+use crate::manifest::Package;
+use crate::runner::{command_error, output_with_timeout, privileged, run_logged, CommandRunner};
+
+/// RPM packages built with rpmbuild and installed with rpm.
+#[derive(Debug)]
+pub struct Rpm;
+
+impl Backend for Rpm {
+    fn generator(&self) -> &'static str {
+        "rosrpm"
+    }
+
+    fn metadata_dir(&self) -> &'static str {
+        "rpm"
+    }
+
+    fn extension(&self) -> &'static str {
+        "rpm"
+    }
+
+    fn artifact_extensions(&self) -> &'static [&'static str] {
+        &["rpm"]
+    }
+
+    fn is_debug(&self, pkg: &Path) -> bool {
+        file_name(pkg).is_some_and(|n| n.contains("-debuginfo-") || n.contains("-debugsource-"))
+    }
+
+    fn installer(&self) -> &'static str {
+        "dnf"
+    }
+
+    fn installer_keys(&self) -> &'static [&'static str] {
+        &["dnf", "yum"]
+    }
+
+    fn tools(&self) -> &'static [&'static str] {
+        &["rpmbuild", "rpm"]
+    }
+
+    fn index_tool(&self) -> &'static str {
+        "createrepo"
+    }
+
+    fn source_list(
+        &self,
+        sources_dir: &Path,
+        name: &str,
+        repo: &Path,
+        _: RepoLayout,
+    ) -> (PathBuf, String) {
+        (
+            sources_dir.join("yum.repos.d").join(format!("{name}.repo")),
+            format!(
+                "[{name}]\nname={name}\nbaseurl=file://{}\nenabled=1\ngpgcheck=0\n",
+                repo.display()
+            ),
+        )
+    }
+
+    fn update(&self, prefix: &[&str], runner: &dyn CommandRunner) -> Result<()> {
+        let o = run_logged(runner, privileged("dnf", &[], prefix).arg("makecache"))?;
+
+        if !o.status.success() {
+            return Err(command_error(
+                &o,
+                &format!(
+                    "Failed to do dnf makecache: {}",
+                    String::from_utf8_lossy(&o.stderr).trim()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn install_system(
+        &self,
+        check: &str,
+        recommends: bool,
+        prefix: &[&str],
+        runner: &dyn CommandRunner,
+    ) -> Result<()> {
+        let pkgs = installer_packages(check, self.installer_keys());
+
+        if pkgs.is_empty() {
+            return Ok(());
+        }
+
+        let o = run_logged(
+            runner,
+            privileged("dnf", &[], prefix)
+                .args(["install", "-y"])
+                .args((!recommends).then_some("--setopt=install_weak_deps=False"))
+                .args(pkgs),
+        )?;
+
+        if !o.status.success() {
+            return Err(command_error(
+                &o,
+                &format!(
+                    "Failed to do dnf install '{}' | '{}'",
+                    String::from_utf8_lossy(&o.stdout),
+                    String::from_utf8_lossy(&o.stderr),
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn patch(
+        &self,
+        src: &Path,
+        build_dir: &Path,
+        _: &Package,
+        _: &BloomConfig,
+        _: &mut String,
+    ) -> Result<()> {
+        // rpmbuild builds in place, thus the sources are placed next to the generated spec
+        copy_dir(src, build_dir)
+    }
+
+    fn build(
+        &self,
+        pkg: &str,
+        build_dir: &Path,
+        config: &BloomConfig,
+        log: &mut String,
+    ) -> Result<Vec<PathBuf>> {
+        let spec = fs::read_dir(build_dir.join("rpm"))?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .find(|p| p.extension() == Some(OsStr::new("spec")))
+            .ok_or_else(|| anyhow!("bloom-generate did not produce a spec file"))?;
+
+        let top_dir = build_dir.parent().unwrap().join("rpmbuild");
+
+        let runner = config.runner;
+        let o = output_with_timeout(
+            runner,
+            Command::new("rpmbuild")
+                .args(["-bb", "--build-in-place", "--define"])
+                .arg(format!("_topdir {}", top_dir.display()))
+                .args(
+                    config
+                        .build_jobs
+                        .iter()
+                        .flat_map(|n| ["--define".into(), format!("_smp_mflags -j{n}")]),
+                )
+                .args((config.testing == Some(false)).then_some("--nocheck"))
+                .arg(spec)
+                .envs(config.env)
+                .current_dir(build_dir),
+            config.build_timeout,
+        )?;
+
+        log_output(log, "rpmbuild", &o);
+
+        check_build_output(&o, pkg)?;
+
+        Ok(find_artifacts(
+            &top_dir.join("RPMS"),
+            self,
+            config.debug_packages,
+        ))
+    }
+
+    fn install(&self, rpms: &[PathBuf], prefix: &[&str], runner: &dyn CommandRunner) -> Result<()> {
+        if rpms.is_empty() {
+            return Ok(());
+        }
+
+        let o = run_logged(
+            runner,
+            privileged("rpm", &[], prefix)
+                .args(["-i", "--replacepkgs"])
+                .args(rpms),
+        )?;
+
+        if !o.status.success() {
+            return Err(anyhow!(
+                "Failed to install rpms: {}",
+                String::from_utf8_lossy(&o.stderr).trim()
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn index(
+        &self,
+        repo: &Path,
+        _: &Path,
+        _: RepoLayout,
+        _: bool,
+        runner: &dyn CommandRunner,
+    ) -> Result<()> {
+        // createrepo always compresses its metadata
+        let o = run_logged(
+            runner,
+            Command::new("createrepo").arg(".").current_dir(repo),
+        )?;
+
+        if !o.status.success() {
+            return Err(anyhow!(
+                "Failed to do createrepo: {}",
+                String::from_utf8_lossy(&o.stderr).trim()
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn verify(&self, rpm: &Path, prefix: &[&str], runner: &dyn CommandRunner) -> Result<()> {
+        let o = run_logged(
+            runner,
+            privileged("dnf", &[], prefix)
+                .args(["install", "-y", "--setopt=tsflags=test"])
+                .arg(rpm),
+        )?;
+
+        if !o.status.success() {
+            return Err(command_error(
+                &o,
+                &format!(
+                    "{} is not installable: {}",
+                    rpm.display(),
+                    String::from_utf8_lossy(&o.stderr).trim()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
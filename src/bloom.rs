@@ -0,0 +1,435 @@
+//! Building single packages out of the packaging metadata generated by bloom.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use std::collections::{BTreeMap, HashMap};
+use std::env::current_dir;
+use std::ffi::OsStr;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::time::Duration;
+use tempfile::{tempdir, TempDir};
+use walkdir::WalkDir;
+
+use crate::backend::deb::deb_env;
+use crate::backend::Backend;
+use crate::cache::{cached_build, copy_verified, restore_cached, store_cached};
+use crate::config::Artifacts;
+use crate::manifest::{strip_test_depends, Package};
+use crate::report::{timed, Timings};
+use crate::runner::{command_error, output_with_timeout, CommandRunner};
+
+/// Settings shared by all package builds.
+pub struct BloomConfig<'a> {
+    pub package_dir: &'a Path,
+    pub os_name: &'a str,
+    pub os_version: &'a str,
+    pub ros_distro: &'a str,
+    pub cache_dir: Option<&'a Path>,
+    pub build_timeout: Option<Duration>,
+    pub log_dir: Option<&'a Path>,
+    pub backend: &'a dyn Backend,
+    pub build_jobs: Option<usize>,
+    pub keep_build_dirs: Option<&'a Path>,
+    pub generator: &'a str,
+    pub bloom_args: &'a [&'a str],
+    pub testing: Option<bool>,
+    pub skip_test_depends: bool,
+    pub provenance: bool,
+    pub build_tmp: Option<&'a Path>,
+    pub debug_packages: bool,
+    pub name_map: &'a BTreeMap<&'a str, &'a str>,
+    /// Name and email of the maintainer of the built packages.
+    pub maintainer: Option<(&'a str, &'a str)>,
+    pub deb_arch: Option<&'a str>,
+    pub artifacts: Artifacts,
+    /// Environment of the package builds.
+    pub env: &'a HashMap<String, String>,
+    pub runner: &'a dyn CommandRunner,
+}
+
+pub(crate) fn log_output(log: &mut String, step: &str, o: &Output) {
+    writeln!(
+        log,
+        "==> {step} ({})\n\nstdout:\n{}\n\nstderr:\n{}\n",
+        o.status,
+        String::from_utf8_lossy(&o.stdout),
+        String::from_utf8_lossy(&o.stderr)
+    )
+    .unwrap();
+}
+
+/// Log the lines changed by patching a file, without changing its number of lines.
+pub(crate) fn log_patch(log: &mut String, file: &str, old: &str, new: &str) {
+    writeln!(log, "==> patch {file}\n").unwrap();
+    for (old, new) in old.lines().zip(new.lines()).filter(|(o, n)| o != n) {
+        writeln!(log, "-{old}\n+{new}").unwrap();
+    }
+    writeln!(log).unwrap();
+}
+
+/// Packages built out of a single ROS package.
+pub struct Built {
+    pub debs: Vec<PathBuf>,
+    pub cached: bool,
+    pub timings: Timings,
+}
+
+/// Packaging metadata generated ahead of the build, along with the log and duration of its
+/// generation.
+pub type Generated = (String, Timings, Result<TempDir>);
+
+pub fn bloom(
+    pkg: &str,
+    path: &Path,
+    package: &Package,
+    cache_key: Option<&str>,
+    generated: Option<Generated>,
+    env: &HashMap<String, String>,
+    config: &BloomConfig,
+) -> Result<Built> {
+    let config = &BloomConfig { env, ..*config };
+
+    let (mut log, generated) = match generated {
+        Some((log, timings, build_root)) => (log, Some((timings, build_root))),
+        None => (String::new(), None),
+    };
+
+    // Another process may have removed the package since the workspace was scanned
+    let res = if path.is_dir() {
+        build_package(pkg, path, package, cache_key, generated, config, &mut log)
+    } else {
+        Err(anyhow!(
+            "Source directory {} no longer exists",
+            path.display()
+        ))
+    };
+
+    if let Some(log_dir) = config.log_dir {
+        if let Err(e) = &res {
+            writeln!(log, "==> error\n\n{e:?}")?;
+        }
+        fs::write(log_dir.join(format!("{pkg}.log")), log)?;
+    }
+
+    res
+}
+
+/// Copy the contents of `src` into `dst`, preserving symlinks.
+pub(crate) fn copy_dir(src: &Path, dst: &Path) -> Result<()> {
+    for entry in WalkDir::new(src).min_depth(1) {
+        let entry = entry?;
+        let target = dst.join(entry.path().strip_prefix(src)?);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(target)?;
+        } else if entry.file_type().is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Persist the build root of a failed package under `--keep-build-dirs` for inspection.
+fn keep_build_dir(pkg: &str, build_root: TempDir, config: &BloomConfig, log: &mut String) {
+    let Some(keep_dir) = config.keep_build_dirs else {
+        return;
+    };
+
+    let target = keep_dir.join(pkg);
+    let build_root = build_root.into_path();
+
+    let res = (|| -> Result<()> {
+        if target.exists() {
+            fs::remove_dir_all(&target)?;
+        }
+        fs::create_dir_all(keep_dir)?;
+
+        // The temp dir may be on another file system
+        if fs::rename(&build_root, &target).is_err() {
+            fs::create_dir(&target)?;
+            copy_dir(&build_root, &target)?;
+            fs::remove_dir_all(&build_root)?;
+        }
+
+        Ok(())
+    })();
+
+    match res {
+        Ok(()) => {
+            // Only errors are shown by default, and the build has failed anyway
+            error!("{pkg}: kept build directory at {}", target.display());
+            let _ = writeln!(log, "==> kept build directory at {}", target.display());
+        }
+        Err(e) => error!(
+            "{pkg}: failed to keep build directory {}: {e}",
+            build_root.display()
+        ),
+    }
+}
+
+fn build_package(
+    pkg: &str,
+    path: &Path,
+    package: &Package,
+    cache_key: Option<&str>,
+    generated: Option<(Timings, Result<TempDir>)>,
+    config: &BloomConfig,
+    log: &mut String,
+) -> Result<Built> {
+    if let Some(cached) = cached_build(cache_key, config) {
+        debug!("{pkg}: using cached debs from {}", cached.display());
+        writeln!(log, "==> restored from {}", cached.display())?;
+        return Ok(Built {
+            debs: restore_cached(&cached, config.package_dir, config.backend)?,
+            cached: true,
+            timings: Timings::default(),
+        });
+    }
+
+    let (mut timings, build_root) = match generated {
+        Some((timings, build_root)) => (timings, build_root?),
+        None => {
+            let mut timings = Timings::default();
+            let build_root = generate_debian(pkg, path, config, &mut timings, log)?;
+            (timings, build_root)
+        }
+    };
+
+    let debs = match build_binary(
+        pkg,
+        path,
+        &build_root.path().join("build"),
+        package,
+        config,
+        &mut timings,
+        log,
+    ) {
+        Ok(debs) => debs,
+        Err(e) => {
+            keep_build_dir(pkg, build_root, config, log);
+            return Err(e);
+        }
+    };
+
+    if let Some((cache_dir, key)) = config.cache_dir.zip(cache_key) {
+        store_cached(&debs, cache_dir, key)?;
+    }
+
+    Ok(Built {
+        debs,
+        cached: false,
+        timings,
+    })
+}
+
+/// Run bloom-generate for a package in a fresh build root. It has no ordering constraints, so
+/// all packages may be generated up front.
+pub(crate) fn generate_debian(
+    pkg: &str,
+    path: &Path,
+    config: &BloomConfig,
+    timings: &mut Timings,
+    log: &mut String,
+) -> Result<TempDir> {
+    let BloomConfig {
+        os_name,
+        os_version,
+        ros_distro,
+        build_timeout,
+        backend,
+        generator,
+        bloom_args,
+        ..
+    } = *config;
+
+    let build_root = match config.build_tmp {
+        Some(dir) => tempfile::Builder::new().tempdir_in(dir)?,
+        None => tempdir()?,
+    };
+
+    let pb = build_root.path().join("build");
+    fs::create_dir(&pb)?;
+
+    let mut p = current_dir()?.join(path);
+
+    // bloom-generate reads the dependencies from the manifest, so it gets one without the tests
+    if config.skip_test_depends {
+        let manifest = build_root
+            .path()
+            .join("manifest")
+            .join(p.file_name().unwrap_or_else(|| OsStr::new(pkg)));
+        fs::create_dir_all(&manifest)?;
+        let xml = fs::read_to_string(p.join("package.xml"))?;
+        fs::write(manifest.join("package.xml"), strip_test_depends(&xml)?)?;
+        if p.join("CHANGELOG.rst").is_file() {
+            fs::copy(p.join("CHANGELOG.rst"), manifest.join("CHANGELOG.rst"))?;
+        }
+        p = manifest;
+    }
+
+    let o = timed(&mut timings.generate_secs, || {
+        output_with_timeout(
+            config.runner,
+            Command::new("bloom-generate")
+                .args([
+                    generator,
+                    "--os-name",
+                    os_name,
+                    "--os-version",
+                    os_version,
+                    "--ros-distro",
+                    ros_distro,
+                ])
+                .args(bloom_args)
+                .arg(&p)
+                .envs(deb_env(config))
+                .current_dir(&pb),
+            build_timeout,
+        )
+    })?;
+
+    log_output(log, "bloom-generate", &o);
+
+    if o.status.code().unwrap_or_default() != 0 {
+        error!(
+            "stdout:\n{}\n\nstderr:\n{}",
+            String::from_utf8_lossy(&o.stdout),
+            String::from_utf8_lossy(&o.stderr)
+        );
+
+        keep_build_dir(pkg, build_root, config, log);
+
+        return Err(command_error(&o, "bloom-generate failed!"));
+    }
+
+    // Passing generations may still have resolved something wrongly
+    for line in warning_lines(&String::from_utf8_lossy(&o.stderr)) {
+        warn!("{pkg}: {line}");
+    }
+
+    // Custom generators and arguments may not produce what the backend builds from
+    if !pb.join(backend.metadata_dir()).is_dir() {
+        keep_build_dir(pkg, build_root, config, log);
+
+        return Err(anyhow!(
+            "bloom-generate {generator} did not produce a {}/ directory",
+            backend.metadata_dir()
+        ));
+    }
+
+    Ok(build_root)
+}
+
+/// Lines of tool output reporting warnings.
+fn warning_lines(output: &str) -> Vec<&str> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|l| l.to_lowercase().contains("warn"))
+        .collect()
+}
+
+/// Generate and patch the packaging metadata of a package, copying it to `out` instead of
+/// building it.
+pub(crate) fn export_metadata(
+    pkg: &str,
+    path: &Path,
+    package: &Package,
+    out: &Path,
+    config: &BloomConfig,
+) -> Result<()> {
+    let mut log = String::new();
+    let mut timings = Timings::default();
+
+    let res = generate_debian(pkg, path, config, &mut timings, &mut log).and_then(|build_root| {
+        let build_dir = build_root.path().join("build");
+        let p = current_dir()?.join(path);
+        config
+            .backend
+            .patch(&p, &build_dir, package, config, &mut log)?;
+
+        let metadata = out.join(config.backend.metadata_dir());
+        if metadata.exists() {
+            fs::remove_dir_all(&metadata)?;
+        }
+        fs::create_dir_all(&metadata)?;
+        copy_dir(&build_dir.join(config.backend.metadata_dir()), &metadata)
+    });
+
+    if let Some(log_dir) = config.log_dir {
+        if let Err(e) = &res {
+            writeln!(log, "==> error\n\n{e:?}")?;
+        }
+        fs::write(log_dir.join(format!("{pkg}.log")), log)?;
+    }
+
+    res
+}
+
+/// Build the binary packages out of the generated `build_dir` and copy them to the repo.
+fn build_binary(
+    pkg: &str,
+    path: &Path,
+    build_dir: &Path,
+    package: &Package,
+    config: &BloomConfig,
+    timings: &mut Timings,
+    log: &mut String,
+) -> Result<Vec<PathBuf>> {
+    let p = current_dir()?.join(path);
+
+    let backend = config.backend;
+    timed(&mut timings.patch_secs, || {
+        backend.patch(&p, build_dir, package, config, log)
+    })?;
+    // Binary builds leave their output in the sources, which would end up in the source package
+    let mut built = vec![];
+    if config.artifacts.source() {
+        built.extend(timed(&mut timings.binary_secs, || {
+            backend.build_source(pkg, build_dir, config, log)
+        })?);
+    }
+    if config.artifacts.binary() {
+        built.extend(timed(&mut timings.binary_secs, || {
+            backend.build(pkg, build_dir, config, log)
+        })?);
+    }
+
+    let mut debs = vec![];
+
+    for origin in built {
+        debug!("{}", origin.display());
+        let target = config.package_dir.join(origin.file_name().unwrap());
+        debug!("Copied to: {}", target.display());
+        copy_verified(&origin, &target)?;
+        debs.push(target);
+    }
+
+    Ok(debs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generator_warnings() {
+        let stderr = "==> Generating debians\n\
+            WARNING: Could not resolve rosdep key 'foo'\n\
+            Deprecation warning: run_depend is deprecated\n\
+            Placing debian template files\n";
+        assert_eq!(
+            warning_lines(stderr),
+            [
+                "WARNING: Could not resolve rosdep key 'foo'",
+                "Deprecation warning: run_depend is deprecated"
+            ]
+        );
+        assert!(warning_lines("").is_empty());
+    }
+}
@@ -0,0 +1,423 @@
+//! Cache entries and checkpoints of built packages.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use rayon::iter::*;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::backend::Backend;
+use crate::bloom::BloomConfig;
+use crate::packages::OrderedPkg;
+
+/// Checkpoint of completed packages, kept in the repo path unless `--checkpoint` is given.
+pub(crate) const CHECKPOINT_FILE: &str = ".catkin-bloom-checkpoint";
+
+fn hash_dir(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+
+    for entry in WalkDir::new(path).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            let rel = entry.path().strip_prefix(path)?;
+            let data = fs::read(entry.path())?;
+            hasher.update(rel.to_string_lossy().as_bytes());
+            hasher.update((data.len() as u64).to_le_bytes());
+            hasher.update(data);
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Compute deb cache keys for every ordered package.
+///
+/// A key covers the package sources, the build target, and the keys of its workspace
+/// dependencies, so that changes propagate to the dependents.
+pub(crate) fn cache_keys(
+    ordered_pkgs: &[Vec<OrderedPkg>],
+    envs: &HashMap<&str, HashMap<String, String>>,
+    config: &BloomConfig,
+) -> Result<HashMap<String, String>> {
+    // Packages removed since the scan get no key, their builds fail with a clearer error
+    let src_hashes = ordered_pkgs
+        .par_iter()
+        .flatten()
+        .filter(|(_, _, d, _)| d.is_dir())
+        .map(|(p, _, d, _)| Ok((p.as_str(), hash_dir(d)?)))
+        .collect::<Result<HashMap<_, _>>>()?;
+
+    let mut keys = HashMap::<String, String>::new();
+
+    for (p, _, _, package) in ordered_pkgs.iter().flatten() {
+        let Some(src_hash) = src_hashes.get(p.as_str()) else {
+            continue;
+        };
+        let mut hasher = Sha256::new();
+
+        for t in [
+            config.os_name,
+            config.os_version,
+            config.ros_distro,
+            config.backend.extension(),
+            config.generator,
+            if config.provenance { "provenance" } else { "" },
+            if config.skip_test_depends {
+                "skip-test-depends"
+            } else {
+                ""
+            },
+            if config.debug_packages {
+                ""
+            } else {
+                "no-debug-packages"
+            },
+        ]
+        .into_iter()
+        .chain(config.bloom_args.iter().copied())
+        .chain(config.name_map.iter().flat_map(|(k, v)| [*k, *v]))
+        .chain(config.maintainer.into_iter().flat_map(|(n, e)| [n, e]))
+        .chain(config.deb_arch)
+        .chain([format!("{:?}", config.artifacts).as_str()])
+        {
+            hasher.update(t);
+            hasher.update([0]);
+        }
+
+        let mut env = envs
+            .get(p.as_str())
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        env.sort_unstable();
+        for (k, v) in env {
+            hasher.update(format!("{k}={v}"));
+            hasher.update([0]);
+        }
+
+        hasher.update(src_hash);
+
+        // The same dependency may be declared by several elements
+        let deps = package.all_depends().collect::<BTreeSet<_>>();
+
+        for d in deps {
+            hasher.update(&d.name);
+            for (op, v) in d.constraints() {
+                hasher.update(format!("({op} {v})"));
+            }
+            hasher.update(keys.get(&d.name).map(String::as_str).unwrap_or_default());
+            hasher.update([0]);
+        }
+
+        keys.insert(p.clone(), format!("{:x}", hasher.finalize()));
+    }
+
+    Ok(keys)
+}
+
+/// A checkpoint line of a completed package: its name, cache key and built file names, separated
+/// by tabs.
+pub(crate) fn checkpoint_entry(pkg: &str, key: &str, files: &[PathBuf]) -> String {
+    let mut entry = format!("{pkg}\t{key}");
+    for f in files {
+        write!(entry, "\t{}", f.file_name().unwrap().to_string_lossy()).unwrap();
+    }
+    entry.push('\n');
+    entry
+}
+
+/// Read the packages completed by an earlier run. Entries whose key no longer matches, or whose
+/// files are gone from the package directory, are dropped to be built again.
+pub(crate) fn read_checkpoint(
+    path: &Path,
+    keys: &HashMap<String, String>,
+    package_dir: &Path,
+) -> Result<HashMap<String, Vec<PathBuf>>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut completed = HashMap::new();
+
+    for line in contents.lines() {
+        let mut fields = line.split('\t');
+        let (Some(pkg), Some(key)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+
+        if keys.get(pkg).map(String::as_str) != Some(key) {
+            debug!("{pkg}: checkpoint is stale");
+            continue;
+        }
+
+        let files = fields.map(|f| package_dir.join(f)).collect::<Vec<_>>();
+
+        if files.iter().all(|f| f.is_file()) {
+            completed.insert(pkg.to_string(), files);
+        } else {
+            debug!("{pkg}: checkpointed files are missing");
+        }
+    }
+
+    Ok(completed)
+}
+
+/// Copy all packages from a cache entry into the package directory.
+pub(crate) fn restore_cached(
+    cached: &Path,
+    package_dir: &Path,
+    backend: &dyn Backend,
+) -> Result<Vec<PathBuf>> {
+    let mut debs = vec![];
+
+    for entry in fs::read_dir(cached)? {
+        let origin = entry?.path();
+        let extension = origin.extension().and_then(OsStr::to_str);
+        if extension.is_some_and(|e| backend.artifact_extensions().contains(&e))
+            || backend.is_source_artifact(&origin)
+        {
+            let target = package_dir.join(origin.file_name().unwrap());
+            debug!("Restored to: {}", target.display());
+            copy_verified(&origin, &target)?;
+            debs.push(target);
+        }
+    }
+
+    Ok(debs)
+}
+
+/// Sidecar file holding the SHA-256 of a copied package, in the format of `sha256sum`.
+fn checksum_path(path: &Path) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".sha256");
+    sidecar.into()
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Copy a package and check that the copy is intact, recording its checksum next to it. A file
+/// which already has a checksum recorded, like a cached one, has to match it too.
+pub(crate) fn copy_verified(origin: &Path, target: &Path) -> Result<()> {
+    let expected = hash_file(origin)?;
+
+    if let Ok(recorded) = fs::read_to_string(checksum_path(origin)) {
+        if recorded.split_whitespace().next() != Some(expected.as_str()) {
+            return Err(anyhow!(
+                "{} does not match its recorded checksum",
+                origin.display()
+            ));
+        }
+    }
+
+    fs::copy(origin, target)?;
+
+    let actual = hash_file(target)?;
+    if actual != expected {
+        return Err(anyhow!(
+            "Checksum mismatch copying {} to {}: expected {expected}, got {actual}",
+            origin.display(),
+            target.display()
+        ));
+    }
+
+    let name = target.file_name().unwrap().to_string_lossy();
+    fs::write(checksum_path(target), format!("{actual}  {name}\n"))?;
+
+    Ok(())
+}
+
+/// Store built debs under a new cache entry.
+pub(crate) fn store_cached(debs: &[PathBuf], cache_dir: &Path, key: &str) -> Result<()> {
+    fs::create_dir_all(cache_dir)?;
+
+    // Populate a temporary directory first so that a partial entry is never picked up
+    let tmp = tempfile::Builder::new()
+        .prefix(&format!(".{key}"))
+        .tempdir_in(cache_dir)?;
+
+    for deb in debs {
+        copy_verified(deb, &tmp.path().join(deb.file_name().unwrap()))?;
+    }
+
+    let entry = cache_dir.join(key);
+
+    if !entry.exists() {
+        fs::rename(tmp.into_path(), entry)?;
+    }
+
+    Ok(())
+}
+
+/// Cache entry of a package, if it was built before.
+pub(crate) fn cached_build(cache_key: Option<&str>, config: &BloomConfig) -> Option<PathBuf> {
+    let cached = config.cache_dir?.join(cache_key?);
+    cached.is_dir().then_some(cached)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{deb_name, Deb};
+    use crate::config::Artifacts;
+    use crate::manifest::{Dependency, Package};
+    use crate::runner::SystemRunner;
+    use std::collections::{BTreeMap, HashMap};
+    use tempfile::tempdir;
+
+    #[test]
+    fn cache_keys_propagate() {
+        let ws = tempdir().unwrap();
+        for p in ["a", "b"] {
+            fs::create_dir(ws.path().join(p)).unwrap();
+            fs::write(ws.path().join(p).join("package.xml"), p).unwrap();
+        }
+
+        let ordered = |root: &Path| {
+            let a_deps = Package::default();
+            let mut b_deps = Package::default();
+            b_deps.insert(
+                b"build_depend",
+                Dependency {
+                    name: "a".to_string(),
+                    ..Default::default()
+                },
+            );
+            vec![
+                vec![(
+                    "a".to_string(),
+                    deb_name("melodic", "a"),
+                    root.join("a"),
+                    a_deps,
+                )],
+                vec![(
+                    "b".to_string(),
+                    deb_name("melodic", "b"),
+                    root.join("b"),
+                    b_deps,
+                )],
+            ]
+        };
+
+        let config = BloomConfig {
+            package_dir: ws.path(),
+            os_name: "ubuntu",
+            os_version: "bionic",
+            ros_distro: "melodic",
+            cache_dir: None,
+            build_timeout: None,
+            log_dir: None,
+            backend: &Deb,
+            build_jobs: None,
+            keep_build_dirs: None,
+            generator: "rosdebian",
+            bloom_args: &[],
+            testing: None,
+            skip_test_depends: false,
+            provenance: false,
+            build_tmp: None,
+            debug_packages: true,
+            name_map: &BTreeMap::new(),
+            maintainer: None,
+            deb_arch: None,
+            artifacts: Artifacts::Binary,
+            env: &HashMap::new(),
+            runner: &SystemRunner,
+        };
+
+        let keys = cache_keys(&ordered(ws.path()), &HashMap::new(), &config).unwrap();
+        assert_eq!(
+            keys,
+            cache_keys(&ordered(ws.path()), &HashMap::new(), &config).unwrap()
+        );
+
+        fs::write(ws.path().join("a/CMakeLists.txt"), "").unwrap();
+        let changed = cache_keys(&ordered(ws.path()), &HashMap::new(), &config).unwrap();
+        assert_ne!(keys["a"], changed["a"]);
+        assert_ne!(keys["b"], changed["b"]);
+
+        let config = BloomConfig {
+            os_version: "focal",
+            ..config
+        };
+        let retargeted = cache_keys(&ordered(ws.path()), &HashMap::new(), &config).unwrap();
+        assert_ne!(changed["a"], retargeted["a"]);
+    }
+
+    #[test]
+    fn checkpoint_entries() {
+        let repo = tempdir().unwrap();
+        fs::write(repo.path().join("a_1.deb"), "").unwrap();
+        fs::write(repo.path().join("b_1.deb"), "").unwrap();
+        fs::write(repo.path().join("b-dbg_1.deb"), "").unwrap();
+
+        let files = |names: &[&str]| {
+            names
+                .iter()
+                .map(|n| repo.path().join(n))
+                .collect::<Vec<_>>()
+        };
+
+        let checkpoint = repo.path().join("checkpoint");
+        fs::write(
+            &checkpoint,
+            [
+                checkpoint_entry("a", "k1", &files(&["a_1.deb"])),
+                checkpoint_entry("b", "k2", &files(&["b_1.deb", "b-dbg_1.deb"])),
+                checkpoint_entry("c", "k3", &files(&["c_1.deb"])),
+                checkpoint_entry("d", "old", &[]),
+            ]
+            .concat(),
+        )
+        .unwrap();
+
+        let keys = HashMap::from([
+            ("a".to_string(), "k1".to_string()),
+            ("b".to_string(), "k2".to_string()),
+            ("c".to_string(), "k3".to_string()),
+            ("d".to_string(), "new".to_string()),
+        ]);
+
+        let completed = read_checkpoint(&checkpoint, &keys, repo.path()).unwrap();
+        assert_eq!(completed.len(), 2);
+        assert_eq!(completed["a"], files(&["a_1.deb"]));
+        assert_eq!(completed["b"], files(&["b_1.deb", "b-dbg_1.deb"]));
+
+        let missing = repo.path().join("missing");
+        assert!(read_checkpoint(&missing, &keys, repo.path())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn verified_copies() {
+        let dir = tempdir().unwrap();
+        let build = dir.path().join("a_1.deb");
+        let cached = dir.path().join("cached.deb");
+        let repo = dir.path().join("repo.deb");
+        fs::write(&build, "deb").unwrap();
+
+        copy_verified(&build, &cached).unwrap();
+        assert_eq!(
+            fs::read_to_string(checksum_path(&cached)).unwrap(),
+            format!("{:x}  cached.deb\n", Sha256::digest("deb"))
+        );
+        copy_verified(&cached, &repo).unwrap();
+        assert_eq!(fs::read_to_string(&repo).unwrap(), "deb");
+
+        // A cached file which changed since it was recorded doesn't get copied
+        fs::write(&cached, "de").unwrap();
+        let err = copy_verified(&cached, &repo).unwrap_err();
+        assert!(err.to_string().contains("recorded checksum"), "{err}");
+    }
+}
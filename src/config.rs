@@ -0,0 +1,1332 @@
+//! Command line interface and config file.
+
+use anyhow::{anyhow, Result};
+use clap::*;
+use glob::Pattern;
+use log::*;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::backend::deb::parse_maintainer;
+use crate::backend::{Backend, PackageFormat};
+use crate::error::EXIT_CODES_HELP;
+use crate::index::RosdepLayout;
+use crate::manifest::{ROS1_DISTROS, ROS2_DISTROS};
+
+/// Packages passed to a single installer invocation, keeping the command line below ARG_MAX.
+const DEFAULT_INSTALL_BATCH: usize = 200;
+
+/// Name and ordering number of the written source lists, after other generators' ones.
+const DEFAULT_SOURCES_PREFIX: &str = "catkin-bloom";
+
+const DEFAULT_SOURCES_PRIORITY: u32 = 99;
+
+pub fn parse_args() -> ArgMatches {
+    cli().get_matches()
+}
+
+/// Command line interface of `catkin-bloom`.
+pub fn cli() -> clap::Command<'static> {
+    clap::Command::new("catkin-bloom")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .after_help(EXIT_CODES_HELP)
+        .arg(
+            Arg::new("os-name")
+                .long("os-name")
+                .takes_value(true)
+                .default_value("ubuntu"),
+        )
+        .arg(
+            Arg::new("os-version")
+                .long("os-version")
+                .takes_value(true)
+                .default_value("bionic"),
+        )
+        .arg(
+            Arg::new("ros-distro")
+                .long("ros-distro")
+                .takes_value(true)
+                .default_value("melodic"),
+        )
+        .arg(
+            Arg::new("allow-unknown-distro")
+                .long("allow-unknown-distro")
+                .help("Accept a --ros-distro missing from the known distributions")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("ros-version")
+                .long("ros-version")
+                .help("ROS version to evaluate package conditions with, instead of the one of --ros-distro")
+                .takes_value(true)
+                .possible_values(["1", "2"]),
+        )
+        .arg(
+            Arg::new("ignore-pkgs")
+                .long("ignore-pkgs")
+                .takes_value(true)
+                .multiple_values(true)
+                .use_value_delimiter(true),
+        )
+        .arg(
+            Arg::new("changed-since")
+                .long("changed-since")
+                .help(
+                    "Only build packages with files changed since the git ref, and the \
+                    packages depending on them",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("ignore-glob")
+                .long("ignore-glob")
+                .help("Ignore packages whose name matches any of the glob patterns")
+                .takes_value(true)
+                .multiple_values(true)
+                .use_value_delimiter(true)
+                .validator(Pattern::new),
+        )
+        .arg(
+            Arg::new("only-check")
+                .long("only-check")
+                .takes_value(true)
+                .multiple_values(true)
+                .use_value_delimiter(true),
+        )
+        .arg(
+            Arg::new("package")
+                .long("package")
+                .help("Only build this package along with the workspace packages it depends on")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::new("only-check-file")
+                .long("only-check-file")
+                .help("Also only check the packages listed in a file, one per line. Blank lines and # comments are ignored")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("repo-path")
+                .long("repo-path")
+                .short('r')
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("deb-dir")
+                .long("deb-dir")
+                .help("Directory to put the built packages in, referenced by the index in the repo [default: the repo path]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("noinstall-deps")
+                .long("noinstall-deps")
+                .short('n')
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("rosdep-defs")
+                .long("rosdep-defs")
+                .short('D')
+                .help("Extra rosdep keys as key=package, expanding ${VAR} and ${VAR:-default}")
+                .takes_value(true)
+                .multiple_values(true)
+                .use_value_delimiter(true),
+        )
+        .arg(
+            Arg::new("name-map")
+                .long("name-map")
+                .help("Binary package names as pkg=deb-name, for packages not following the ros-<distro>-<name> scheme")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .use_value_delimiter(true),
+        )
+        .arg(
+            Arg::new("extra-repos")
+                .long("extra-repos")
+                .short('e')
+                .takes_value(true)
+                .multiple_values(true)
+                .use_value_delimiter(true),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .help("Read options from a TOML file instead of <src>/catkin-bloom.toml")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .help("Print the build plan without running any commands or touching the system")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("dump-graph")
+                .long("dump-graph")
+                .help("Write the ordered dependency graph to a Graphviz dot file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("cache-dir")
+                .long("cache-dir")
+                .help("Reuse debs of packages whose sources, dependencies and target are unchanged")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("install-mode")
+                .long("install-mode")
+                .help("Install debs after each layer, or all at once after building. Installing once requires build dependencies to already be available")
+                .takes_value(true)
+                .possible_values(["per-layer", "once"])
+                .default_value("per-layer"),
+        )
+        .arg(
+            Arg::new("build-timeout")
+                .long("build-timeout")
+                .help("Abort generating or building a single package after this many seconds")
+                .takes_value(true)
+                .validator(|v| v.parse::<u64>()),
+        )
+        .arg(
+            Arg::new("log-dir")
+                .long("log-dir")
+                .help("Write the build output of every package to <log-dir>/<package>.log")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("retries")
+                .long("retries")
+                .help("Retry package builds and dependency installs failing due to network errors")
+                .takes_value(true)
+                .validator(|v| v.parse::<usize>()),
+        )
+        .arg(
+            Arg::new("follow-symlinks")
+                .long("follow-symlinks")
+                .help("Follow symbolic links when searching the workspace for packages")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("no-prune")
+                .long("no-prune")
+                .help("Also search build, install, log, devel and .git directories for packages")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("prune-dir")
+                .long("prune-dir")
+                .help("Skip directories with this name when searching the workspace for packages")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::new("apt-snapshot")
+                .long("apt-snapshot")
+                .help("Install upstream dependencies from this snapshot repo URL, or date of snapshots.ros.org, e.g. 2024-01-15. The generated repos get pinned above it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("deb-maintainer")
+                .long("deb-maintainer")
+                .help("Maintainer of the built debs as \"Name <email>\", instead of the one of package.xml")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("deb-arch")
+                .long("deb-arch")
+                .help("Cross build the debs for this architecture, e.g. arm64")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .help("After building, keep rebuilding changed packages and everything depending on them")
+                .takes_value(false)
+                .conflicts_with_all(&["dry-run", "list", "rdeps", "check-graph", "audit-parse"]),
+        )
+        .arg(
+            Arg::new("only-generate")
+                .long("only-generate")
+                .help("Only generate and patch the packaging metadata, copying it to <pkg>/debian under this directory")
+                .takes_value(true)
+                .conflicts_with_all(&["watch", "manifest-only"]),
+        )
+        .arg(
+            Arg::new("underlay")
+                .long("underlay")
+                .help("Installed workspaces or package paths providing dependencies, warning about dependencies found in neither them nor the workspace. Takes colon separated lists like $ROS_PACKAGE_PATH or $AMENT_PREFIX_PATH")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::new("layer-install")
+                .long("layer-install")
+                .help("Install built debs with dpkg, or as local packages with apt-get, which also fetches their missing system dependencies")
+                .takes_value(true)
+                .possible_values(["dpkg", "apt"])
+                .default_value("dpkg"),
+        )
+        .arg(
+            Arg::new("audit-parse")
+                .long("audit-parse")
+                .help("Compare the dependencies of every package with the ones read by catkin_pkg, or a stricter parser if it is not installed, then exit without building")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("artifacts")
+                .long("artifacts")
+                .help("Build binary packages, source packages for uploading to a build farm, or both")
+                .takes_value(true)
+                .possible_values(["binary", "source", "both"])
+                .default_value("binary"),
+        )
+        .arg(
+            Arg::new("quiet")
+                .long("quiet")
+                .short('q')
+                .help("Only print warnings and errors, without progress")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("progress")
+                .long("progress")
+                .help("Show build progress as a bar, or as a line per finished package. Defaults to the bar on terminals")
+                .takes_value(true)
+                .possible_values(["auto", "bar", "plain"])
+                .default_value("auto"),
+        )
+        .arg(
+            Arg::new("sources-prefix")
+                .long("sources-prefix")
+                .help("Base name of the written rosdep and package manager source lists")
+                .takes_value(true)
+                .validator(|v| match v {
+                    "" => Err("must not be empty"),
+                    v if v.contains('/') => Err("must not contain '/'"),
+                    _ => Ok(()),
+                })
+                .default_value(DEFAULT_SOURCES_PREFIX),
+        )
+        .arg(
+            Arg::new("sources-priority")
+                .long("sources-priority")
+                .help("Number the source lists start with, ordering them among the other lists")
+                .takes_value(true)
+                .validator(|v| v.parse::<u32>()),
+        )
+        .arg(
+            Arg::new("write-metadata")
+                .long("write-metadata")
+                .help("Write the maintainers and licenses of all packages to metadata.json in the repo")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("allow-empty")
+                .long("allow-empty")
+                .help("Only warn instead of failing when the source trees contain no packages")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("skip-if-upstream-matches")
+                .long("skip-if-upstream-matches")
+                .help("Skip unmodified packages whose version is available from the apt repositories, unless their dependencies get rebuilt")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("no-rosdep-update")
+                .long("no-rosdep-update")
+                .help("Trust the existing rosdep cache instead of updating it. A stale cache may not resolve the workspace packages")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("package-format")
+                .long("package-format")
+                .help("Format of the built binary packages")
+                .takes_value(true)
+                .possible_values(["deb", "rpm"])
+                .default_value("deb"),
+        )
+        .arg(
+            Arg::new("sources-dir")
+                .long("sources-dir")
+                .help(
+                    "Base directory of the rosdep and package manager source lists. \
+                    $ROSDEP_SOURCE_PATH overrides the rosdep one",
+                )
+                .takes_value(true)
+                .default_value("/etc"),
+        )
+        .arg(
+            Arg::new("keep-build-dirs")
+                .long("keep-build-dirs")
+                .help("Move build directories of failed packages into the given directory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("generator")
+                .long("generator")
+                .help("bloom-generate generator to use instead of the one of --package-format")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("bloom-arg")
+                .long("bloom-arg")
+                .help("Extra argument passed to bloom-generate, may be repeated")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .number_of_values(1)
+                .allow_hyphen_values(true),
+        )
+        .arg(
+            Arg::new("no-tests")
+                .long("no-tests")
+                .help("Build packages with BUILD_TESTING off and skip running their tests")
+                .takes_value(false)
+                .conflicts_with("run-tests"),
+        )
+        .arg(
+            Arg::new("run-tests")
+                .long("run-tests")
+                .help("Build packages with BUILD_TESTING on")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("skip-test-depends")
+                .long("skip-test-depends")
+                .help(
+                    "Ignore test_depend entries of workspace packages entirely. Unlike \
+                    --no-tests, which only stops building and running tests, the dependencies \
+                    are dropped from the build graph and from the generated packaging metadata",
+                )
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("time-budget")
+                .long("time-budget")
+                .help(
+                    "Stop starting package builds after this many seconds, index what was \
+                    built and exit with code 2",
+                )
+                .takes_value(true)
+                .validator(|v| v.parse::<u64>()),
+        )
+        .arg(
+            Arg::new("keep-going")
+                .long("keep-going")
+                .short('k')
+                .help("Build every package of a layer even if some fail, then report all failures")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("manifest-only")
+                .long("manifest-only")
+                .help(
+                    "Skip building and regenerate package.yaml, the source lists and the index \
+                    from the packages already in the repo",
+                )
+                .takes_value(false)
+                .conflicts_with("verify"),
+        )
+        .arg(
+            Arg::new("compress-manifest")
+                .long("compress-manifest")
+                .help("Also write the package index as Packages.gz")
+                .takes_value(true)
+                .possible_values(["true", "false"])
+                .default_value("true"),
+        )
+        .arg(
+            Arg::new("gen-release")
+                .long("gen-release")
+                .help("Write an apt Release file next to the package index, always done unless --flat-repo is given")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("flat-repo")
+                .long("flat-repo")
+                .help("Keep the package index at the repo root instead of under dists/<os-version>, as earlier versions did")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("verify")
+                .long("verify")
+                .help("Check that every built package installs from the generated repo")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .help("Write a JSON summary of the build to the given path")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("strict")
+                .long("strict")
+                .help("Fail instead of skipping packages with a malformed package.xml")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("strict-cycles")
+                .long("strict-cycles")
+                .help("Fail instead of warning when dependency cycles are found")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("check-graph")
+                .long("check-graph")
+                .help("Check the dependency graph for cycles, then exit without building")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("break-edge")
+                .long("break-edge")
+                .help("Ignore the dependency of a package on another as from:to, e.g. to break a cycle")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::new("pin-priority")
+                .long("pin-priority")
+                .help("Pin the packages of the generated repos to this apt priority under <sources-dir>/apt/preferences.d, 1001 allows downgrades")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .validator(|v| v.parse::<i32>()),
+        )
+        .arg(
+            Arg::new("strict-deps")
+                .long("strict-deps")
+                .help("Fail instead of warning when packages depend on ignored workspace packages")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("strict-rosdep")
+                .long("strict-rosdep")
+                .help("Fail before building when rosdep check reports unresolvable keys or errors")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("rosdep-layout")
+                .long("rosdep-layout")
+                .help("Key the rules of package.yaml by the OS name only, or by the OS name and version")
+                .takes_value(true)
+                .possible_values(["flat", "versioned"])
+                .default_value("flat"),
+        )
+        .arg(
+            Arg::new("build-jobs")
+                .long("build-jobs")
+                .help(
+                    "Parallel jobs within each package build. Up to --jobs packages are built \
+                    at once, so the total is about --jobs times --build-jobs",
+                )
+                .takes_value(true)
+                .validator(|v| v.parse::<usize>()),
+        )
+        .arg(
+            Arg::new("install-batch")
+                .long("install-batch")
+                .help("Install at most this many packages per installer invocation")
+                .takes_value(true)
+                .validator(|v| match v.parse::<usize>() {
+                    Ok(0) => Err("must be at least 1".to_owned()),
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e.to_string()),
+                }),
+        )
+        .arg(
+            Arg::new("no-color")
+                .long("no-color")
+                .help("Print status messages without colors, also disabled by setting NO_COLOR")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("from-repos")
+                .long("from-repos")
+                .help("Clone the git repositories listed in a .repos or .rosinstall file into src before collecting packages")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("resume")
+                .long("resume")
+                .help("Reuse packages an interrupted run completed, unless their sources or dependencies changed since")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("checkpoint")
+                .long("checkpoint")
+                .help("File recording completed packages for --resume [default: .catkin-bloom-checkpoint in the repo path]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("list")
+                .long("list")
+                .help("Print the workspace packages with their dependencies and layers, then exit")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .help("Output format of --list")
+                .takes_value(true)
+                .possible_values(["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::new("add-provenance")
+                .long("add-provenance")
+                .help("Record the source path, git commit and catkin-bloom version in an XB-Catkin-Bloom-Source field of every deb")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("rdeps")
+                .long("rdeps")
+                .help("Print the packages that have to be rebuilt when the given one changes, in build order, then exit")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::new("build-tmp")
+                .long("build-tmp")
+                .help("Directory to create package build roots in, instead of the system temp directory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("no-install-recommends")
+                .long("no-install-recommends")
+                .help("Install system dependencies without the packages they recommend")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("no-debug-packages")
+                .long("no-debug-packages")
+                .help("Leave out the debug symbol packages (.ddeb, -dbgsym, -debuginfo) of built packages")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("warn-deep-graph")
+                .long("warn-deep-graph")
+                .help("Warn when the number of build layers exceeds this percentage of the package count")
+                .takes_value(true)
+                .validator(|v| v.parse::<usize>()),
+        )
+        .arg(
+            Arg::new("command-prefix")
+                .long("command-prefix")
+                .help("Command to run rosdep and the system package manager through, e.g. 'sudo'")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("min-free-space")
+                .long("min-free-space")
+                .help("Fail before building unless the build tmp and repo file systems have this many MiB free")
+                .takes_value(true)
+                .validator(|v| v.parse::<u64>()),
+        )
+        .arg(
+            Arg::new("space-per-package")
+                .long("space-per-package")
+                .help("MiB of disk space a package build is assumed to need, warning before building when less is free [default: 100]")
+                .takes_value(true)
+                .validator(|v| v.parse::<u64>()),
+        )
+        .arg(Arg::new("jobs").long("jobs").short('j').takes_value(true))
+        .arg(
+            Arg::new("src")
+                .help("Source trees to collect packages from, resolving dependencies across all of them")
+                .takes_value(true)
+                .multiple_values(true)
+                .default_value("."),
+        )
+}
+
+/// Options of a run, as given on the command line and in the config file.
+#[derive(Debug, Clone)]
+pub struct RuntimeArgs<'a> {
+    pub(crate) os_name: &'a str,
+    pub(crate) os_version: &'a str,
+    pub(crate) ros_distro: &'a str,
+    pub(crate) repo_path: &'a str,
+    pub(crate) ignored_pkgs: Vec<&'a str>,
+    pub(crate) ignored_globs: Vec<Pattern>,
+    pub(crate) extra_repos: Vec<&'a str>,
+    pub(crate) only_check: Option<Vec<&'a str>>,
+    pub(crate) rosdep_defs: Vec<(String, String)>,
+    pub(crate) srcs: Vec<&'a str>,
+    pub(crate) jobs: usize,
+    pub(crate) noinstall_deps: bool,
+    pub(crate) strict_cycles: bool,
+    pub(crate) dry_run: bool,
+    pub(crate) dump_graph: Option<&'a str>,
+    pub(crate) cache_dir: Option<&'a str>,
+    pub(crate) install_mode: InstallMode,
+    pub(crate) build_timeout: Option<Duration>,
+    pub(crate) log_dir: Option<&'a str>,
+    pub(crate) retries: usize,
+    pub(crate) follow_symlinks: bool,
+    pub(crate) backend: &'static dyn Backend,
+    pub(crate) strict: bool,
+    pub(crate) build_jobs: Option<usize>,
+    pub(crate) changed_since: Option<&'a str>,
+    pub(crate) report: Option<&'a str>,
+    pub(crate) keep_build_dirs: Option<&'a str>,
+    pub(crate) sources_dir: &'a str,
+    pub(crate) verify: bool,
+    pub(crate) manifest_only: bool,
+    pub(crate) keep_going: bool,
+    pub(crate) generator: Option<&'a str>,
+    pub(crate) bloom_args: Vec<&'a str>,
+    pub(crate) testing: Option<bool>,
+    pub(crate) time_budget: Option<Duration>,
+    pub(crate) gen_release: bool,
+    pub(crate) compress_manifest: bool,
+    pub(crate) install_batch: usize,
+    pub(crate) color: bool,
+    pub(crate) from_repos: Option<&'a str>,
+    pub(crate) skip_test_depends: bool,
+    pub(crate) resume: bool,
+    pub(crate) checkpoint: Option<&'a str>,
+    pub(crate) list: Option<&'a str>,
+    pub(crate) add_provenance: bool,
+    pub(crate) only_check_file: Option<&'a str>,
+    pub(crate) rdeps: Vec<&'a str>,
+    pub(crate) flat_repo: bool,
+    pub(crate) build_tmp: Option<&'a str>,
+    pub(crate) strict_deps: bool,
+    pub(crate) no_install_recommends: bool,
+    pub(crate) selected_pkgs: Vec<&'a str>,
+    pub(crate) no_debug_packages: bool,
+    pub(crate) warn_deep_graph: Option<usize>,
+    pub(crate) command_prefix: Vec<&'a str>,
+    pub(crate) strict_rosdep: bool,
+    pub(crate) rosdep_layout: RosdepLayout,
+    pub(crate) min_free_space: Option<u64>,
+    pub(crate) space_per_package: u64,
+    pub(crate) ros_version: Option<&'a str>,
+    pub(crate) name_map: BTreeMap<&'a str, &'a str>,
+    pub(crate) check_graph: bool,
+    pub(crate) break_edges: Vec<(&'a str, &'a str)>,
+    pub(crate) pin_priority: Option<i32>,
+    pub(crate) no_prune: bool,
+    pub(crate) prune_dirs: Vec<&'a str>,
+    pub(crate) apt_snapshot: Option<&'a str>,
+    pub(crate) deb_maintainer: Option<(&'a str, &'a str)>,
+    pub(crate) deb_arch: Option<&'a str>,
+    pub(crate) watch: bool,
+    pub(crate) only_generate: Option<&'a str>,
+    pub(crate) underlays: Vec<&'a str>,
+    pub(crate) layer_install: LayerInstall,
+    pub(crate) audit_parse: bool,
+    pub(crate) artifacts: Artifacts,
+    pub(crate) quiet: bool,
+    pub(crate) progress: Progress,
+    pub(crate) sources_prefix: &'a str,
+    pub(crate) sources_priority: u32,
+    pub(crate) write_metadata: bool,
+    pub(crate) allow_empty: bool,
+    pub(crate) skip_if_upstream_matches: bool,
+    pub(crate) deb_dir: Option<&'a str>,
+    pub(crate) no_rosdep_update: bool,
+    pub(crate) build_env: HashMap<String, String>,
+    pub(crate) package_env: HashMap<&'a str, HashMap<String, String>>,
+}
+
+/// Package names of a list file, one per line. `#` starts a comment.
+pub(crate) fn parse_package_list(contents: &str) -> impl Iterator<Item = &str> {
+    contents
+        .lines()
+        .map(|l| l.split('#').next().unwrap().trim())
+        .filter(|l| !l.is_empty())
+}
+
+/// Reject distributions missing from the known ones, suggesting the closest of them.
+fn check_ros_distro(ros_distro: &str, allow_unknown: bool) -> Result<&str> {
+    let known = ROS1_DISTROS.iter().chain(ROS2_DISTROS);
+
+    if allow_unknown || known.clone().any(|d| *d == ros_distro) {
+        return Ok(ros_distro);
+    }
+
+    let closest = known
+        .map(|d| (edit_distance(d, ros_distro), d))
+        .min()
+        .filter(|(n, _)| *n <= 2);
+
+    match closest {
+        Some((_, d)) => Err(anyhow!(
+            "Unknown ROS distribution {ros_distro}, did you mean {d}? \
+            Pass --allow-unknown-distro to use it anyway"
+        )),
+        None => Err(anyhow!(
+            "Unknown ROS distribution {ros_distro}, pass --allow-unknown-distro to use it anyway"
+        )),
+    }
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let subst = diag + usize::from(ca != *cb);
+            diag = row[j + 1];
+            row[j + 1] = subst.min(row[j] + 1).min(diag + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+impl<'a> RuntimeArgs<'a> {
+    pub fn new(matches: &'a ArgMatches, config: &'a Config) -> Result<Self> {
+        let expand_env = |env: &BTreeMap<String, String>| {
+            env.iter()
+                .map(|(k, v)| Ok((k.clone(), expand_vars(v, |var| std::env::var(var).ok())?)))
+                .collect::<Result<HashMap<_, _>>>()
+        };
+
+        Ok(Self {
+            os_name: arg_value(matches, "os-name", &config.os_name).unwrap(),
+            os_version: arg_value(matches, "os-version", &config.os_version).unwrap(),
+            ros_distro: check_ros_distro(
+                arg_value(matches, "ros-distro", &config.ros_distro).unwrap(),
+                matches.is_present("allow-unknown-distro") || config.allow_unknown_distro,
+            )?,
+            // Listing packages never touches the repo
+            repo_path: arg_value(matches, "repo-path", &config.repo_path)
+                .or_else(|| {
+                    (matches.is_present("list")
+                        || matches.is_present("rdeps")
+                        || matches.is_present("check-graph"))
+                    .then_some("")
+                })
+                .ok_or_else(|| {
+                    anyhow!("repo-path must be set on the command line or in the config")
+                })?,
+            ignored_pkgs: arg_values(matches, "ignore-pkgs", &config.ignore_pkgs),
+            ignored_globs: arg_values(matches, "ignore-glob", &config.ignore_glob)
+                .into_iter()
+                .map(|g| Pattern::new(g).map_err(|e| anyhow!("Invalid glob '{g}': {e}")))
+                .collect::<Result<_>>()?,
+            extra_repos: arg_values(matches, "extra-repos", &config.extra_repos),
+            rosdep_defs: match matches.values_of("rosdep-defs") {
+                Some(defs) => defs.filter_map(|l| l.split_once('=')).collect::<Vec<_>>(),
+                None => config
+                    .rosdep_defs
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect::<Vec<_>>(),
+            }
+            .into_iter()
+            .map(|(k, v)| {
+                let env = |var: &str| std::env::var(var).ok();
+                Ok((expand_vars(k, env)?, expand_vars(v, env)?))
+            })
+            .collect::<Result<_>>()?,
+            only_check: matches
+                .values_of("only-check")
+                .map(Iterator::collect)
+                .or_else(|| {
+                    config
+                        .only_check
+                        .as_ref()
+                        .map(|v| v.iter().map(String::as_str).collect())
+                }),
+            srcs: matches.values_of("src").unwrap().collect(),
+            jobs: matches
+                .value_of("jobs")
+                .and_then(|j| j.parse().ok())
+                .or(config.jobs)
+                .unwrap_or(1),
+            noinstall_deps: matches.is_present("noinstall-deps") || config.noinstall_deps,
+            strict_cycles: matches.is_present("strict-cycles") || config.strict_cycles,
+            dry_run: matches.is_present("dry-run") || config.dry_run,
+            dump_graph: arg_value(matches, "dump-graph", &config.dump_graph),
+            cache_dir: arg_value(matches, "cache-dir", &config.cache_dir),
+            install_mode: arg_value(matches, "install-mode", &config.install_mode)
+                .unwrap()
+                .parse()?,
+            build_timeout: matches
+                .value_of("build-timeout")
+                .map(|t| t.parse().unwrap())
+                .or(config.build_timeout)
+                .map(Duration::from_secs),
+            log_dir: arg_value(matches, "log-dir", &config.log_dir),
+            retries: matches
+                .value_of("retries")
+                .map(|r| r.parse().unwrap())
+                .or(config.retries)
+                .unwrap_or_default(),
+            follow_symlinks: matches.is_present("follow-symlinks") || config.follow_symlinks,
+            backend: arg_value(matches, "package-format", &config.package_format)
+                .unwrap()
+                .parse::<PackageFormat>()?
+                .backend(),
+            strict: matches.is_present("strict") || config.strict,
+            build_jobs: matches
+                .value_of("build-jobs")
+                .map(|j| j.parse().unwrap())
+                .or(config.build_jobs),
+            changed_since: arg_value(matches, "changed-since", &config.changed_since),
+            report: arg_value(matches, "report", &config.report),
+            keep_build_dirs: arg_value(matches, "keep-build-dirs", &config.keep_build_dirs),
+            sources_dir: arg_value(matches, "sources-dir", &config.sources_dir).unwrap(),
+            verify: matches.is_present("verify") || config.verify,
+            manifest_only: matches.is_present("manifest-only") || config.manifest_only,
+            keep_going: matches.is_present("keep-going") || config.keep_going,
+            generator: arg_value(matches, "generator", &config.generator),
+            bloom_args: arg_values(matches, "bloom-arg", &config.bloom_args),
+            testing: if matches.is_present("no-tests") {
+                Some(false)
+            } else if matches.is_present("run-tests") {
+                Some(true)
+            } else {
+                config.tests
+            },
+            time_budget: matches
+                .value_of("time-budget")
+                .map(|t| t.parse().unwrap())
+                .or(config.time_budget)
+                .map(Duration::from_secs),
+            gen_release: matches.is_present("gen-release") || config.gen_release,
+            compress_manifest: match matches.value_source("compress-manifest") {
+                Some(ValueSource::CommandLine) => None,
+                _ => config.compress_manifest,
+            }
+            .unwrap_or_else(|| matches.value_of("compress-manifest") == Some("true")),
+            install_batch: matches
+                .value_of("install-batch")
+                .map(|b| b.parse().unwrap())
+                .or(config.install_batch)
+                .unwrap_or(DEFAULT_INSTALL_BATCH),
+            color: !(matches.is_present("no-color") || config.no_color)
+                && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                && console::colors_enabled(),
+            from_repos: arg_value(matches, "from-repos", &config.from_repos),
+            skip_test_depends: matches.is_present("skip-test-depends") || config.skip_test_depends,
+            resume: matches.is_present("resume") || config.resume,
+            checkpoint: arg_value(matches, "checkpoint", &config.checkpoint),
+            add_provenance: matches.is_present("add-provenance") || config.add_provenance,
+            only_check_file: arg_value(matches, "only-check-file", &config.only_check_file),
+            flat_repo: matches.is_present("flat-repo") || config.flat_repo,
+            build_tmp: arg_value(matches, "build-tmp", &config.build_tmp),
+            strict_deps: matches.is_present("strict-deps") || config.strict_deps,
+            no_install_recommends: matches.is_present("no-install-recommends")
+                || config.no_install_recommends,
+            selected_pkgs: arg_values(matches, "package", &config.package),
+            no_debug_packages: matches.is_present("no-debug-packages") || config.no_debug_packages,
+            warn_deep_graph: matches
+                .value_of("warn-deep-graph")
+                .map(|p| p.parse().unwrap())
+                .or(config.warn_deep_graph),
+            command_prefix: arg_value(matches, "command-prefix", &config.command_prefix)
+                .map(|p| p.split_whitespace().collect())
+                .unwrap_or_default(),
+            strict_rosdep: matches.is_present("strict-rosdep") || config.strict_rosdep,
+            rosdep_layout: arg_value(matches, "rosdep-layout", &config.rosdep_layout)
+                .unwrap()
+                .parse()?,
+            min_free_space: matches
+                .value_of("min-free-space")
+                .map(|s| s.parse().unwrap())
+                .or(config.min_free_space),
+            space_per_package: matches
+                .value_of("space-per-package")
+                .map(|s| s.parse().unwrap())
+                .or(config.space_per_package)
+                .unwrap_or(DEFAULT_SPACE_PER_PACKAGE),
+            ros_version: arg_value(matches, "ros-version", &config.ros_version),
+            name_map: match matches.values_of("name-map") {
+                Some(maps) => maps
+                    .map(|m| {
+                        m.split_once('=').ok_or_else(|| {
+                            anyhow!("Invalid name mapping '{m}', expected pkg=deb-name")
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+                None => config
+                    .name_map
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect(),
+            },
+            check_graph: matches.is_present("check-graph"),
+            break_edges: arg_values(matches, "break-edge", &config.break_edge)
+                .into_iter()
+                .map(|e| {
+                    e.split_once(':')
+                        .ok_or_else(|| anyhow!("Invalid edge '{e}', expected from:to"))
+                })
+                .collect::<Result<_>>()?,
+            pin_priority: matches
+                .value_of("pin-priority")
+                .map(|p| p.parse().unwrap())
+                .or(config.pin_priority),
+            no_prune: matches.is_present("no-prune") || config.no_prune,
+            prune_dirs: arg_values(matches, "prune-dir", &config.prune_dir),
+            apt_snapshot: arg_value(matches, "apt-snapshot", &config.apt_snapshot),
+            deb_maintainer: arg_value(matches, "deb-maintainer", &config.deb_maintainer)
+                .map(parse_maintainer)
+                .transpose()?,
+            deb_arch: arg_value(matches, "deb-arch", &config.deb_arch),
+            watch: matches.is_present("watch"),
+            only_generate: matches.value_of("only-generate"),
+            underlays: arg_values(matches, "underlay", &config.underlay)
+                .into_iter()
+                .flat_map(|p| p.split(':'))
+                .filter(|p| !p.is_empty())
+                .collect(),
+            layer_install: arg_value(matches, "layer-install", &config.layer_install)
+                .unwrap()
+                .parse()?,
+            audit_parse: matches.is_present("audit-parse") || config.audit_parse,
+            artifacts: arg_value(matches, "artifacts", &config.artifacts)
+                .unwrap()
+                .parse()?,
+            quiet: matches.is_present("quiet") || config.quiet,
+            progress: arg_value(matches, "progress", &config.progress)
+                .unwrap()
+                .parse()?,
+            sources_prefix: arg_value(matches, "sources-prefix", &config.sources_prefix).unwrap(),
+            sources_priority: matches
+                .value_of("sources-priority")
+                .map(|p| p.parse().unwrap())
+                .or(config.sources_priority)
+                .unwrap_or(DEFAULT_SOURCES_PRIORITY),
+            write_metadata: matches.is_present("write-metadata") || config.write_metadata,
+            allow_empty: matches.is_present("allow-empty") || config.allow_empty,
+            skip_if_upstream_matches: matches.is_present("skip-if-upstream-matches")
+                || config.skip_if_upstream_matches,
+            deb_dir: arg_value(matches, "deb-dir", &config.deb_dir),
+            no_rosdep_update: matches.is_present("no-rosdep-update") || config.no_rosdep_update,
+            build_env: expand_env(&config.env)?,
+            package_env: config
+                .package_env
+                .iter()
+                .map(|(p, env)| Ok((p.as_str(), expand_env(env)?)))
+                .collect::<Result<_>>()?,
+            rdeps: matches
+                .values_of("rdeps")
+                .map(Iterator::collect)
+                .unwrap_or_default(),
+            list: matches
+                .is_present("list")
+                .then(|| matches.value_of("format").unwrap()),
+        })
+    }
+}
+
+/// Disk space assumed per package build by default, in MiB.
+const DEFAULT_SPACE_PER_PACKAGE: u64 = 100;
+
+/// Expand `${VAR}` and `${VAR:-default}` references through `lookup`.
+fn expand_vars(s: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = s;
+
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unterminated variable reference in '{s}'"))?;
+        let reference = &rest[start + 2..start + end];
+
+        let (var, default) = match reference.split_once(":-") {
+            Some((var, default)) => (var, Some(default)),
+            None => (reference, None),
+        };
+
+        match lookup(var) {
+            Some(v) => out.push_str(&v),
+            None => out.push_str(default.ok_or_else(|| {
+                anyhow!("Environment variable {var} referenced in '{s}' is not set")
+            })?),
+        }
+
+        rest = &rest[start + end + 1..];
+    }
+
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+/// Value given on the command line, falling back to the config file, and then to the default.
+fn arg_value<'a>(matches: &'a ArgMatches, id: &str, file: &'a Option<String>) -> Option<&'a str> {
+    match matches.value_source(id) {
+        Some(ValueSource::DefaultValue) | None => file.as_deref().or_else(|| matches.value_of(id)),
+        _ => matches.value_of(id),
+    }
+}
+
+/// Values given on the command line, falling back to the config file.
+fn arg_values<'a>(matches: &'a ArgMatches, id: &str, file: &'a [String]) -> Vec<&'a str> {
+    match matches.values_of(id) {
+        Some(values) => values.collect(),
+        None => file.iter().map(String::as_str).collect(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InstallMode {
+    PerLayer,
+    Once,
+}
+
+impl std::str::FromStr for InstallMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "per-layer" => Ok(Self::PerLayer),
+            "once" => Ok(Self::Once),
+            _ => Err(anyhow!("Invalid install mode '{s}'")),
+        }
+    }
+}
+
+/// Kinds of packages built out of the packaging metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Artifacts {
+    Binary,
+    Source,
+    Both,
+}
+
+impl Artifacts {
+    pub(crate) fn binary(self) -> bool {
+        self != Self::Source
+    }
+
+    pub(crate) fn source(self) -> bool {
+        self != Self::Binary
+    }
+}
+
+impl std::str::FromStr for Artifacts {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "binary" => Ok(Self::Binary),
+            "source" => Ok(Self::Source),
+            "both" => Ok(Self::Both),
+            _ => Err(anyhow!("Invalid artifacts '{s}'")),
+        }
+    }
+}
+
+/// How build progress is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Progress {
+    /// A bar on terminals, lines otherwise.
+    Auto,
+    Bar,
+    Plain,
+}
+
+impl std::str::FromStr for Progress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "bar" => Ok(Self::Bar),
+            "plain" => Ok(Self::Plain),
+            _ => Err(anyhow!("Invalid progress '{s}'")),
+        }
+    }
+}
+
+/// Tool installing the built packages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LayerInstall {
+    Dpkg,
+    Apt,
+}
+
+impl std::str::FromStr for LayerInstall {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dpkg" => Ok(Self::Dpkg),
+            "apt" => Ok(Self::Apt),
+            _ => Err(anyhow!("Invalid layer install tool '{s}'")),
+        }
+    }
+}
+
+/// Options read from `catkin-bloom.toml`. Keys are named after the long command line flags.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    os_name: Option<String>,
+    os_version: Option<String>,
+    ros_distro: Option<String>,
+    repo_path: Option<String>,
+    ignore_pkgs: Vec<String>,
+    ignore_glob: Vec<String>,
+    extra_repos: Vec<String>,
+    only_check: Option<Vec<String>>,
+    rosdep_defs: BTreeMap<String, String>,
+    jobs: Option<usize>,
+    noinstall_deps: bool,
+    strict_cycles: bool,
+    dry_run: bool,
+    dump_graph: Option<String>,
+    cache_dir: Option<String>,
+    install_mode: Option<String>,
+    build_timeout: Option<u64>,
+    log_dir: Option<String>,
+    retries: Option<usize>,
+    follow_symlinks: bool,
+    package_format: Option<String>,
+    strict: bool,
+    build_jobs: Option<usize>,
+    changed_since: Option<String>,
+    report: Option<String>,
+    keep_build_dirs: Option<String>,
+    sources_dir: Option<String>,
+    verify: bool,
+    manifest_only: bool,
+    keep_going: bool,
+    generator: Option<String>,
+    bloom_args: Vec<String>,
+    tests: Option<bool>,
+    time_budget: Option<u64>,
+    gen_release: bool,
+    compress_manifest: Option<bool>,
+    install_batch: Option<usize>,
+    no_color: bool,
+    from_repos: Option<String>,
+    skip_test_depends: bool,
+    resume: bool,
+    checkpoint: Option<String>,
+    allow_unknown_distro: bool,
+    add_provenance: bool,
+    only_check_file: Option<String>,
+    flat_repo: bool,
+    build_tmp: Option<String>,
+    strict_deps: bool,
+    no_install_recommends: bool,
+    package: Vec<String>,
+    no_debug_packages: bool,
+    warn_deep_graph: Option<usize>,
+    command_prefix: Option<String>,
+    strict_rosdep: bool,
+    rosdep_layout: Option<String>,
+    min_free_space: Option<u64>,
+    space_per_package: Option<u64>,
+    ros_version: Option<String>,
+    name_map: BTreeMap<String, String>,
+    break_edge: Vec<String>,
+    pin_priority: Option<i32>,
+    no_prune: bool,
+    prune_dir: Vec<String>,
+    apt_snapshot: Option<String>,
+    deb_maintainer: Option<String>,
+    deb_arch: Option<String>,
+    underlay: Vec<String>,
+    layer_install: Option<String>,
+    audit_parse: bool,
+    artifacts: Option<String>,
+    quiet: bool,
+    progress: Option<String>,
+    sources_prefix: Option<String>,
+    sources_priority: Option<u32>,
+    write_metadata: bool,
+    allow_empty: bool,
+    skip_if_upstream_matches: bool,
+    deb_dir: Option<String>,
+    no_rosdep_update: bool,
+    /// Environment of every package build.
+    env: BTreeMap<String, String>,
+    /// Environment of the builds of single packages, on top of `env`.
+    package_env: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// Load the config given by `--config`, or `catkin-bloom.toml` at the workspace root if present.
+pub fn load_config(matches: &ArgMatches) -> Result<Config> {
+    let path = match matches.value_of("config") {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let path = Path::new(matches.value_of("src").unwrap()).join("catkin-bloom.toml");
+            if !path.exists() {
+                return Ok(Config::default());
+            }
+            path
+        }
+    };
+
+    debug!("Loading config from {}", path.display());
+
+    let config =
+        fs::read_to_string(&path).map_err(|e| anyhow!("Failed to read {}: {e}", path.display()))?;
+
+    toml::from_str(&config).map_err(|e| anyhow!("Failed to parse {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ros_distros() {
+        assert_eq!(edit_distance("melodic", "meldoic"), 2);
+        assert_eq!(edit_distance("", "foxy"), 4);
+        assert_eq!(edit_distance("humble", "humble"), 0);
+
+        assert_eq!(check_ros_distro("noetic", false).unwrap(), "noetic");
+        assert_eq!(check_ros_distro("jazzy", false).unwrap(), "jazzy");
+
+        let err = check_ros_distro("meldoic", false).unwrap_err().to_string();
+        assert!(err.contains("did you mean melodic?"), "{err}");
+        let err = check_ros_distro("humbel", false).unwrap_err().to_string();
+        assert!(err.contains("did you mean humble?"), "{err}");
+        let err = check_ros_distro("mydistro", false).unwrap_err().to_string();
+        assert!(!err.contains("did you mean"), "{err}");
+
+        assert_eq!(check_ros_distro("mydistro", true).unwrap(), "mydistro");
+    }
+
+    #[test]
+    fn package_lists() {
+        let list = "# core\nfoo\n\n  bar  \nbaz # flaky\n   # indented comment\n";
+        assert_eq!(
+            parse_package_list(list).collect::<Vec<_>>(),
+            ["foo", "bar", "baz"]
+        );
+    }
+
+    #[test]
+    fn expand_env_vars() {
+        let env = |var: &str| (var == "MIRROR").then(|| "local".to_string());
+
+        assert_eq!(expand_vars("libfoo-dev", env).unwrap(), "libfoo-dev");
+        assert_eq!(expand_vars("${MIRROR}-foo", env).unwrap(), "local-foo");
+        assert_eq!(
+            expand_vars("a${MIRROR}b${MIRROR}", env).unwrap(),
+            "alocalblocal"
+        );
+        assert_eq!(expand_vars("${UNSET:-foo}", env).unwrap(), "foo");
+        assert_eq!(expand_vars("${MIRROR:-foo}", env).unwrap(), "local");
+        assert_eq!(expand_vars("${UNSET:-}x", env).unwrap(), "x");
+        assert!(expand_vars("${UNSET}", env).is_err());
+        assert!(expand_vars("${MIRROR", env).is_err());
+    }
+}
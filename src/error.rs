@@ -0,0 +1,93 @@
+//! Failure categories and their exit codes.
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Exit code of runs stopped by dependency cycles.
+pub const EXIT_DEPENDENCY_CYCLE: i32 = 2;
+
+/// Exit code of runs where a package failed to build.
+pub const EXIT_BUILD_FAILED: i32 = 3;
+
+/// Exit code of runs where dependencies or built packages failed to install.
+pub const EXIT_INSTALL_FAILED: i32 = 4;
+
+/// Exit code of runs missing the tools they need.
+pub const EXIT_MISSING_PREREQUISITES: i32 = 5;
+
+/// Exit code of runs cut short, by a command exceeding its timeout, the `--time-budget` or
+/// SIGINT.
+pub const EXIT_TIMEOUT_OR_INTERRUPT: i32 = 6;
+
+/// Exit codes of the failure categories, as listed in the help.
+pub(crate) const EXIT_CODES_HELP: &str = "EXIT CODES:
+    1    Any other failure
+    2    Dependency cycle
+    3    Build failure
+    4    Install failure
+    5    Missing prerequisites
+    6    Command timeout, exceeded time budget or interrupt";
+
+/// Failures with an exit code of their own, carried by the errors of a run. Any other failure
+/// exits with 1.
+#[derive(Debug)]
+pub enum BloomError {
+    DependencyCycle(String),
+    Build(String),
+    Install(String),
+    MissingPrerequisites(String),
+    Timeout(String),
+    TimeBudget(String),
+    Interrupted(String),
+}
+
+impl BloomError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::DependencyCycle(_) => EXIT_DEPENDENCY_CYCLE,
+            Self::Build(_) => EXIT_BUILD_FAILED,
+            Self::Install(_) => EXIT_INSTALL_FAILED,
+            Self::MissingPrerequisites(_) => EXIT_MISSING_PREREQUISITES,
+            Self::Timeout(_) | Self::TimeBudget(_) | Self::Interrupted(_) => {
+                EXIT_TIMEOUT_OR_INTERRUPT
+            }
+        }
+    }
+}
+
+impl Display for BloomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DependencyCycle(msg)
+            | Self::Build(msg)
+            | Self::Install(msg)
+            | Self::MissingPrerequisites(msg)
+            | Self::Timeout(msg)
+            | Self::TimeBudget(msg)
+            | Self::Interrupted(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for BloomError {}
+
+/// Add `msg` to `e` as a failure of `category`, unless a cause already has a more specific one.
+pub(crate) fn categorize(
+    e: anyhow::Error,
+    category: fn(String) -> BloomError,
+    msg: String,
+) -> anyhow::Error {
+    if e.downcast_ref::<BloomError>().is_some() {
+        e.context(msg)
+    } else {
+        e.context(category(msg))
+    }
+}
+
+/// Set by the first SIGINT, after which no new builds get started.
+pub(crate) static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Stop starting new builds, returning whether the run was already interrupted.
+pub fn interrupt() -> bool {
+    INTERRUPTED.swap(true, Ordering::Relaxed)
+}
@@ -0,0 +1,519 @@
+//! Package indices and rosdep rules of the generated repos.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::backend::{file_name, find_artifacts, Backend};
+use crate::packages::OrderedPkg;
+use crate::runner::{run_logged, CommandRunner};
+
+/// Assemble the rosdep yaml of workspace packages and extra definitions, sorted by key. Extra
+/// definitions take precedence over workspace packages. rosdep rules only map keys to package
+/// names, version constraints are carried by the generated packaging metadata instead.
+pub(crate) fn rosdep_yaml(
+    ordered_pkgs: &[Vec<OrderedPkg>],
+    rosdep_defs: &[(String, String)],
+    os_name: &str,
+    os_version: &str,
+    layout: RosdepLayout,
+) -> Result<String> {
+    let mut keys = ordered_pkgs
+        .iter()
+        .flatten()
+        .map(|(p, pkg, _, _)| (p.as_str(), pkg.as_str()))
+        .collect::<BTreeMap<_, _>>();
+
+    for (k, v) in rosdep_defs {
+        if let Some(pkg) = keys.insert(k, v) {
+            warn!("rosdep definition {k}={v} replaces {pkg}");
+        }
+    }
+
+    let rules = keys
+        .into_iter()
+        .map(|(k, v)| {
+            let rule = match layout {
+                RosdepLayout::Flat => serde_yaml::to_value([v])?,
+                RosdepLayout::Versioned => {
+                    serde_yaml::to_value(BTreeMap::from([(os_version, [v])]))?
+                }
+            };
+            Ok((k, BTreeMap::from([(os_name, rule)])))
+        })
+        .collect::<Result<BTreeMap<_, _>>>()?;
+
+    Ok(serde_yaml::to_string(&rules)?)
+}
+
+/// Structure of the rules of the generated rosdep yaml.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RosdepLayout {
+    /// Packages keyed by the OS only, resolving on any of its versions.
+    Flat,
+    /// Packages keyed by the OS and its version.
+    Versioned,
+}
+
+impl std::str::FromStr for RosdepLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "flat" => Ok(Self::Flat),
+            "versioned" => Ok(Self::Versioned),
+            _ => Err(anyhow!("Invalid rosdep layout '{s}'")),
+        }
+    }
+}
+
+/// Path of `to` relative to the directory `from`, both absolute.
+pub(crate) fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let common = from
+        .components()
+        .zip(to.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let path = std::iter::repeat_n(Path::new(".."), from.components().count() - common)
+        .chain(
+            to.components()
+                .skip(common)
+                .map(|c| Path::new(c.as_os_str())),
+        )
+        .collect::<PathBuf>();
+
+    if path.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        path
+    }
+}
+
+/// Where the package index lives within a repo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepoLayout<'a> {
+    /// Directly at the repo root.
+    Flat,
+    /// Under `dists/<suite>`, so clients can pin the repo by its codename. Packages are indexed
+    /// for the given architecture, or else the one of the host.
+    Suite(&'a str, Option<&'a str>),
+}
+
+/// The only component of apt repos using a suite layout.
+pub(crate) const DEB_COMPONENT: &str = "main";
+
+/// URL of an upstream snapshot given either as URL or as date of the official ROS snapshots.
+pub(crate) fn snapshot_url(snapshot: &str, ros_distro: &str, os_name: &str) -> String {
+    if snapshot.contains("://") {
+        snapshot.trim_end_matches('/').to_string()
+    } else {
+        format!("http://snapshots.ros.org/{ros_distro}/{snapshot}/{os_name}")
+    }
+}
+
+/// Pin of an upstream snapshot, above 1000 to downgrade packages installed from the live repo.
+const SNAPSHOT_PRIORITY: i32 = 1001;
+
+/// Pins of the generated repos and of the upstream snapshot if used. The generated repos get
+/// pinned above the snapshot by default, and the snapshot stays below them otherwise, so that
+/// the built packages win over the released ones of the same name.
+pub(crate) fn pin_priorities(pin_priority: Option<i32>, snapshot: bool) -> (Option<i32>, i32) {
+    if !snapshot {
+        return (pin_priority, SNAPSHOT_PRIORITY);
+    }
+
+    let repo = pin_priority.unwrap_or(SNAPSHOT_PRIORITY + 1);
+    (Some(repo), SNAPSHOT_PRIORITY.min(repo - 1))
+}
+
+/// Origin and label of generated apt repos.
+pub(crate) const RELEASE_ORIGIN: &str = "catkin-bloom";
+
+/// Contents of an apt `Release` file listing the checksums of the index files in `repo`.
+pub(crate) fn release_file(
+    dir: &Path,
+    codename: &str,
+    arches: Option<&[String]>,
+    now: std::time::SystemTime,
+) -> Result<String> {
+    let mut release = String::new();
+
+    writeln!(release, "Origin: {RELEASE_ORIGIN}")?;
+    writeln!(release, "Label: {RELEASE_ORIGIN}")?;
+    writeln!(release, "Suite: {codename}")?;
+    writeln!(release, "Codename: {codename}")?;
+    if let Some(arches) = arches {
+        writeln!(release, "Architectures: {}", arches.join(" "))?;
+        writeln!(release, "Components: {DEB_COMPONENT}")?;
+    }
+    writeln!(release, "Date: {}", rfc2822_date(now))?;
+    writeln!(release, "SHA256:")?;
+
+    let prefixes = match arches {
+        Some(arches) => arches
+            .iter()
+            .map(|arch| format!("{DEB_COMPONENT}/binary-{arch}/"))
+            .collect(),
+        None => vec![String::new()],
+    };
+    let source_prefix = arches
+        .map(|_| format!("{DEB_COMPONENT}/source/"))
+        .unwrap_or_default();
+
+    let indices = prefixes
+        .iter()
+        .flat_map(|prefix| [format!("{prefix}Packages"), format!("{prefix}Packages.gz")])
+        .chain([
+            format!("{source_prefix}Sources"),
+            format!("{source_prefix}Sources.gz"),
+        ]);
+
+    for index in indices {
+        let path = dir.join(&index);
+        if !path.is_file() {
+            continue;
+        }
+        let data = fs::read(path)?;
+        writeln!(
+            release,
+            " {:x} {} {index}",
+            Sha256::digest(&data),
+            data.len()
+        )?;
+    }
+
+    Ok(release)
+}
+
+/// Architectures with a `binary-<arch>` index under the component directory of a suite.
+pub(crate) fn indexed_architectures(component: &Path) -> Result<BTreeSet<String>> {
+    let mut arches = BTreeSet::new();
+
+    if !component.is_dir() {
+        return Ok(arches);
+    }
+
+    for entry in fs::read_dir(component)? {
+        let entry = entry?;
+        if let Some(arch) = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.strip_prefix("binary-"))
+        {
+            arches.insert(arch.to_string());
+        }
+    }
+
+    Ok(arches)
+}
+
+/// The `Architecture` control field of every deb under `dir`, by file name.
+pub(crate) fn deb_architectures(
+    dir: &Path,
+    backend: &dyn Backend,
+    runner: &dyn CommandRunner,
+) -> Result<HashMap<String, String>> {
+    let mut arches = HashMap::new();
+
+    for deb in find_artifacts(dir, backend, true) {
+        if deb.extension() != Some(OsStr::new("deb")) {
+            continue;
+        }
+
+        let o = run_logged(
+            runner,
+            Command::new("dpkg-deb")
+                .arg("-f")
+                .arg(&deb)
+                .arg("Architecture"),
+        )?;
+
+        if !o.status.success() {
+            return Err(anyhow!(
+                "Failed to read the architecture of {}: {}",
+                deb.display(),
+                String::from_utf8_lossy(&o.stderr).trim()
+            ));
+        }
+
+        let arch = String::from_utf8_lossy(&o.stdout).trim().to_string();
+        if let Some(name) = file_name(&deb).filter(|_| !arch.is_empty()) {
+            arches.insert(name.to_string(), arch);
+        }
+    }
+
+    Ok(arches)
+}
+
+/// Split the output of dpkg-scanpackages into the index of each of `targets`, with the
+/// architecture independent packages in all of them. Packages of other architectures get an
+/// index of their own.
+pub(crate) fn split_index(
+    index: &str,
+    mut targets: BTreeSet<String>,
+    arches: &HashMap<String, String>,
+) -> BTreeMap<String, String> {
+    let stanzas = index
+        .split("\n\n")
+        .filter(|s| !s.trim().is_empty())
+        .map(|stanza| {
+            let arch = stanza
+                .lines()
+                .find_map(|l| l.strip_prefix("Filename: "))
+                .and_then(|f| arches.get(file_name(Path::new(f))?))
+                .map_or("all", String::as_str);
+            (stanza.trim_end(), arch)
+        })
+        .collect::<Vec<_>>();
+
+    targets.extend(
+        stanzas
+            .iter()
+            .filter(|(_, arch)| *arch != "all")
+            .map(|(_, arch)| arch.to_string()),
+    );
+
+    targets
+        .into_iter()
+        .map(|target| {
+            let mut split = String::new();
+            for (stanza, arch) in &stanzas {
+                if *arch == "all" || *arch == target {
+                    writeln!(split, "{stanza}\n").unwrap();
+                }
+            }
+            (target, split)
+        })
+        .collect()
+}
+
+/// Format a time as an RFC 2822 date in UTC, e.g. `Thu, 01 Jan 1970 00:00:00 UTC`.
+fn rfc2822_date(time: std::time::SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let days = (secs / 86400) as i64;
+    let secs = secs % 86400;
+
+    // Civil date from days since the epoch, after Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} UTC",
+        DAYS[(days % 7) as usize],
+        MONTHS[month as usize - 1],
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Write the index `name` into `dir`, along with a gzipped copy if `compress` is set.
+pub(crate) fn write_index(dir: &Path, name: &str, index: &[u8], compress: bool) -> Result<()> {
+    fs::write(dir.join(name), index)?;
+
+    // Never leave a stale compressed index behind
+    let gz_path = dir.join(format!("{name}.gz"));
+
+    if compress {
+        let mut gz =
+            flate2::write::GzEncoder::new(File::create(gz_path)?, flate2::Compression::default());
+        gz.write_all(index)?;
+        gz.finish()?;
+    } else if gz_path.exists() {
+        fs::remove_file(gz_path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{deb_name, Deb, Rpm};
+    use crate::manifest::Package;
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn release() {
+        let time = |secs| std::time::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(rfc2822_date(time(0)), "Thu, 01 Jan 1970 00:00:00 UTC");
+        assert_eq!(
+            rfc2822_date(time(951_782_400 + 3723)),
+            "Tue, 29 Feb 2000 01:02:03 UTC"
+        );
+
+        let repo = tempdir().unwrap();
+        fs::write(repo.path().join("Packages"), "abc").unwrap();
+
+        let release = release_file(repo.path(), "bionic", None, time(0)).unwrap();
+        assert_eq!(
+            release,
+            "Origin: catkin-bloom\n\
+            Label: catkin-bloom\n\
+            Suite: bionic\n\
+            Codename: bionic\n\
+            Date: Thu, 01 Jan 1970 00:00:00 UTC\n\
+            SHA256:\n \
+            ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad 3 Packages\n"
+        );
+
+        let index = repo.path().join("main/binary-arm64");
+        fs::create_dir_all(&index).unwrap();
+        fs::write(index.join("Packages"), "abc").unwrap();
+
+        let release =
+            release_file(repo.path(), "bionic", Some(&["arm64".to_string()]), time(0)).unwrap();
+        assert_eq!(
+            release,
+            "Origin: catkin-bloom\n\
+            Label: catkin-bloom\n\
+            Suite: bionic\n\
+            Codename: bionic\n\
+            Architectures: arm64\n\
+            Components: main\n\
+            Date: Thu, 01 Jan 1970 00:00:00 UTC\n\
+            SHA256:\n \
+            ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad 3 \
+            main/binary-arm64/Packages\n"
+        );
+
+        let sources = Path::new("/etc");
+        let repo = Path::new("/repo");
+        assert_eq!(
+            Deb.source_list(sources, "name", repo, RepoLayout::Flat).1,
+            "deb [trusted=yes] file:///repo /\n"
+        );
+        assert_eq!(
+            Deb.source_list(sources, "name", repo, RepoLayout::Suite("bionic", None))
+                .1,
+            "deb [trusted=yes] file:///repo bionic main\n"
+        );
+
+        assert_eq!(
+            Deb.preferences(sources, 1001, true),
+            Some((
+                PathBuf::from("/etc/apt/preferences.d/99-catkin-bloom"),
+                "Package: *\nPin: release o=catkin-bloom\nPin-Priority: 1001\n".to_string()
+            ))
+        );
+        assert_eq!(
+            Deb.preferences(sources, 500, false).unwrap().1,
+            "Package: *\nPin: origin \"\"\nPin-Priority: 500\n"
+        );
+        assert_eq!(Rpm.preferences(sources, 500, true), None);
+
+        let url = snapshot_url("2024-01-15", "noetic", "ubuntu");
+        assert_eq!(url, "http://snapshots.ros.org/noetic/2024-01-15/ubuntu");
+        assert_eq!(
+            snapshot_url("https://mirror:8080/ros/", "noetic", "ubuntu"),
+            "https://mirror:8080/ros"
+        );
+        let files = Deb.snapshot_sources(sources, &url, "focal", 1001).unwrap();
+        assert_eq!(
+            files[0],
+            (
+                PathBuf::from("/etc/apt/sources.list.d/99-catkin-bloom-snapshot.list"),
+                "deb http://snapshots.ros.org/noetic/2024-01-15/ubuntu focal main\n".to_string()
+            )
+        );
+        assert_eq!(
+            files[1].1,
+            "Package: *\nPin: origin snapshots.ros.org\nPin-Priority: 1001\n"
+        );
+        assert_eq!(Rpm.snapshot_sources(sources, &url, "36", 1001), None);
+
+        // The generated repos win over the snapshot, which wins over the live repos
+        assert_eq!(pin_priorities(None, false), (None, 1001));
+        assert_eq!(pin_priorities(Some(500), false), (Some(500), 1001));
+        assert_eq!(pin_priorities(None, true), (Some(1002), 1001));
+        assert_eq!(pin_priorities(Some(1001), true), (Some(1001), 1000));
+        assert_eq!(pin_priorities(Some(2000), true), (Some(2000), 1001));
+    }
+
+    #[test]
+    fn index_per_architecture() {
+        let index = "Package: a\nArchitecture: all\nFilename: ./a_1_all.deb\n\n\
+            Package: b\nArchitecture: arm64\nFilename: ./b_1_arm64.deb\n\n";
+        let arches = HashMap::from([
+            ("a_1_all.deb".to_string(), "all".to_string()),
+            ("b_1_arm64.deb".to_string(), "arm64".to_string()),
+        ]);
+
+        let split = split_index(index, BTreeSet::from(["amd64".to_string()]), &arches);
+        assert_eq!(
+            split,
+            BTreeMap::from([
+                (
+                    "amd64".to_string(),
+                    "Package: a\nArchitecture: all\nFilename: ./a_1_all.deb\n\n".to_string()
+                ),
+                ("arm64".to_string(), index.to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn rosdep_keys() {
+        let ordered = vec![
+            vec![(
+                "b".to_string(),
+                deb_name("melodic", "b"),
+                PathBuf::from("b"),
+                Package::default(),
+            )],
+            vec![(
+                "a".to_string(),
+                deb_name("melodic", "a"),
+                PathBuf::from("a"),
+                Package::default(),
+            )],
+        ];
+        let defs = [
+            ("c".to_string(), "libc-dev".to_string()),
+            ("a".to_string(), "liba-dev".to_string()),
+        ];
+
+        assert_eq!(
+            rosdep_yaml(&ordered, &defs, "ubuntu", "bionic", RosdepLayout::Flat).unwrap(),
+            "a:\n  ubuntu:\n  - liba-dev\n\
+            b:\n  ubuntu:\n  - ros-melodic-b\n\
+            c:\n  ubuntu:\n  - libc-dev\n"
+        );
+
+        let versioned =
+            rosdep_yaml(&ordered, &defs, "ubuntu", "bionic", RosdepLayout::Versioned).unwrap();
+        assert_eq!(
+            serde_yaml::from_str::<BTreeMap<String, BTreeMap<String, BTreeMap<String, Vec<String>>>>>(
+                &versioned
+            )
+            .unwrap()["b"]["ubuntu"]["bionic"],
+            ["ros-melodic-b"]
+        );
+        assert!(versioned.starts_with("a:\n  ubuntu:\n    bionic:\n    - liba-dev\n"));
+    }
+}
@@ -0,0 +1,52 @@
+//! Build every package of catkin workspaces into native packages using bloom.
+//!
+//! The `catkin-bloom` binary is a thin wrapper around [`run`], the individual steps are
+//! [`collect_packages`], [`order_packages`] and [`bloom`].
+
+mod backend;
+mod bloom;
+mod cache;
+mod config;
+mod error;
+mod index;
+mod manifest;
+mod packages;
+mod pipeline;
+mod prerequisites;
+mod report;
+mod runner;
+mod vcs;
+
+pub use backend::{Backend, Deb, Rpm};
+pub use bloom::{bloom, BloomConfig, Built, Generated};
+pub use config::{cli, load_config, parse_args, Artifacts, Config, RuntimeArgs};
+pub use error::{
+    interrupt, BloomError, EXIT_BUILD_FAILED, EXIT_DEPENDENCY_CYCLE, EXIT_INSTALL_FAILED,
+    EXIT_MISSING_PREREQUISITES, EXIT_TIMEOUT_OR_INTERRUPT,
+};
+pub use index::RepoLayout;
+pub use manifest::{Dependency, Package};
+pub use packages::{
+    collect_packages, order_packages, OrderedPkg, PackageMap, UnorderedPkg, Walk, PRUNED_DIRS,
+};
+pub use report::{PackageReport, RunReport, Status, Timings};
+pub use runner::{mock_output, CommandRunner, Invocation, MockRunner, SystemRunner};
+
+use anyhow::Result;
+
+use crate::pipeline::{build_workspace, watch};
+
+/// Build the workspace as configured by `args`. Runs stopped by the time budget or an interrupt
+/// return successfully, telling so in the report.
+pub fn run(args: RuntimeArgs) -> Result<RunReport> {
+    run_with(args, &SystemRunner)
+}
+
+/// [`run`] with all external commands going through `runner`.
+pub fn run_with(args: RuntimeArgs, runner: &dyn CommandRunner) -> Result<RunReport> {
+    if args.watch {
+        return watch(args, runner);
+    }
+
+    build_workspace(args, None, runner)
+}
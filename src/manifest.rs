@@ -0,0 +1,808 @@
+//! Parsing of `package.xml` manifests.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use quick_xml::{
+    events::{BytesStart, Event},
+    Reader,
+};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::packages::PackageMap;
+use crate::runner::{run_logged, CommandRunner};
+
+/// Dependencies of a package, by the `package.xml` element declaring them, along with its
+/// maintainers and licenses.
+#[derive(Debug, Clone, Default)]
+pub struct Package {
+    /// As `Name <email>`, or only the name if no email is given.
+    pub(crate) maintainers: Vec<String>,
+    pub(crate) licenses: Vec<String>,
+    pub(crate) version: String,
+    depend: HashSet<Dependency>,
+    build_depend: HashSet<Dependency>,
+    build_export_depend: HashSet<Dependency>,
+    exec_depend: HashSet<Dependency>,
+    pub(crate) test_depend: HashSet<Dependency>,
+    buildtool_depend: HashSet<Dependency>,
+    doc_depend: HashSet<Dependency>,
+    run_depend: HashSet<Dependency>,
+}
+
+impl Package {
+    fn categories(&self) -> [&HashSet<Dependency>; 8] {
+        [
+            &self.depend,
+            &self.build_depend,
+            &self.build_export_depend,
+            &self.buildtool_depend,
+            &self.exec_depend,
+            &self.run_depend,
+            &self.test_depend,
+            &self.doc_depend,
+        ]
+    }
+
+    /// Add a dependency declared by the given element, returning false for unknown elements.
+    pub(crate) fn insert(&mut self, element: &[u8], dep: Dependency) -> bool {
+        let deps = match element {
+            b"depend" => &mut self.depend,
+            b"build_depend" => &mut self.build_depend,
+            b"build_export_depend" => &mut self.build_export_depend,
+            b"buildtool_depend" => &mut self.buildtool_depend,
+            b"exec_depend" => &mut self.exec_depend,
+            b"run_depend" => &mut self.run_depend,
+            b"test_depend" => &mut self.test_depend,
+            b"doc_depend" => &mut self.doc_depend,
+            _ => return false,
+        };
+        deps.insert(dep);
+        true
+    }
+
+    /// Dependencies which have to be built before the package.
+    pub fn build_depends(&self) -> impl Iterator<Item = &Dependency> {
+        self.categories().into_iter().take(4).flatten()
+    }
+
+    pub fn all_depends(&self) -> impl Iterator<Item = &Dependency> {
+        self.categories().into_iter().flatten()
+    }
+
+    /// Dependencies of building and testing the package, the `Build-Depends` of a deb.
+    pub(crate) fn build_test_depends(&self) -> impl Iterator<Item = &Dependency> {
+        [
+            &self.depend,
+            &self.build_depend,
+            &self.buildtool_depend,
+            &self.test_depend,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Dependencies of the built package, the `Depends` of a deb.
+    pub(crate) fn run_depends(&self) -> impl Iterator<Item = &Dependency> {
+        [
+            &self.depend,
+            &self.build_export_depend,
+            &self.exec_depend,
+            &self.run_depend,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    pub(crate) fn retain(&mut self, f: impl Fn(&Dependency) -> bool) {
+        for deps in [
+            &mut self.depend,
+            &mut self.build_depend,
+            &mut self.build_export_depend,
+            &mut self.buildtool_depend,
+            &mut self.exec_depend,
+            &mut self.run_depend,
+            &mut self.test_depend,
+            &mut self.doc_depend,
+        ] {
+            deps.retain(&f);
+        }
+    }
+}
+
+/// A dependency entry of a `package.xml`, along with its version constraints.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Dependency {
+    pub name: String,
+    pub version_lt: Option<String>,
+    pub version_lte: Option<String>,
+    pub version_eq: Option<String>,
+    pub version_gte: Option<String>,
+    pub version_gt: Option<String>,
+}
+
+impl Dependency {
+    /// Version constraints as debian relation operator and version pairs.
+    pub(crate) fn constraints(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("<<", &self.version_lt),
+            ("<=", &self.version_lte),
+            ("=", &self.version_eq),
+            (">=", &self.version_gte),
+            (">>", &self.version_gt),
+        ]
+        .into_iter()
+        .filter_map(|(op, v)| v.as_deref().map(|v| (op, v)))
+    }
+}
+
+/// ROS 1 distributions, everything else is assumed to be ROS 2.
+pub(crate) const ROS1_DISTROS: &[&str] = &[
+    "boxturtle",
+    "cturtle",
+    "diamondback",
+    "electric",
+    "fuerte",
+    "groovy",
+    "hydro",
+    "indigo",
+    "jade",
+    "kinetic",
+    "lunar",
+    "melodic",
+    "noetic",
+];
+
+/// ROS 2 distributions, only used to catch misspelled ones.
+pub(crate) const ROS2_DISTROS: &[&str] = &[
+    "ardent", "bouncy", "crystal", "dashing", "eloquent", "foxy", "galactic", "humble", "iron",
+    "jazzy", "kilted", "rolling",
+];
+
+pub(crate) fn ros_version(ros_distro: &str) -> &'static str {
+    if ROS1_DISTROS.contains(&ros_distro) {
+        "1"
+    } else {
+        "2"
+    }
+}
+
+fn ros_python_version(ros_distro: &str) -> &'static str {
+    if ros_distro != "noetic" && ROS1_DISTROS.contains(&ros_distro) {
+        "2"
+    } else {
+        "3"
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum CondToken<'a> {
+    LParen,
+    RParen,
+    Eq,
+    Ne,
+    And,
+    Or,
+    Value(&'a str),
+}
+
+fn tokenize_condition(expr: &str) -> Option<Vec<CondToken<'_>>> {
+    let mut tokens = vec![];
+    let mut rest = expr.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        let (token, len) = match c {
+            '(' => (CondToken::LParen, 1),
+            ')' => (CondToken::RParen, 1),
+            '=' if rest.starts_with("==") => (CondToken::Eq, 2),
+            '!' if rest.starts_with("!=") => (CondToken::Ne, 2),
+            '"' | '\'' => {
+                let end = rest[1..].find(c)? + 1;
+                (CondToken::Value(&rest[1..end]), end + 1)
+            }
+            _ => {
+                let len = rest
+                    .find(|c: char| c.is_whitespace() || "()=!\"'".contains(c))
+                    .unwrap_or(rest.len());
+
+                match &rest[..len] {
+                    "" => return None,
+                    "and" => (CondToken::And, len),
+                    "or" => (CondToken::Or, len),
+                    v => (CondToken::Value(v), len),
+                }
+            }
+        };
+
+        tokens.push(token);
+        rest = rest[len..].trim_start();
+    }
+
+    Some(tokens)
+}
+
+struct CondParser<'a> {
+    tokens: &'a [CondToken<'a>],
+    pos: usize,
+    env: &'a HashMap<&'a str, &'a str>,
+}
+
+impl<'a> CondParser<'a> {
+    fn peek(&self) -> Option<&CondToken<'a>> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&'a CondToken<'a>> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn or(&mut self) -> Option<bool> {
+        let mut val = self.and()?;
+        while self.peek() == Some(&CondToken::Or) {
+            self.pos += 1;
+            val |= self.and()?;
+        }
+        Some(val)
+    }
+
+    fn and(&mut self) -> Option<bool> {
+        let mut val = self.term()?;
+        while self.peek() == Some(&CondToken::And) {
+            self.pos += 1;
+            val &= self.term()?;
+        }
+        Some(val)
+    }
+
+    fn term(&mut self) -> Option<bool> {
+        if self.peek() == Some(&CondToken::LParen) {
+            self.pos += 1;
+            let val = self.or()?;
+            return match self.next() {
+                Some(CondToken::RParen) => Some(val),
+                _ => None,
+            };
+        }
+
+        let lhs = self.value()?;
+        let eq = match self.next()? {
+            CondToken::Eq => true,
+            CondToken::Ne => false,
+            _ => return None,
+        };
+        let rhs = self.value()?;
+
+        Some((lhs == rhs) == eq)
+    }
+
+    fn value(&mut self) -> Option<&'a str> {
+        let env = self.env;
+        match self.next()? {
+            CondToken::Value(v) => Some(match v.strip_prefix('$') {
+                Some(var) => env.get(var).copied().unwrap_or(""),
+                None => v,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Evaluate a package format 3 `condition` attribute.
+///
+/// Supports `==`, `!=`, `and`, `or` and parentheses. Variables (`$VAR`) not present in `env`
+/// evaluate to an empty string. Malformed expressions evaluate to false.
+fn eval_condition(expr: &str, env: &HashMap<&str, &str>) -> bool {
+    let res = tokenize_condition(expr).and_then(|tokens| {
+        let mut parser = CondParser {
+            tokens: &tokens,
+            pos: 0,
+            env,
+        };
+        parser.or().filter(|_| parser.pos == tokens.len())
+    });
+
+    res.unwrap_or_else(|| {
+        warn!("Invalid condition expression '{expr}'");
+        false
+    })
+}
+
+/// Variables available to `condition` attributes.
+pub(crate) fn condition_env<'a>(
+    ros_distro: &'a str,
+    ros_version: &'a str,
+) -> HashMap<&'static str, &'a str> {
+    HashMap::from([
+        ("ROS_VERSION", ros_version),
+        ("ROS_DISTRO", ros_distro),
+        ("ROS_PYTHON_VERSION", ros_python_version(ros_distro)),
+    ])
+}
+
+/// Parse the name and the dependencies of a `package.xml`, skipping dependencies whose
+/// condition evaluates to false.
+pub(crate) fn parse_package_xml(
+    path: &Path,
+    condition_env: &HashMap<&str, &str>,
+) -> Result<Option<(String, Package)>> {
+    let xml = fs::read_to_string(path)?;
+    let mut reader = Reader::from_str(&xml);
+    let mut buf = vec![];
+
+    let mut name = None;
+    let mut package = Package::default();
+
+    // Only the <name> child of the root <package> names the package, not the
+    // ones of e.g. <author> or <export> entries
+    let mut depth = 0usize;
+    let mut in_package = false;
+
+    // Report parse errors with the location the reader stopped at
+    let malformed = |reader: &Reader<&[u8]>, e: quick_xml::Error| {
+        let pos = reader.buffer_position();
+        let line = xml.as_bytes()[..pos.min(xml.len())]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count()
+            + 1;
+        anyhow!("{}:{line}: {e} (at byte {pos})", path.display())
+    };
+
+    loop {
+        match reader
+            .read_event(&mut buf)
+            .map_err(|e| malformed(&reader, e))?
+        {
+            Event::Start(ref e) if depth == 1 && in_package && e.local_name() == b"name" => {
+                name =
+                    Some(element_text(&mut reader, e.name()).map_err(|e| malformed(&reader, e))?);
+            }
+            Event::Start(ref e) if depth == 1 && in_package && e.local_name() == b"maintainer" => {
+                let mut email = None;
+                for a in e.attributes() {
+                    let a = a.map_err(|e| malformed(&reader, e.into()))?;
+                    if a.key == b"email" {
+                        email = Some(
+                            a.unescape_and_decode_value(&reader)
+                                .map_err(|e| malformed(&reader, e))?,
+                        );
+                    }
+                }
+
+                let name =
+                    element_text(&mut reader, e.name()).map_err(|e| malformed(&reader, e))?;
+                package.maintainers.push(match email {
+                    Some(email) => format!("{name} <{email}>"),
+                    None => name,
+                });
+            }
+            Event::Start(ref e) if depth == 1 && in_package && e.local_name() == b"version" => {
+                package.version =
+                    element_text(&mut reader, e.name()).map_err(|e| malformed(&reader, e))?;
+            }
+            Event::Start(ref e) if depth == 1 && in_package && e.local_name() == b"license" => {
+                let license =
+                    element_text(&mut reader, e.name()).map_err(|e| malformed(&reader, e))?;
+                package.licenses.push(license);
+            }
+            Event::Start(ref e) if e.local_name().ends_with(b"depend") => {
+                let (mut dep, condition) =
+                    dependency_attributes(e, &reader).map_err(|e| malformed(&reader, e))?;
+
+                dep.name =
+                    element_text(&mut reader, e.name()).map_err(|e| malformed(&reader, e))?;
+
+                match condition {
+                    Some(c) if !eval_condition(&c, condition_env) => {
+                        debug!("Skipping {}, condition '{c}' is false", dep.name);
+                    }
+                    _ => {
+                        let element = e.local_name().to_vec();
+                        if !package.insert(&element, dep) {
+                            debug!(
+                                "Ignoring unknown dependency element {}",
+                                String::from_utf8_lossy(&element)
+                            );
+                        }
+                    }
+                }
+            }
+            Event::Start(ref e) => {
+                if depth == 0 {
+                    in_package = e.local_name() == b"package";
+                }
+                depth += 1;
+            }
+            Event::End(_) => depth = depth.saturating_sub(1),
+            Event::Eof => break,
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    Ok(name.map(|name| (name, package)))
+}
+
+/// Version constraints and condition of a dependency element.
+fn dependency_attributes(
+    e: &BytesStart,
+    reader: &Reader<&[u8]>,
+) -> quick_xml::Result<(Dependency, Option<String>)> {
+    let mut dep = Dependency::default();
+    let mut condition = None;
+
+    for a in e.attributes() {
+        let a = a?;
+        let v = a.unescape_and_decode_value(reader)?;
+        match a.key {
+            b"condition" => condition = Some(v),
+            b"version_lt" => dep.version_lt = Some(v),
+            b"version_lte" => dep.version_lte = Some(v),
+            b"version_eq" => dep.version_eq = Some(v),
+            b"version_gte" => dep.version_gte = Some(v),
+            b"version_gt" => dep.version_gt = Some(v),
+            _ => {}
+        }
+    }
+
+    Ok((dep, condition))
+}
+
+/// Parse the dependencies of a `package.xml` like [`parse_package_xml`], but only accepting
+/// dependency elements which are direct children of the root `<package>`, without namespaces,
+/// and rejecting malformed comments.
+pub(crate) fn parse_package_xml_strict(
+    path: &Path,
+    condition_env: &HashMap<&str, &str>,
+) -> Result<Package> {
+    let xml = fs::read_to_string(path)?;
+    let mut reader = Reader::from_str(&xml);
+    reader.check_comments(true);
+    let mut buf = vec![];
+
+    let mut package = Package::default();
+    let mut depth = 0usize;
+    let mut in_package = false;
+
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(ref e) if depth == 1 && in_package && e.name().ends_with(b"depend") => {
+                let (mut dep, condition) = dependency_attributes(e, &reader)?;
+                dep.name = element_text(&mut reader, e.name())?;
+
+                if condition.is_none_or(|c| eval_condition(&c, condition_env)) {
+                    package.insert(e.name(), dep);
+                }
+            }
+            Event::Start(ref e) => {
+                if depth == 0 {
+                    in_package = e.name() == b"package";
+                }
+                depth += 1;
+            }
+            Event::End(_) => depth = depth.saturating_sub(1),
+            Event::Eof => break,
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    Ok(package)
+}
+
+/// Dependency names by catkin_pkg category.
+type DependNames = BTreeMap<String, BTreeSet<String>>;
+
+/// Names of the dependencies of a package as catkin_pkg resolves them, by the catkin_pkg
+/// category. `<depend>` and format 1 `<run_depend>` entries expand to multiple categories.
+pub(crate) fn effective_depends(p: &Package) -> DependNames {
+    let names = |sets: &[&HashSet<Dependency>]| {
+        sets.iter()
+            .copied()
+            .flatten()
+            .map(|d| d.name.clone())
+            .collect::<BTreeSet<_>>()
+    };
+
+    [
+        ("build", names(&[&p.depend, &p.build_depend])),
+        (
+            "build_export",
+            names(&[&p.depend, &p.build_export_depend, &p.run_depend]),
+        ),
+        ("buildtool", names(&[&p.buildtool_depend])),
+        ("exec", names(&[&p.depend, &p.exec_depend, &p.run_depend])),
+        ("test", names(&[&p.test_depend])),
+        ("doc", names(&[&p.doc_depend])),
+    ]
+    .into_iter()
+    .map(|(c, deps)| (c.to_string(), deps))
+    .collect()
+}
+
+/// Prints the [`effective_depends`] of the manifests given as arguments as a JSON list, with
+/// an error message for manifests catkin_pkg rejects.
+const CATKIN_PKG_DEPENDS: &str = r#"
+import json, os, sys
+from catkin_pkg.package import parse_package
+
+out = []
+for path in sys.argv[1:]:
+    try:
+        pkg = parse_package(path)
+        pkg.evaluate_conditions(os.environ)
+        out.append({
+            c: sorted({d.name for d in getattr(pkg, c + "_depends") if d.evaluated_condition is not False})
+            for c in ["build", "build_export", "buildtool", "exec", "test", "doc"]
+        })
+    except Exception as e:
+        out.append(str(e))
+json.dump(out, sys.stdout)
+"#;
+
+/// Result of parsing a manifest with the reference parser of `--audit-parse`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum ReferenceParse {
+    Depends(DependNames),
+    Error(String),
+}
+
+/// Dependencies of every package read by catkin_pkg if it is installed, otherwise by
+/// [`parse_package_xml_strict`], keyed by package directory. Returns the name of the parser
+/// used along with them.
+pub(crate) fn reference_depends(
+    pkgs: &PackageMap,
+    ros_distro: &str,
+    ros_version: &str,
+    runner: &dyn CommandRunner,
+) -> Result<(&'static str, HashMap<PathBuf, ReferenceParse>)> {
+    let env = condition_env(ros_distro, ros_version);
+    let dirs = pkgs.values().map(|(dir, _)| dir).collect::<Vec<_>>();
+
+    let catkin_pkg = run_logged(
+        runner,
+        Command::new("python3").args(["-c", "import catkin_pkg"]),
+    )
+    .is_ok_and(|o| o.status.success());
+
+    if !catkin_pkg {
+        debug!("catkin_pkg is not available, using the strict parser");
+        let parsed = dirs
+            .into_iter()
+            .map(|dir| {
+                let parsed = match parse_package_xml_strict(&dir.join("package.xml"), &env) {
+                    Ok(p) => ReferenceParse::Depends(effective_depends(&p)),
+                    Err(e) => ReferenceParse::Error(e.to_string()),
+                };
+                (dir.clone(), parsed)
+            })
+            .collect();
+        return Ok(("the strict parser", parsed));
+    }
+
+    let o = run_logged(
+        runner,
+        Command::new("python3")
+            .args(["-c", CATKIN_PKG_DEPENDS])
+            .args(dirs.iter().map(|d| d.join("package.xml")))
+            .envs(env),
+    )?;
+    if !o.status.success() {
+        return Err(anyhow!(
+            "Failed to parse packages with catkin_pkg: {}",
+            String::from_utf8_lossy(&o.stderr).trim()
+        ));
+    }
+
+    let parsed = serde_json::from_slice::<Vec<ReferenceParse>>(&o.stdout)?;
+    let parsed = dirs.into_iter().cloned().zip(parsed).collect();
+    Ok(("catkin_pkg", parsed))
+}
+
+/// Describe the dependencies found by only one of catkin-bloom and the `reference` parser.
+pub(crate) fn parse_differences(
+    ours: &DependNames,
+    theirs: &DependNames,
+    reference: &str,
+) -> Vec<String> {
+    let empty = BTreeSet::new();
+    let only = |a: &BTreeSet<String>, b: &BTreeSet<String>| {
+        a.difference(b)
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let mut diff = vec![];
+    for category in ours.keys().chain(theirs.keys()).collect::<BTreeSet<_>>() {
+        let a = ours.get(category).unwrap_or(&empty);
+        let b = theirs.get(category).unwrap_or(&empty);
+
+        let extra = only(a, b);
+        if !extra.is_empty() {
+            diff.push(format!(
+                "{category}_depends only found by catkin-bloom: {extra}"
+            ));
+        }
+        let missing = only(b, a);
+        if !missing.is_empty() {
+            diff.push(format!(
+                "{category}_depends only found by {reference}: {missing}"
+            ));
+        }
+    }
+    diff
+}
+
+/// Read the text of the element ending with `end`, skipping comments and surrounding whitespace.
+fn element_text(reader: &mut Reader<&[u8]>, end: &[u8]) -> quick_xml::Result<String> {
+    let mut text = String::new();
+    let mut buf = vec![];
+
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Text(t) => text.push_str(&t.unescape_and_decode(reader)?),
+            Event::End(e) if e.name() == end => break,
+            Event::Eof => {
+                return Err(quick_xml::Error::UnexpectedEof(format!(
+                    "</{}>",
+                    String::from_utf8_lossy(end)
+                )))
+            }
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    Ok(text.trim().to_string())
+}
+
+/// A package manifest without its `<test_depend>` entries, otherwise kept as is.
+pub(crate) fn strip_test_depends(xml: &str) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = quick_xml::Writer::new(vec![]);
+    let mut buf = vec![];
+    let mut skip = vec![];
+
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(e) if e.local_name() == b"test_depend" => {
+                reader.read_to_end(e.name(), &mut skip)?;
+            }
+            Event::Empty(e) if e.local_name() == b"test_depend" => {}
+            Event::Eof => break,
+            e => writer.write_event(e)?,
+        }
+
+        buf.clear();
+    }
+
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env() -> HashMap<&'static str, &'static str> {
+        HashMap::from([
+            ("ROS_VERSION", "1"),
+            ("ROS_DISTRO", "melodic"),
+            ("ROS_PYTHON_VERSION", "2"),
+        ])
+    }
+
+    #[test]
+    fn condition_simple() {
+        let env = env();
+        assert!(eval_condition("$ROS_VERSION == 1", &env));
+        assert!(!eval_condition("$ROS_VERSION == 2", &env));
+        assert!(eval_condition("$ROS_VERSION != 2", &env));
+        assert!(eval_condition("melodic == $ROS_DISTRO", &env));
+        assert!(eval_condition("$ROS_DISTRO == \"melodic\"", &env));
+        assert!(eval_condition("$ROS_DISTRO=='melodic'", &env));
+    }
+
+    #[test]
+    fn condition_nested() {
+        let env = env();
+        assert!(eval_condition(
+            "$ROS_VERSION == 2 or $ROS_PYTHON_VERSION == 2",
+            &env
+        ));
+        assert!(!eval_condition(
+            "$ROS_VERSION == 1 and $ROS_PYTHON_VERSION == 3",
+            &env
+        ));
+        assert!(eval_condition(
+            "($ROS_VERSION == 2 or $ROS_DISTRO == melodic) and $ROS_PYTHON_VERSION == 2",
+            &env
+        ));
+        assert!(!eval_condition(
+            "$ROS_VERSION == 2 or ($ROS_DISTRO == melodic and ($ROS_PYTHON_VERSION == 3))",
+            &env
+        ));
+        // `and` binds tighter than `or`
+        assert!(eval_condition(
+            "$ROS_VERSION == 1 or $ROS_VERSION == 2 and $ROS_DISTRO == noetic",
+            &env
+        ));
+    }
+
+    #[test]
+    fn condition_unknown_variable() {
+        let env = env();
+        assert!(eval_condition("$UNKNOWN == ''", &env));
+        assert!(!eval_condition("$UNKNOWN == 1", &env));
+        assert!(eval_condition("$UNKNOWN != 1", &env));
+    }
+
+    #[test]
+    fn condition_malformed() {
+        let env = env();
+        assert!(!eval_condition("$ROS_VERSION ==", &env));
+        assert!(!eval_condition("($ROS_VERSION == 1", &env));
+        assert!(!eval_condition("$ROS_VERSION == 1)", &env));
+        assert!(!eval_condition("$ROS_VERSION == 1 and", &env));
+    }
+
+    #[test]
+    fn version_constraints() {
+        let ws = tempfile::tempdir().unwrap();
+        let path = ws.path().join("package.xml");
+        fs::write(
+            &path,
+            "<package format=\"3\"><name>a</name>\
+            <build_depend version_gte=\"1.0\" version_lt=\"2.0\">b</build_depend>\
+            <exec_depend version_eq=\"1.2.3\">c</exec_depend></package>",
+        )
+        .unwrap();
+
+        let (_, package) = parse_package_xml(&path, &env()).unwrap().unwrap();
+
+        let b = Dependency {
+            name: "b".into(),
+            version_gte: Some("1.0".into()),
+            version_lt: Some("2.0".into()),
+            ..Default::default()
+        };
+        let c = Dependency {
+            name: "c".into(),
+            version_eq: Some("1.2.3".into()),
+            ..Default::default()
+        };
+        assert_eq!(package.build_test_depends().collect::<Vec<_>>(), [&b]);
+        assert_eq!(package.run_depends().collect::<Vec<_>>(), [&c]);
+    }
+
+    #[test]
+    fn strip_test_manifest() {
+        let xml = "<package format=\"3\"><name>a</name><depend>b</depend>\
+            <test_depend condition=\"$ROS_VERSION == 1\">c</test_depend><test_depend/>\
+            <exec_depend>d</exec_depend></package>";
+
+        assert_eq!(
+            strip_test_depends(xml).unwrap(),
+            "<package format=\"3\"><name>a</name><depend>b</depend>\
+            <exec_depend>d</exec_depend></package>"
+        );
+    }
+
+    #[test]
+    fn distro_versions() {
+        assert_eq!(ros_version("melodic"), "1");
+        assert_eq!(ros_version("humble"), "2");
+        assert_eq!(ros_python_version("melodic"), "2");
+        assert_eq!(ros_python_version("noetic"), "3");
+        assert_eq!(ros_python_version("humble"), "3");
+    }
+}
@@ -0,0 +1,1456 @@
+//! Collecting the packages of a workspace and ordering them by their dependencies.
+
+use anyhow::{anyhow, Result};
+use glob::Pattern;
+use log::*;
+use rayon::iter::*;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use std::fmt::Write as FmtWrite;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use walkdir::WalkDir;
+
+use crate::backend::deb::upstream_version;
+use crate::backend::mapped_deb_name;
+use crate::manifest::{condition_env, parse_package_xml, Dependency, Package};
+use crate::runner::{run_logged, CommandRunner};
+
+/// Smallest, largest and mean number of packages per layer.
+pub(crate) fn layer_sizes<T>(layers: &[Vec<T>]) -> Option<(usize, usize, f64)> {
+    let min = layers.iter().map(Vec::len).min()?;
+    let max = layers.iter().map(Vec::len).max()?;
+    let total = layers.iter().map(Vec::len).sum::<usize>();
+    Some((min, max, total as f64 / layers.len() as f64))
+}
+
+/// Sort packages into layers whose build dependencies are all in earlier layers. Layers are
+/// sorted by name so runs over the same workspace are reproducible.
+///
+/// Also returns the packages that could not be ordered along with their unresolved dependencies.
+pub fn order_packages(
+    pkgs: &PackageMap,
+    ros_distro: &str,
+    name_map: &BTreeMap<&str, &str>,
+) -> (Vec<Vec<OrderedPkg>>, Vec<UnorderedPkg>) {
+    let mut ordered_pkgs = vec![];
+
+    let mut tmp_pkgs = pkgs
+        .iter()
+        .map(|(n, (p, d))| (n.clone(), p.clone(), d.build_depends().cloned().collect()))
+        .collect::<Vec<UnorderedPkg>>();
+    tmp_pkgs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    loop {
+        let mut drained = vec![];
+        let mut drained_names = HashSet::new();
+
+        let mut i = 0;
+        while i < tmp_pkgs.len() {
+            if tmp_pkgs[i].2.is_empty() {
+                trace!("REMOVE {}", tmp_pkgs[i].0);
+                let (name, path, _) = tmp_pkgs.remove(i);
+                drained_names.insert(name.clone());
+                let pkg = mapped_deb_name(ros_distro, &name, name_map);
+                let package = pkgs[&name].1.clone();
+                drained.push((name, pkg, path, package));
+            } else {
+                i += 1;
+            }
+        }
+
+        if drained.is_empty() {
+            break;
+        }
+
+        trace!("DRAIN {:?}", drained);
+
+        for (_, _, d) in &mut tmp_pkgs {
+            d.retain(|d| !drained_names.contains(&d.name))
+        }
+
+        drained.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        ordered_pkgs.push(drained);
+    }
+
+    (ordered_pkgs, tmp_pkgs)
+}
+
+/// Maintainers and licenses of a package as written by `--write-metadata`.
+#[derive(Serialize)]
+struct PackageMetadata<'a> {
+    maintainers: &'a [String],
+    licenses: &'a [String],
+}
+
+/// Describe the maintainers and licenses of every package as json, keyed by package name.
+pub(crate) fn package_metadata(pkgs: &PackageMap) -> Result<String> {
+    let metadata = pkgs
+        .iter()
+        .map(|(name, (_, package))| {
+            let metadata = PackageMetadata {
+                maintainers: &package.maintainers,
+                licenses: &package.licenses,
+            };
+            (name.as_str(), metadata)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    Ok(serde_json::to_string_pretty(&metadata)? + "\n")
+}
+
+/// A workspace package as printed by `--list`.
+#[derive(Serialize)]
+struct ListedPackage<'a> {
+    path: &'a Path,
+    layer: Option<usize>,
+    depends: BTreeSet<&'a str>,
+}
+
+/// Describe every package with its workspace dependencies and layer, as text or json. Packages
+/// that could not be ordered have no layer.
+pub(crate) fn list_packages(
+    pkgs: &PackageMap,
+    ordered_pkgs: &[Vec<OrderedPkg>],
+    format: &str,
+) -> Result<String> {
+    let layers = ordered_pkgs
+        .iter()
+        .enumerate()
+        .flat_map(|(i, l)| l.iter().map(move |(p, ..)| (p.as_str(), i)))
+        .collect::<HashMap<_, _>>();
+
+    let listed = pkgs
+        .iter()
+        .map(|(name, (path, package))| {
+            let listed = ListedPackage {
+                path,
+                layer: layers.get(name.as_str()).copied(),
+                depends: package.all_depends().map(|d| d.name.as_str()).collect(),
+            };
+            (name.as_str(), listed)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    if format == "json" {
+        return Ok(serde_json::to_string_pretty(&listed)? + "\n");
+    }
+
+    let mut out = String::new();
+
+    for (name, p) in &listed {
+        let layer = p.layer.map_or_else(|| "-".to_string(), |l| l.to_string());
+        write!(out, "{layer}\t{name}")?;
+        if !p.depends.is_empty() {
+            let depends = p.depends.iter().copied().collect::<Vec<_>>();
+            write!(out, ": {}", depends.join(", "))?;
+        }
+        writeln!(out)?;
+    }
+
+    Ok(out)
+}
+
+/// Packages transitively build depending on any of `roots`, in build order. The roots themselves
+/// are left out.
+pub(crate) fn reverse_depends<'a>(
+    pkgs: &'a PackageMap,
+    ordered_pkgs: &'a [Vec<OrderedPkg>],
+    roots: &[&str],
+) -> Result<Vec<&'a str>> {
+    let roots = roots
+        .iter()
+        .map(|r| match pkgs.get_key_value(*r) {
+            Some((name, _)) => Ok(name.as_str()),
+            None => Err(anyhow!("Package {r} is not in the workspace")),
+        })
+        .collect::<Result<HashSet<_>>>()?;
+
+    let closure = reverse_closure(pkgs, roots.clone());
+
+    Ok(ordered_pkgs
+        .iter()
+        .flatten()
+        .map(|(p, ..)| p.as_str())
+        .filter(|p| closure.contains(p) && !roots.contains(p))
+        .collect())
+}
+
+/// Source directory and dependencies of every workspace package, keyed by package name.
+pub type PackageMap = HashMap<String, (PathBuf, Package)>;
+
+/// Package name, deb name, source directory and manifest, with only its workspace dependencies
+/// left, of an ordered package.
+pub type OrderedPkg = (String, String, PathBuf, Package);
+
+/// Package name, source directory and unresolved build dependencies of a package being ordered.
+pub type UnorderedPkg = (String, PathBuf, HashSet<Dependency>);
+
+/// Files that exclude the package in the same directory from the workspace.
+const IGNORE_MARKERS: &[&str] = &["CATKIN_IGNORE", "COLCON_IGNORE", "AMENT_IGNORE"];
+
+/// Build, install and VCS directories whose copies of package.xml files are not sources.
+pub const PRUNED_DIRS: &[&str] = &["build", "install", "log", "devel", ".git"];
+
+/// How the source trees are walked for packages.
+#[derive(Default)]
+pub struct Walk<'a> {
+    pub follow_symlinks: bool,
+    /// Names of directories skipped along with their contents, the source trees themselves are
+    /// always walked.
+    pub pruned: &'a [&'a str],
+}
+
+/// Walk the source trees and collect every package not explicitly ignored, along with the names
+/// of the ignored ones.
+pub fn collect_packages(
+    srcs: &[&str],
+    ignored_pkgs: &[&str],
+    ignored_globs: &[Pattern],
+    ros_distro: &str,
+    ros_version: &str,
+    walk: &Walk,
+    strict: bool,
+) -> Result<(PackageMap, BTreeSet<String>)> {
+    let condition_env = condition_env(ros_distro, ros_version);
+
+    let mut pkgs = PackageMap::new();
+    let mut ignored = BTreeSet::new();
+    let mut seen = HashSet::new();
+    let mut manifests = vec![];
+
+    // Nested trees reach the same packages twice
+    let dedup = walk.follow_symlinks || srcs.len() > 1;
+
+    for (src, entry) in srcs.iter().flat_map(|src| {
+        WalkDir::new(src)
+            .follow_links(walk.follow_symlinks)
+            .into_iter()
+            .filter_entry(|e| {
+                let pruned = e.depth() > 0
+                    && e.file_type().is_dir()
+                    && walk.pruned.iter().any(|d| e.file_name() == OsStr::new(d));
+                if pruned {
+                    debug!("Pruning {}", e.path().display());
+                }
+                !pruned
+            })
+            .map(move |e| (src, e))
+    }) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                if e.loop_ancestor().is_some() {
+                    warn!("Skipping symlink loop: {e}");
+                }
+                continue;
+            }
+        };
+
+        if entry.file_type().is_file() && entry.file_name() == OsStr::new("package.xml") {
+            debug!("Found {}", entry.path().display());
+
+            // The same package may be reachable through multiple symlinks or trees
+            if dedup && !seen.insert(entry.path().canonicalize()?) {
+                debug!("Already collected {}", entry.path().display());
+                continue;
+            }
+
+            let dir = entry.path().parent().unwrap_or_else(|| Path::new(src));
+            if let Some(marker) = IGNORE_MARKERS.iter().find(|m| dir.join(m).exists()) {
+                debug!("Ignoring {} ({marker})", dir.display());
+                continue;
+            }
+
+            manifests.push(entry.into_path());
+        }
+    }
+
+    // Manifests parse independently, the walk order is kept to report duplicates consistently
+    let parsed = manifests
+        .into_par_iter()
+        .map(|path| {
+            let parsed = parse_package_xml(&path, &condition_env);
+            (path, parsed)
+        })
+        .collect::<Vec<_>>();
+
+    for (mut dir, parsed) in parsed {
+        let parsed = match parsed {
+            Err(e) if !strict => {
+                warn!("Skipping malformed package: {e}");
+                continue;
+            }
+            parsed => parsed?,
+        };
+
+        if let Some((name, package)) = parsed {
+            if ignored_pkgs.contains(&name.as_str())
+                || ignored_globs.iter().any(|g| g.matches(&name))
+            {
+                debug!("Ignoring {name}");
+                ignored.insert(name);
+            } else {
+                dir.pop();
+
+                if let Some((other, _)) = pkgs.get(&name) {
+                    return Err(anyhow!(
+                        "Package {name} found in both {} and {}",
+                        other.display(),
+                        dir.display()
+                    ));
+                }
+
+                pkgs.insert(name, (dir, package));
+            }
+        }
+    }
+
+    Ok((pkgs, ignored))
+}
+
+/// Remove the dependencies of `from` on `to` for every edge, returning the edges missing from
+/// the graph.
+pub(crate) fn remove_edges<'a>(
+    pkgs: &mut PackageMap,
+    edges: &[(&'a str, &'a str)],
+) -> Vec<(&'a str, &'a str)> {
+    edges
+        .iter()
+        .copied()
+        .filter(|(from, to)| match pkgs.get_mut(*from) {
+            Some((_, package)) if package.all_depends().any(|d| d.name == *to) => {
+                package.retain(|d| d.name != *to);
+                false
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+/// Dependencies on ignored workspace packages, as pairs of dependent and dependency names.
+pub(crate) fn ignored_depends<'a>(
+    pkgs: &'a PackageMap,
+    ignored: &'a BTreeSet<String>,
+) -> BTreeSet<(&'a str, &'a str)> {
+    pkgs.iter()
+        .flat_map(|(name, (_, package))| {
+            package
+                .all_depends()
+                .filter(|d| ignored.contains(&d.name))
+                .map(move |d| (name.as_str(), d.name.as_str()))
+        })
+        .collect()
+}
+
+/// Names of the packages under the underlay paths, searching the `share` directory of install
+/// prefixes.
+pub(crate) fn underlay_packages(paths: &[&str], walk: &Walk) -> HashSet<String> {
+    let mut names = HashSet::new();
+
+    for path in paths {
+        let path = Path::new(path);
+        let share = path.join("share");
+        let root = if share.is_dir() { &share } else { path };
+
+        for entry in WalkDir::new(root)
+            .follow_links(walk.follow_symlinks)
+            .into_iter()
+            .filter_entry(|e| {
+                e.depth() == 0 || !walk.pruned.iter().any(|d| e.file_name() == OsStr::new(d))
+            })
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file() && e.file_name() == OsStr::new("package.xml"))
+        {
+            match parse_package_xml(entry.path(), &HashMap::new()) {
+                Ok(Some((name, _))) => {
+                    names.insert(name);
+                }
+                Ok(None) => {}
+                Err(e) => debug!("Skipping underlay package: {e}"),
+            }
+        }
+    }
+
+    names
+}
+
+/// Dependencies on packages neither in the workspace nor `known`, along with the packages
+/// depending on them.
+pub(crate) fn unknown_depends(
+    pkgs: &PackageMap,
+    known: impl Fn(&str) -> bool,
+) -> BTreeMap<&str, BTreeSet<&str>> {
+    let mut unknown = BTreeMap::<_, BTreeSet<_>>::new();
+
+    for (name, (_, package)) in pkgs {
+        for d in package.all_depends() {
+            if !pkgs.contains_key(&d.name) && !known(&d.name) {
+                unknown
+                    .entry(d.name.as_str())
+                    .or_default()
+                    .insert(name.as_str());
+            }
+        }
+    }
+
+    unknown
+}
+
+/// Find dependency cycles among packages that could not be ordered.
+///
+/// Returns the shortest cycle through each package that is part of one. Every cycle is listed
+/// once, starting at its lexicographically smallest package.
+pub(crate) fn find_cycles(pkgs: &[UnorderedPkg]) -> Vec<Vec<String>> {
+    let graph = pkgs
+        .iter()
+        .map(|(n, _, d)| {
+            let mut deps = d.iter().map(|d| d.name.as_str()).collect::<Vec<_>>();
+            deps.sort_unstable();
+            deps.dedup();
+            (n.as_str(), deps)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut nodes = graph.keys().copied().collect::<Vec<_>>();
+    nodes.sort_unstable();
+
+    let mut cycles = vec![];
+
+    for start in nodes {
+        // Breadth-first search back to the starting package yields the shortest cycle
+        let mut parents = HashMap::new();
+        let mut queue = VecDeque::from([start]);
+
+        while let Some(n) = queue.pop_front() {
+            let next = graph.get(n).into_iter().flatten().copied();
+
+            if next.clone().any(|d| d == start) {
+                let mut cycle = vec![n];
+                while let Some(&p) = parents.get(cycle.last().unwrap()) {
+                    cycle.push(p);
+                }
+                cycle.reverse();
+
+                let min = (0..cycle.len()).min_by_key(|&i| cycle[i]).unwrap_or(0);
+                cycle.rotate_left(min);
+
+                let cycle = cycle.into_iter().map(str::to_string).collect::<Vec<_>>();
+                if !cycles.contains(&cycle) {
+                    cycles.push(cycle);
+                }
+                break;
+            }
+
+            for d in next {
+                if d != start && !parents.contains_key(d) && graph.contains_key(d) {
+                    parents.insert(d, n);
+                    queue.push_back(d);
+                }
+            }
+        }
+    }
+
+    cycles
+}
+
+/// Workspace packages owning the files changed since `git_ref`.
+pub(crate) fn changed_packages<'a>(
+    src: &str,
+    git_ref: &str,
+    pkgs: &'a PackageMap,
+    runner: &dyn CommandRunner,
+) -> Result<HashSet<&'a str>> {
+    let o = run_logged(
+        runner,
+        Command::new("git")
+            .args(["diff", "--name-only", "--relative", git_ref])
+            .current_dir(src),
+    )?;
+
+    if !o.status.success() {
+        return Err(anyhow!(
+            "Failed to do git diff {git_ref}: {}",
+            String::from_utf8_lossy(&o.stderr).trim()
+        ));
+    }
+
+    let files = String::from_utf8_lossy(&o.stdout)
+        .lines()
+        .map(|file| Path::new(src).join(file))
+        .collect::<Vec<_>>();
+
+    Ok(owning_packages(files, pkgs))
+}
+
+/// Packages of the workspace without local modifications, whose version is the candidate of
+/// their deb in the apt repositories. Packages outside of git checkouts count as modified.
+pub(crate) fn upstream_matches<'a>(
+    pkgs: &'a PackageMap,
+    srcs: &[&str],
+    ros_distro: &str,
+    name_map: &BTreeMap<&str, &str>,
+    runner: &dyn CommandRunner,
+) -> Result<HashSet<&'a str>> {
+    let mut modified = HashSet::new();
+
+    for src in srcs {
+        let listings = [
+            &["diff", "--name-only", "--relative", "HEAD"][..],
+            &["ls-files", "--others", "--exclude-standard"],
+        ]
+        .map(|args| run_logged(runner, Command::new("git").args(args).current_dir(src)))
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+
+        if listings.iter().any(|o| !o.status.success()) {
+            warn!("{src} is not a git checkout, building all of its packages");
+            modified.extend(
+                pkgs.iter()
+                    .filter(|(_, (dir, _))| dir.starts_with(src))
+                    .map(|(name, _)| name.as_str()),
+            );
+            continue;
+        }
+
+        let files = listings.iter().flat_map(|o| {
+            String::from_utf8_lossy(&o.stdout)
+                .lines()
+                .map(|file| Path::new(src).join(file))
+                .collect::<Vec<_>>()
+        });
+        modified.extend(owning_packages(files, pkgs));
+    }
+
+    let candidates = pkgs
+        .keys()
+        .filter(|p| !modified.contains(p.as_str()))
+        .map(|p| (mapped_deb_name(ros_distro, p, name_map), p.as_str()))
+        .collect::<BTreeMap<_, _>>();
+
+    if candidates.is_empty() {
+        return Ok(HashSet::new());
+    }
+
+    let o = run_logged(
+        runner,
+        Command::new("apt-cache")
+            .arg("policy")
+            .args(candidates.keys()),
+    )?;
+
+    if !o.status.success() {
+        return Err(anyhow!(
+            "Failed to query upstream versions: {}",
+            String::from_utf8_lossy(&o.stderr).trim()
+        ));
+    }
+
+    let stdout = String::from_utf8_lossy(&o.stdout);
+    let available = parse_apt_policy(&stdout);
+
+    Ok(candidates
+        .into_iter()
+        .filter(|(deb, p)| {
+            let version = &pkgs[*p].1.version;
+            let upstream = available.get(deb.as_str()).map(|v| upstream_version(v));
+            debug!("{p}: version {version}, upstream {upstream:?}");
+            upstream == Some(version.as_str())
+        })
+        .map(|(_, p)| p)
+        .collect())
+}
+
+/// Candidate versions of the packages in the output of `apt-cache policy`.
+fn parse_apt_policy(policy: &str) -> HashMap<&str, &str> {
+    let mut candidates = HashMap::new();
+    let mut package = None;
+
+    for line in policy.lines() {
+        if let Some(name) = line.strip_suffix(':').filter(|_| !line.starts_with(' ')) {
+            package = Some(name);
+        } else if let Some(version) = line.trim_start().strip_prefix("Candidate: ") {
+            if let Some(package) = package.filter(|_| version != "(none)") {
+                candidates.insert(package, version);
+            }
+        }
+    }
+
+    candidates
+}
+
+/// Names of the packages the files belong to.
+pub(crate) fn owning_packages(
+    files: impl IntoIterator<Item = PathBuf>,
+    pkgs: &PackageMap,
+) -> HashSet<&str> {
+    let mut changed = HashSet::new();
+
+    for file in files {
+        // Nested packages own their files, not the enclosing one
+        let owner = pkgs
+            .iter()
+            .filter(|(_, (dir, _))| file.starts_with(dir))
+            .max_by_key(|(_, (dir, _))| dir.components().count());
+
+        match owner {
+            Some((name, _)) => {
+                changed.insert(name.as_str());
+            }
+            None => debug!("{} is not part of any package", file.display()),
+        }
+    }
+
+    changed
+}
+
+/// Extend `roots` with every package they transitively depend on.
+pub(crate) fn forward_closure<'a>(
+    pkgs: &'a PackageMap,
+    mut roots: HashSet<&'a str>,
+) -> HashSet<&'a str> {
+    let mut queue = roots.iter().copied().collect::<VecDeque<_>>();
+
+    while let Some(p) = queue.pop_front() {
+        for d in pkgs[p].1.all_depends() {
+            if let Some((name, _)) = pkgs.get_key_value(&d.name) {
+                if roots.insert(name) {
+                    queue.push_back(name);
+                }
+            }
+        }
+    }
+
+    roots
+}
+
+/// Extend `roots` with every package transitively build depending on them.
+pub(crate) fn reverse_closure<'a>(
+    pkgs: &'a PackageMap,
+    mut roots: HashSet<&'a str>,
+) -> HashSet<&'a str> {
+    let mut queue = roots.iter().copied().collect::<VecDeque<_>>();
+
+    while let Some(p) = queue.pop_front() {
+        for (name, (_, package)) in pkgs {
+            if package.build_depends().any(|d| d.name == p) && roots.insert(name) {
+                queue.push_back(name);
+            }
+        }
+    }
+
+    roots
+}
+
+pub(crate) fn format_cycle(cycle: &[String]) -> String {
+    cycle
+        .iter()
+        .chain(cycle.first())
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+/// Render the workspace dependency graph in Graphviz dot format, grouping packages of each
+/// build layer into a cluster. Edges run from dependencies to dependents so that the layers read
+/// left to right, with arrows drawn back at the dependencies.
+pub(crate) fn graph_dot(ordered_pkgs: &[Vec<OrderedPkg>], pkgs: &PackageMap) -> Result<String> {
+    let mut dot = String::new();
+
+    writeln!(dot, "digraph catkin_bloom {{")?;
+    writeln!(dot, "  rankdir=LR;")?;
+    writeln!(dot, "  node [shape=box];")?;
+    writeln!(dot, "  edge [dir=back];")?;
+
+    for (i, layer) in ordered_pkgs.iter().enumerate() {
+        writeln!(dot, "  subgraph cluster_{i} {{")?;
+        writeln!(dot, "    label=\"Layer {i}\";")?;
+        for (p, _, _, _) in layer {
+            writeln!(dot, "    \"{p}\";")?;
+        }
+        writeln!(dot, "  }}")?;
+    }
+
+    let mut names = pkgs.keys().collect::<Vec<_>>();
+    names.sort_unstable();
+
+    for p in names {
+        let mut deps = pkgs[p]
+            .1
+            .build_depends()
+            .map(|d| &d.name)
+            .collect::<Vec<_>>();
+        deps.sort_unstable();
+        deps.dedup();
+
+        for d in deps {
+            writeln!(dot, "  \"{d}\" -> \"{p}\";")?;
+        }
+    }
+
+    writeln!(dot, "}}")?;
+
+    Ok(dot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::deb::patch_control;
+    use crate::manifest::{
+        condition_env, effective_depends, parse_differences, parse_package_xml_strict, Dependency,
+    };
+    use crate::runner::SystemRunner;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn pkg(name: &str, deps: &[&str]) -> (String, PathBuf, HashSet<Dependency>) {
+        let deps = deps
+            .iter()
+            .map(|d| Dependency {
+                name: d.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        (name.into(), PathBuf::from(name), deps)
+    }
+
+    #[test]
+    fn cycles() {
+        let pkgs = [
+            pkg("a", &["b"]),
+            pkg("b", &["c"]),
+            pkg("c", &["a", "b"]),
+            pkg("d", &["d"]),
+            pkg("e", &["a"]),
+        ];
+
+        let cycles = find_cycles(&pkgs);
+
+        assert_eq!(
+            cycles.iter().map(|c| format_cycle(c)).collect::<Vec<_>>(),
+            ["a -> b -> c -> a", "b -> c -> b", "d -> d"]
+        );
+    }
+
+    #[test]
+    fn deterministic_order() {
+        let ws = tempdir().unwrap();
+        for name in ["e", "c", "a", "d", "b", "f"] {
+            let deps = match name {
+                "d" => "<build_depend>a</build_depend><build_depend>c</build_depend>",
+                "b" => "<build_depend>e</build_depend>",
+                "f" => "<exec_depend>a</exec_depend>",
+                _ => "",
+            };
+            write_pkg(ws.path(), name, name, deps);
+        }
+        let src = ws.path().to_str().unwrap();
+
+        let layers = || {
+            // Every collection hashes with a different seed
+            let (pkgs, _) =
+                collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false)
+                    .unwrap();
+            let (ordered, unordered) = order_packages(&pkgs, "melodic", &BTreeMap::new());
+            assert!(unordered.is_empty());
+            ordered
+                .iter()
+                .map(|l| l.iter().map(|(p, ..)| p.clone()).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+
+        let first = layers();
+        assert_eq!(first, [vec!["a", "c", "e", "f"], vec!["b", "d"]]);
+        for _ in 0..10 {
+            assert_eq!(layers(), first);
+        }
+    }
+
+    #[test]
+    fn listing() {
+        let ws = tempdir().unwrap();
+        write_pkg(ws.path(), "a", "a", "");
+        write_pkg(ws.path(), "b", "b", "<build_depend>a</build_depend>");
+        write_pkg(ws.path(), "c", "c", "<exec_depend>b</exec_depend>");
+        write_pkg(ws.path(), "x", "x", "<build_depend>y</build_depend>");
+        write_pkg(ws.path(), "y", "y", "<build_depend>x</build_depend>");
+        let src = ws.path().to_str().unwrap();
+
+        let (pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        let (ordered, _) = order_packages(&pkgs, "melodic", &BTreeMap::new());
+
+        assert_eq!(
+            list_packages(&pkgs, &ordered, "text").unwrap(),
+            "0\ta\n1\tb: a\n0\tc: b\n-\tx: y\n-\ty: x\n"
+        );
+
+        let json = list_packages(&pkgs, &ordered, "json").unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["b"]["layer"], 1);
+        assert_eq!(json["b"]["depends"], serde_json::json!(["a"]));
+        assert_eq!(json["x"]["layer"], serde_json::Value::Null);
+        assert_eq!(json["c"]["path"], ws.path().join("c").to_str().unwrap());
+    }
+
+    #[test]
+    fn dependency_graph() {
+        let ws = tempdir().unwrap();
+        write_pkg(ws.path(), "a", "a", "");
+        write_pkg(ws.path(), "b", "b", "<build_depend>a</build_depend>");
+        let src = ws.path().to_str().unwrap();
+
+        let (pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        let (ordered, _) = order_packages(&pkgs, "melodic", &BTreeMap::new());
+
+        assert_eq!(
+            graph_dot(&ordered, &pkgs).unwrap(),
+            "digraph catkin_bloom {\n  \
+            rankdir=LR;\n  \
+            node [shape=box];\n  \
+            edge [dir=back];\n  \
+            subgraph cluster_0 {\n    label=\"Layer 0\";\n    \"a\";\n  }\n  \
+            subgraph cluster_1 {\n    label=\"Layer 1\";\n    \"b\";\n  }\n  \
+            \"a\" -> \"b\";\n\
+            }\n"
+        );
+    }
+
+    #[test]
+    fn reverse_dependencies() {
+        let ws = tempdir().unwrap();
+        write_pkg(ws.path(), "a", "a", "");
+        write_pkg(ws.path(), "b", "b", "<build_depend>a</build_depend>");
+        write_pkg(ws.path(), "c", "c", "<depend>b</depend>");
+        write_pkg(ws.path(), "d", "d", "<exec_depend>a</exec_depend>");
+        write_pkg(ws.path(), "e", "e", "");
+        write_pkg(ws.path(), "f", "f", "<build_depend>e</build_depend>");
+        let src = ws.path().to_str().unwrap();
+
+        let (pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        let (ordered, _) = order_packages(&pkgs, "melodic", &BTreeMap::new());
+
+        assert_eq!(
+            reverse_depends(&pkgs, &ordered, &["a"]).unwrap(),
+            ["b", "c"]
+        );
+        assert_eq!(reverse_depends(&pkgs, &ordered, &["c"]).unwrap(), [""; 0]);
+        assert_eq!(
+            reverse_depends(&pkgs, &ordered, &["b", "e"]).unwrap(),
+            ["f", "c"]
+        );
+        assert!(reverse_depends(&pkgs, &ordered, &["x"]).is_err());
+    }
+
+    #[test]
+    fn ignored_dependencies() {
+        let ws = tempdir().unwrap();
+        write_pkg(ws.path(), "a", "a", "");
+        write_pkg(ws.path(), "b", "b", "<build_depend>a</build_depend>");
+        write_pkg(
+            ws.path(),
+            "c",
+            "c",
+            "<exec_depend>a</exec_depend><depend>b</depend>",
+        );
+        write_pkg(ws.path(), "d", "d", "<depend>roscpp</depend>");
+        let src = ws.path().to_str().unwrap();
+
+        let (pkgs, ignored) =
+            collect_packages(&[src], &["a"], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        assert_eq!(ignored, BTreeSet::from(["a".to_string()]));
+        assert_eq!(
+            ignored_depends(&pkgs, &ignored),
+            BTreeSet::from([("b", "a"), ("c", "a")])
+        );
+
+        let (pkgs, ignored) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        assert!(ignored_depends(&pkgs, &ignored).is_empty());
+    }
+
+    #[test]
+    fn underlay_dependencies() {
+        let underlay = tempdir().unwrap();
+        write_pkg(underlay.path(), "share/roscpp", "roscpp", "");
+        write_pkg(underlay.path(), "share/build/stale", "stale", "");
+        let sources = tempdir().unwrap();
+        write_pkg(sources.path(), "common/std_msgs", "std_msgs", "");
+
+        let paths = [
+            underlay.path().to_str().unwrap(),
+            sources.path().to_str().unwrap(),
+        ];
+        let walk = Walk {
+            pruned: PRUNED_DIRS,
+            ..Default::default()
+        };
+        let external = underlay_packages(&paths, &walk);
+        assert_eq!(
+            external,
+            HashSet::from(["roscpp".to_string(), "std_msgs".to_string()])
+        );
+
+        let ws = tempdir().unwrap();
+        write_pkg(
+            ws.path(),
+            "a",
+            "a",
+            "<depend>roscpp</depend><depend>rospy</depend>",
+        );
+        write_pkg(
+            ws.path(),
+            "b",
+            "b",
+            "<depend>a</depend><exec_depend>rospy</exec_depend>",
+        );
+        write_pkg(ws.path(), "c", "c", "<depend>std_msg</depend>");
+        let src = ws.path().to_str().unwrap();
+        let (pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+
+        assert_eq!(
+            unknown_depends(&pkgs, |d| external.contains(d)),
+            BTreeMap::from([
+                ("rospy", BTreeSet::from(["a", "b"])),
+                ("std_msg", BTreeSet::from(["c"]))
+            ])
+        );
+    }
+
+    #[test]
+    fn dependency_closure() {
+        let ws = tempdir().unwrap();
+        write_pkg(ws.path(), "a", "a", "");
+        write_pkg(ws.path(), "b", "b", "<build_depend>a</build_depend>");
+        write_pkg(ws.path(), "c", "c", "<exec_depend>b</exec_depend>");
+        write_pkg(
+            ws.path(),
+            "d",
+            "d",
+            "<depend>c</depend><depend>roscpp</depend>",
+        );
+        write_pkg(ws.path(), "e", "e", "<depend>a</depend>");
+        let src = ws.path().to_str().unwrap();
+
+        let (mut pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        for (_, package) in pkgs.values_mut() {
+            package.retain(|d| d.name != "roscpp");
+        }
+
+        let closure = |roots: &[&str]| {
+            let mut closure = forward_closure(&pkgs, roots.iter().copied().collect())
+                .into_iter()
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            closure.sort_unstable();
+            closure
+        };
+
+        assert_eq!(closure(&["a"]), ["a"]);
+        assert_eq!(closure(&["d"]), ["a", "b", "c", "d"]);
+        assert_eq!(closure(&["b", "e"]), ["a", "b", "e"]);
+    }
+
+    #[test]
+    fn layers() {
+        assert_eq!(layer_sizes::<u8>(&[]), None);
+        assert_eq!(
+            layer_sizes(&[vec![1, 2, 3], vec![4], vec![5, 6]]),
+            Some((1, 3, 2.0))
+        );
+    }
+
+    fn write_pkg(root: &Path, dir: &str, name: &str, deps: &str) {
+        let dir = root.join(dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("package.xml"),
+            format!("<package format=\"3\"><name>{name}</name>{deps}</package>"),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn duplicate_packages() {
+        let ws = tempdir().unwrap();
+        write_pkg(ws.path(), "a/foo", "foo", "");
+        write_pkg(ws.path(), "b/foo", "foo", "");
+        let src = ws.path().to_str().unwrap();
+
+        let err = collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Package foo found in both"), "{err}");
+        assert!(err.contains("a/foo") && err.contains("b/foo"), "{err}");
+
+        let ws = tempdir().unwrap();
+        write_pkg(ws.path(), "a/foo", "foo", "<depend>bar</depend>");
+        write_pkg(ws.path(), "bar", "bar", "");
+        let src = ws.path().to_str().unwrap();
+
+        let (pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        assert_eq!(pkgs.len(), 2);
+        assert!(pkgs["foo"].1.all_depends().any(|d| d.name == "bar"));
+    }
+
+    #[test]
+    fn broken_edges() {
+        let ws = tempdir().unwrap();
+        write_pkg(ws.path(), "foo", "foo", "<depend>bar</depend>");
+        write_pkg(ws.path(), "bar", "bar", "<build_depend>foo</build_depend>");
+        let src = ws.path().to_str().unwrap();
+        let (mut pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+
+        let (_, unordered) = order_packages(&pkgs, "melodic", &BTreeMap::new());
+        assert_eq!(unordered.len(), 2);
+
+        assert_eq!(
+            remove_edges(&mut pkgs, &[("bar", "foo"), ("foo", "baz"), ("baz", "foo")]),
+            [("foo", "baz"), ("baz", "foo")]
+        );
+        assert!(pkgs["bar"].1.all_depends().next().is_none());
+        assert!(pkgs["foo"].1.all_depends().any(|d| d.name == "bar"));
+
+        let (ordered, unordered) = order_packages(&pkgs, "melodic", &BTreeMap::new());
+        assert!(unordered.is_empty());
+        assert_eq!(ordered.len(), 2);
+    }
+
+    #[test]
+    fn name_mapping() {
+        let ws = tempdir().unwrap();
+        write_pkg(
+            ws.path(),
+            "foo",
+            "foo",
+            "<depend version_gte=\"1.0\">bar_lib</depend>",
+        );
+        write_pkg(ws.path(), "bar_lib", "bar_lib", "");
+        let src = ws.path().to_str().unwrap();
+        let (pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+
+        let name_map = BTreeMap::from([("bar_lib", "libbar")]);
+        let (ordered, _) = order_packages(&pkgs, "melodic", &name_map);
+        let names = ordered.iter().flatten().map(|p| p.1.as_str());
+        assert_eq!(names.collect::<Vec<_>>(), ["libbar", "ros-melodic-foo"]);
+
+        let control = "Source: ros-melodic-foo\nDepends: libbar\n";
+        assert_eq!(
+            patch_control(control, &pkgs["foo"].1, "melodic", &name_map),
+            "Source: ros-melodic-foo\nDepends: libbar (>= 1.0)\n"
+        );
+    }
+
+    #[test]
+    fn ros_version_override() {
+        let ws = tempdir().unwrap();
+        write_pkg(
+            ws.path(),
+            "foo",
+            "foo",
+            "<depend condition=\"$ROS_VERSION == 2\">bar</depend>",
+        );
+        write_pkg(ws.path(), "bar", "bar", "");
+        let src = ws.path().to_str().unwrap();
+
+        let depends = |ros_version| {
+            let (pkgs, _) = collect_packages(
+                &[src],
+                &[],
+                &[],
+                "mydistro",
+                ros_version,
+                &Walk::default(),
+                false,
+            )
+            .unwrap();
+            let names = pkgs["foo"].1.all_depends().map(|d| d.name.clone());
+            names.collect::<Vec<_>>()
+        };
+        assert!(depends("1").is_empty());
+        assert_eq!(depends("2"), ["bar"]);
+    }
+
+    #[test]
+    fn multiple_trees() {
+        let ws = tempdir().unwrap();
+        let other = tempdir().unwrap();
+        write_pkg(ws.path(), "foo", "foo", "<depend>bar</depend>");
+        write_pkg(other.path(), "bar", "bar", "");
+        let srcs = [ws.path().to_str().unwrap(), other.path().to_str().unwrap()];
+
+        let (pkgs, _) =
+            collect_packages(&srcs, &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        let (ordered, unordered) = order_packages(&pkgs, "melodic", &BTreeMap::new());
+        assert!(unordered.is_empty());
+        assert_eq!(
+            ordered
+                .iter()
+                .map(|l| l.iter().map(|p| p.0.as_str()).collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+            [["bar"], ["foo"]]
+        );
+
+        // Nested trees find the same packages again
+        let nested = ws.path().join("foo");
+        let srcs = [ws.path().to_str().unwrap(), nested.to_str().unwrap()];
+        let (pkgs, _) =
+            collect_packages(&srcs, &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        assert_eq!(pkgs.keys().collect::<Vec<_>>(), ["foo"]);
+
+        write_pkg(other.path(), "foo", "foo", "");
+        let srcs = [ws.path().to_str().unwrap(), other.path().to_str().unwrap()];
+        let err = collect_packages(&srcs, &[], &[], "melodic", "1", &Walk::default(), false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Package foo found in both"), "{err}");
+    }
+
+    #[test]
+    fn follow_symlinks() {
+        let ws = tempdir().unwrap();
+        let vendor = tempdir().unwrap();
+        write_pkg(vendor.path(), "foo", "foo", "");
+        std::os::unix::fs::symlink(vendor.path().join("foo"), ws.path().join("foo")).unwrap();
+        std::os::unix::fs::symlink(vendor.path().join("foo"), ws.path().join("foo2")).unwrap();
+        std::os::unix::fs::symlink(ws.path(), ws.path().join("loop")).unwrap();
+        let src = ws.path().to_str().unwrap();
+
+        assert!(
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false)
+                .unwrap()
+                .0
+                .is_empty()
+        );
+
+        let follow = Walk {
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        let (pkgs, _) = collect_packages(&[src], &[], &[], "melodic", "1", &follow, false).unwrap();
+        assert_eq!(pkgs.keys().collect::<Vec<_>>(), ["foo"]);
+    }
+
+    #[test]
+    fn pruned_dirs() {
+        let ws = tempdir().unwrap();
+        write_pkg(ws.path(), "build", "build", "");
+        write_pkg(ws.path(), "src/foo", "foo", "");
+        write_pkg(ws.path(), "install/share/foo", "foo", "");
+        write_pkg(ws.path(), "vendor/bar", "bar", "");
+        let src = ws.path().to_str().unwrap();
+
+        let walk = Walk {
+            pruned: PRUNED_DIRS,
+            ..Default::default()
+        };
+        let (pkgs, _) = collect_packages(&[src], &[], &[], "melodic", "1", &walk, false).unwrap();
+        assert_eq!(
+            pkgs.keys().collect::<BTreeSet<_>>(),
+            BTreeSet::from([&"bar".to_string(), &"foo".to_string()])
+        );
+
+        // The package itself is in a directory with a pruned name
+        let walk = Walk {
+            pruned: &["build", "install", "vendor"],
+            ..Default::default()
+        };
+        let (pkgs, _) = collect_packages(&[src], &[], &[], "melodic", "1", &walk, false).unwrap();
+        assert!(!pkgs.contains_key("bar") && !pkgs.contains_key("build"));
+
+        // Pruning does not apply to the source trees themselves
+        let root = ws.path().join("install");
+        let src = root.to_str().unwrap();
+        let (pkgs, _) = collect_packages(&[src], &[], &[], "melodic", "1", &walk, false).unwrap();
+        assert_eq!(pkgs.keys().collect::<Vec<_>>(), ["foo"]);
+    }
+
+    #[test]
+    fn malformed_packages() {
+        let ws = tempdir().unwrap();
+        write_pkg(ws.path(), "foo", "foo", "");
+        fs::create_dir_all(ws.path().join("bar")).unwrap();
+        fs::write(
+            ws.path().join("bar/package.xml"),
+            "<package format=\"3\">\n  <name>bar</name>\n  <depend>foo</exec_depend>\n</package>",
+        )
+        .unwrap();
+        let src = ws.path().to_str().unwrap();
+
+        let (pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        assert_eq!(pkgs.keys().collect::<Vec<_>>(), ["foo"]);
+
+        let err = collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), true)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("bar/package.xml:3:"), "{err}");
+    }
+
+    #[test]
+    fn package_names() {
+        let ws = tempdir().unwrap();
+        write_pkg(
+            ws.path(),
+            "foo",
+            "foo",
+            "<author><name>Jane</name></author><export><name>bar</name></export>",
+        );
+        fs::create_dir_all(ws.path().join("bar")).unwrap();
+        fs::write(
+            ws.path().join("bar/package.xml"),
+            "<ros:package xmlns:ros=\"http://ros.org\"><ros:name>bar</ros:name>\
+            <ros:depend>foo</ros:depend></ros:package>",
+        )
+        .unwrap();
+        let src = ws.path().to_str().unwrap();
+
+        let (pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        let mut names = pkgs.keys().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["bar", "foo"]);
+        assert!(pkgs["bar"].1.all_depends().any(|d| d.name == "foo"));
+    }
+
+    #[test]
+    fn audit_parse() {
+        let ws = tempdir().unwrap();
+        write_pkg(
+            ws.path(),
+            "foo",
+            "foo",
+            "<depend>a</depend><run_depend>b</run_depend>\
+            <test_depend condition=\"$ROS_VERSION == 2\">c</test_depend>\
+            <export><build_depend>d</build_depend></export>",
+        );
+        let src = ws.path().to_str().unwrap();
+        let (pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        let (dir, package) = &pkgs["foo"];
+
+        let ours = effective_depends(package);
+        assert_eq!(ours["build"], BTreeSet::from(["a".into(), "d".into()]));
+        assert_eq!(ours["exec"], BTreeSet::from(["a".into(), "b".into()]));
+        assert!(ours["test"].is_empty());
+
+        // The strict parser does not take dependencies nested in other elements
+        let env = condition_env("melodic", "1");
+        let strict = parse_package_xml_strict(&dir.join("package.xml"), &env).unwrap();
+        assert_eq!(
+            parse_differences(&ours, &effective_depends(&strict), "the strict parser"),
+            ["build_depends only found by catkin-bloom: d"]
+        );
+        assert!(parse_differences(&ours, &ours, "the strict parser").is_empty());
+    }
+
+    #[test]
+    fn maintainers_and_licenses() {
+        let ws = tempdir().unwrap();
+        write_pkg(
+            ws.path(),
+            "foo",
+            "foo",
+            "<maintainer email=\"jane@example.com\">Jane Doe</maintainer>\
+            <maintainer>John Doe</maintainer><license>BSD</license><license>Apache-2.0</license>\
+            <author><license>MIT</license></author>",
+        );
+        let src = ws.path().to_str().unwrap();
+
+        let (pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        let package = &pkgs["foo"].1;
+        assert_eq!(
+            package.maintainers,
+            ["Jane Doe <jane@example.com>", "John Doe"]
+        );
+        assert_eq!(package.licenses, ["BSD", "Apache-2.0"]);
+
+        let metadata: serde_json::Value =
+            serde_json::from_str(&package_metadata(&pkgs).unwrap()).unwrap();
+        assert_eq!(metadata["foo"]["licenses"][1], "Apache-2.0");
+        assert_eq!(metadata["foo"]["maintainers"][1], "John Doe");
+    }
+
+    #[test]
+    fn dependency_kinds() {
+        let ws = tempdir().unwrap();
+        write_pkg(
+            ws.path(),
+            "foo",
+            "foo",
+            "<depend>a</depend><build_depend>b</build_depend>\
+            <buildtool_depend>c</buildtool_depend><exec_depend>d</exec_depend>\
+            <test_depend>e</test_depend><group_depend>f</group_depend>",
+        );
+        let src = ws.path().to_str().unwrap();
+
+        let (pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        let foo = &pkgs["foo"].1;
+
+        let mut build = foo
+            .build_depends()
+            .map(|d| d.name.as_str())
+            .collect::<Vec<_>>();
+        build.sort_unstable();
+        assert_eq!(build, ["a", "b", "c"]);
+
+        let mut all = foo
+            .all_depends()
+            .map(|d| d.name.as_str())
+            .collect::<Vec<_>>();
+        all.sort_unstable();
+        assert_eq!(all, ["a", "b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn ignore_globs() {
+        let ws = tempdir().unwrap();
+        for name in [
+            "foo",
+            "foo_tests",
+            "experimental_bar",
+            "bar1",
+            "bar2",
+            "bar3",
+        ] {
+            write_pkg(ws.path(), name, name, "");
+        }
+        let src = ws.path().to_str().unwrap();
+
+        let collect = |globs: &[&str]| {
+            let globs = globs
+                .iter()
+                .map(|g| Pattern::new(g).unwrap())
+                .collect::<Vec<_>>();
+            let (pkgs, _) = collect_packages(
+                &[src],
+                &["foo"],
+                &globs,
+                "melodic",
+                "1",
+                &Walk::default(),
+                false,
+            )
+            .unwrap();
+            let mut names = pkgs.into_keys().collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        assert_eq!(
+            collect(&[]),
+            ["bar1", "bar2", "bar3", "experimental_bar", "foo_tests"]
+        );
+
+        fs::write(ws.path().join("bar2/COLCON_IGNORE"), "").unwrap();
+        fs::write(ws.path().join("bar3/CATKIN_IGNORE"), "").unwrap();
+        assert_eq!(collect(&[]), ["bar1", "experimental_bar", "foo_tests"]);
+        fs::remove_file(ws.path().join("bar2/COLCON_IGNORE")).unwrap();
+        fs::remove_file(ws.path().join("bar3/CATKIN_IGNORE")).unwrap();
+        assert_eq!(
+            collect(&["experimental_*", "*_tests"]),
+            ["bar1", "bar2", "bar3"]
+        );
+        assert_eq!(collect(&["bar?"]), ["experimental_bar", "foo_tests"]);
+        assert_eq!(
+            collect(&["bar[12]"]),
+            ["bar3", "experimental_bar", "foo_tests"]
+        );
+        assert_eq!(
+            collect(&["bar[!12]"]),
+            ["bar1", "bar2", "experimental_bar", "foo_tests"]
+        );
+    }
+
+    #[test]
+    fn changed_since() {
+        let ws = tempdir().unwrap();
+        write_pkg(ws.path(), "a", "a", "");
+        write_pkg(ws.path(), "a/nested", "nested", "");
+        write_pkg(ws.path(), "b", "b", "<build_depend>a</build_depend>");
+        write_pkg(ws.path(), "c", "c", "<exec_depend>a</exec_depend>");
+        write_pkg(ws.path(), "d", "d", "<depend>b</depend>");
+        let src = ws.path().to_str().unwrap();
+
+        let git = |args: &[&str]| {
+            let o = Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(src)
+                .output()
+                .unwrap();
+            assert!(o.status.success(), "{o:?}");
+        };
+        git(&["init", "-q"]);
+        git(&["add", "."]);
+        git(&["commit", "-qm", "init"]);
+
+        let (pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+
+        assert!(changed_packages(src, "HEAD", &pkgs, &SystemRunner)
+            .unwrap()
+            .is_empty());
+
+        fs::write(ws.path().join("a/nested/CMakeLists.txt"), "").unwrap();
+        git(&["add", "."]);
+        assert_eq!(
+            changed_packages(src, "HEAD", &pkgs, &SystemRunner).unwrap(),
+            HashSet::from(["nested"])
+        );
+
+        fs::write(
+            ws.path().join("a/package.xml"),
+            "<package><name>a</name></package>",
+        )
+        .unwrap();
+        let changed = changed_packages(src, "HEAD", &pkgs, &SystemRunner).unwrap();
+        assert_eq!(changed, HashSet::from(["a", "nested"]));
+        assert_eq!(
+            reverse_closure(&pkgs, changed),
+            HashSet::from(["a", "nested", "b", "d"])
+        );
+
+        assert!(changed_packages(src, "missing-ref", &pkgs, &SystemRunner).is_err());
+    }
+
+    #[test]
+    fn padded_names() {
+        let ws = tempdir().unwrap();
+        write_pkg(ws.path(), "foo", "\n  foo\n", "");
+        write_pkg(
+            ws.path(),
+            "bar",
+            " bar ",
+            "<depend>\n    foo\n  </depend>\
+            <exec_depend><!-- needed at runtime -->\n  baz <!-- system --></exec_depend>",
+        );
+        let src = ws.path().to_str().unwrap();
+
+        let (pkgs, _) =
+            collect_packages(&[src], &[], &[], "melodic", "1", &Walk::default(), false).unwrap();
+        let mut names = pkgs.keys().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["bar", "foo"]);
+
+        let mut deps = pkgs["bar"]
+            .1
+            .all_depends()
+            .map(|d| d.name.as_str())
+            .collect::<Vec<_>>();
+        deps.sort_unstable();
+        assert_eq!(deps, ["baz", "foo"]);
+        assert!(pkgs["bar"].1.build_depends().any(|d| d.name == "foo"));
+    }
+}
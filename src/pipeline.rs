@@ -0,0 +1,1724 @@
+//! Building a whole workspace, once or on every change.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use rayon::{iter::*, *};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env::current_dir;
+use std::ffi::OsStr;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::backend::{install_batched, rosdep_problems};
+use crate::bloom::{bloom, export_metadata, generate_debian, BloomConfig, Built, Generated};
+use crate::cache::{cache_keys, cached_build, checkpoint_entry, read_checkpoint, CHECKPOINT_FILE};
+use crate::config::{parse_package_list, InstallMode, Progress, RuntimeArgs};
+use crate::error::{categorize, BloomError, INTERRUPTED};
+use crate::index::{pin_priorities, rosdep_yaml, snapshot_url, RepoLayout};
+use crate::manifest::{
+    effective_depends, parse_differences, reference_depends, ros_version, ReferenceParse,
+    ROS1_DISTROS, ROS2_DISTROS,
+};
+use crate::packages::{
+    changed_packages, collect_packages, find_cycles, format_cycle, forward_closure, graph_dot,
+    ignored_depends, layer_sizes, list_packages, order_packages, owning_packages, package_metadata,
+    remove_edges, reverse_closure, reverse_depends, underlay_packages, unknown_depends,
+    upstream_matches, OrderedPkg, PackageMap, UnorderedPkg, Walk, PRUNED_DIRS,
+};
+use crate::prerequisites::{
+    available_space, check_build_tmp, check_prerequisites, distinct_file_systems, MIB,
+};
+use crate::report::{
+    timing_table, write_report, PackageReport, Reporter, RunReport, Status, Timings,
+};
+use crate::runner::{privileged, run_logged, run_with_retries, CommandRunner, NONINTERACTIVE};
+use crate::vcs::{fetch_repos, parse_repos};
+
+/// Quiet period after the last change to the sources before rebuilding in watch mode.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Build the workspace, then rebuild the packages affected by every batch of source changes
+/// until interrupted.
+pub(crate) fn watch(args: RuntimeArgs, runner: &dyn CommandRunner) -> Result<RunReport> {
+    let reporter = Reporter::new(args.color).with_quiet(args.quiet);
+
+    let mut report = build_workspace(args.clone(), None, runner)?;
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for src in &args.srcs {
+        notify::Watcher::watch(
+            &mut watcher,
+            Path::new(src),
+            notify::RecursiveMode::Recursive,
+        )?;
+    }
+
+    // Events come with absolute paths, packages are found relative to the trees as given
+    let roots = args
+        .srcs
+        .iter()
+        .map(|src| Ok((current_dir()?.join(src), Path::new(*src))))
+        .collect::<Result<Vec<_>>>()?;
+    let repo = current_dir()?.join(args.repo_path);
+    let debs = current_dir()?.join(args.deb_dir.unwrap_or(args.repo_path));
+    let pruned = PRUNED_DIRS
+        .iter()
+        .filter(|_| !args.no_prune)
+        .chain(&args.prune_dirs)
+        .map(OsStr::new)
+        .collect::<HashSet<_>>();
+
+    let source_path = |path: &Path| {
+        // Editor swap and backup files
+        let name = path.file_name()?.to_str()?;
+        if name.starts_with('.')
+            || name.ends_with('~')
+            || path.starts_with(&repo)
+            || path.starts_with(&debs)
+        {
+            return None;
+        }
+
+        let (abs, src) = roots.iter().find(|(abs, _)| path.starts_with(abs))?;
+        let rel = path.strip_prefix(abs).ok()?;
+        if rel.components().any(|c| pruned.contains(c.as_os_str())) {
+            return None;
+        }
+
+        Some(src.join(rel))
+    };
+
+    while !report.interrupted && !report.budget_exceeded {
+        reporter.step("Watching for changes, interrupt to stop");
+
+        let mut changed = BTreeSet::new();
+
+        let changed = loop {
+            match rx.recv_timeout(WATCH_DEBOUNCE) {
+                Ok(Ok(event)) if !event.kind.is_access() => {
+                    changed.extend(event.paths.iter().filter_map(|p| source_path(p)));
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("Failed to watch the sources: {e}"),
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                    if INTERRUPTED.load(Ordering::Relaxed) {
+                        return Ok(report);
+                    }
+                    if !changed.is_empty() {
+                        break changed;
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        for path in &changed {
+            debug!("Changed {}", path.display());
+        }
+
+        let changed = changed.into_iter().collect::<Vec<_>>();
+
+        // A failed rebuild is fixed by the next change
+        match build_workspace(args.clone(), Some(&changed), runner) {
+            Ok(r) => report = r,
+            Err(e) => reporter.error(format!("Rebuild failed: {e}")),
+        }
+    }
+
+    Ok(report)
+}
+
+/// Build the workspace, only rebuilding the packages owning `changed_files` and everything
+/// depending on them if given.
+pub(crate) fn build_workspace(
+    args: RuntimeArgs,
+    changed_files: Option<&[PathBuf]>,
+    runner: &dyn CommandRunner,
+) -> Result<RunReport> {
+    debug!("{args:?}");
+
+    check_prerequisites(&args, runner)?;
+
+    if let Some(dir) = args.build_tmp.filter(|_| !args.dry_run) {
+        check_build_tmp(Path::new(dir))?;
+    }
+
+    let mut run = Run::new(&args, runner);
+
+    // Step 1 - collect all dependencies in the workspace
+    let Some((mut pkgs, ignored)) = run.collect()? else {
+        return Ok(RunReport::default());
+    };
+
+    if args.audit_parse {
+        run.audit_parse(&pkgs)?;
+        return Ok(RunReport::default());
+    }
+
+    // Step 2 - clear out any non-workspace deps
+    run.prune_depends(&mut pkgs, &ignored)?;
+
+    // Step 3 - sort the packages in the dependency fullfilling order
+    let order = run.order(&pkgs)?;
+
+    if run.inspect(&pkgs, &order)? {
+        return Ok(RunReport::default());
+    }
+
+    let selection = run.select(&pkgs, changed_files)?;
+
+    // Step 4 - generate packages
+    run.register_sources(&selection.scoped(&order.layers))?;
+    run.update_rosdep()?;
+
+    if args.manifest_only {
+        run.reindex()?;
+        return Ok(RunReport::default());
+    }
+
+    run.install_dependencies(&pkgs, &selection)?;
+
+    let config = run.bloom_config()?;
+    let package_envs = run.package_envs(&pkgs);
+
+    if let Some(out) = args.only_generate {
+        run.export(Path::new(out), &order.layers, &selection, &config)?;
+        return Ok(RunReport::default());
+    }
+
+    let mut report = run.build(&pkgs, order, &selection, &config, &package_envs)?;
+
+    run.publish(&pkgs, &selection, &mut report)?;
+
+    Ok(report)
+}
+
+/// Settings and helpers shared by the phases of a workspace build.
+struct Run<'a> {
+    args: &'a RuntimeArgs<'a>,
+    runner: &'a dyn CommandRunner,
+    reporter: Reporter,
+    pool: ThreadPool,
+    ros_version: &'a str,
+    layout: RepoLayout<'a>,
+    pruned_dirs: Vec<&'a str>,
+    package_root: &'a Path,
+    /// The packages themselves may live apart from the index referencing them.
+    deb_root: &'a Path,
+}
+
+/// The workspace packages in build order.
+struct BuildOrder {
+    layers: Vec<Vec<OrderedPkg>>,
+    /// Packages left out of the layers by dependency cycles.
+    unordered: Vec<UnorderedPkg>,
+    cycles: Vec<Vec<String>>,
+}
+
+/// The packages to build, everything by default.
+struct Selection<'p> {
+    only_check: Option<Vec<&'p str>>,
+    /// The selected packages and what they need to build and install.
+    scope: Option<HashSet<&'p str>>,
+}
+
+impl Selection<'_> {
+    fn contains(&self, p: &str) -> bool {
+        self.only_check
+            .as_ref()
+            .map(|v| v.contains(&p))
+            .unwrap_or(true)
+    }
+
+    /// The layers left with the packages in scope.
+    fn scoped(&self, ordered_pkgs: &[Vec<OrderedPkg>]) -> Vec<Vec<OrderedPkg>> {
+        match &self.scope {
+            Some(scope) => ordered_pkgs
+                .iter()
+                .map(|l| {
+                    l.iter()
+                        .filter(|(p, ..)| scope.contains(p.as_str()))
+                        .cloned()
+                        .collect()
+                })
+                .collect(),
+            None => ordered_pkgs.to_vec(),
+        }
+    }
+
+    /// The paths to install the dependencies of, the sources of the packages in scope.
+    fn dep_paths(&self, pkgs: &PackageMap, srcs: &[&str]) -> Vec<PathBuf> {
+        match &self.scope {
+            Some(scope) => scope
+                .iter()
+                .map(|p| pkgs[*p].0.clone())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+            None => srcs.iter().map(PathBuf::from).collect(),
+        }
+    }
+}
+
+impl<'a> Run<'a> {
+    fn new(args: &'a RuntimeArgs<'a>, runner: &'a dyn CommandRunner) -> Self {
+        let layout = if args.flat_repo {
+            RepoLayout::Flat
+        } else {
+            RepoLayout::Suite(args.os_version, args.deb_arch)
+        };
+
+        let reporter = Reporter::new(args.color)
+            .with_stderr(args.list.is_some() || !args.rdeps.is_empty())
+            .with_quiet(args.quiet);
+
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(args.jobs)
+            .build()
+            .unwrap();
+
+        let ros_distro = args.ros_distro;
+        let ros_version = match args.ros_version {
+            Some(version) => {
+                let known = ROS1_DISTROS
+                    .iter()
+                    .chain(ROS2_DISTROS)
+                    .any(|d| *d == ros_distro);
+                if known && version != ros_version(ros_distro) {
+                    reporter.warn(format!(
+                        "--ros-version {version} contradicts {ros_distro}, a ROS {} distribution",
+                        ros_version(ros_distro)
+                    ));
+                }
+                version
+            }
+            None => ros_version(ros_distro),
+        };
+
+        let pruned_dirs = PRUNED_DIRS
+            .iter()
+            .filter(|_| !args.no_prune)
+            .chain(&args.prune_dirs)
+            .copied()
+            .collect();
+
+        Self {
+            args,
+            runner,
+            reporter,
+            pool,
+            ros_version,
+            layout,
+            pruned_dirs,
+            package_root: Path::new(args.repo_path),
+            deb_root: Path::new(args.deb_dir.unwrap_or(args.repo_path)),
+        }
+    }
+
+    fn walk(&self) -> Walk<'_> {
+        Walk {
+            follow_symlinks: self.args.follow_symlinks,
+            pruned: &self.pruned_dirs,
+        }
+    }
+
+    /// Collect the workspace packages along with the names of the ignored ones, `None` if there
+    /// is nothing to build.
+    fn collect(&self) -> Result<Option<(PackageMap, BTreeSet<String>)>> {
+        let args = self.args;
+        let reporter = &self.reporter;
+
+        if let Some(path) = args.from_repos {
+            let repos = parse_repos(&fs::read_to_string(path)?)?;
+            reporter.step(format!("Fetching {} repositories from {path}", repos.len()));
+            fetch_repos(args.srcs[0], &repos, args.dry_run, reporter, self.runner)?;
+        }
+
+        reporter.step("Collecting packages");
+
+        let walk = self.walk();
+
+        let (pkgs, ignored) = self.pool.install(|| {
+            collect_packages(
+                &args.srcs,
+                &args.ignored_pkgs,
+                &args.ignored_globs,
+                args.ros_distro,
+                self.ros_version,
+                &walk,
+                args.strict,
+            )
+        })?;
+
+        // Nothing to build is more likely a wrong path than intended
+        if pkgs.is_empty() {
+            let mut msg = format!("No packages found in {}", args.srcs.join(", "));
+            if !ignored.is_empty() {
+                write!(msg, ", {} packages are ignored", ignored.len())?;
+            }
+            if !args.allow_empty {
+                return Err(anyhow!(msg));
+            }
+            reporter.warn(msg);
+            return Ok(None);
+        }
+
+        // Dependencies found nowhere are likely misspelled, unless rosdep knows them
+        if !args.underlays.is_empty() {
+            for path in args.underlays.iter().filter(|p| !Path::new(p).exists()) {
+                reporter.warn(format!("Underlay {path} does not exist"));
+            }
+
+            let external = underlay_packages(&args.underlays, &walk);
+            reporter.info(format!(
+                "Found {} packages in {} underlays",
+                external.len(),
+                args.underlays.len()
+            ));
+
+            let known = |name: &str| {
+                external.contains(name)
+                    || ignored.contains(name)
+                    || args.rosdep_defs.iter().any(|(k, _)| k == name)
+            };
+
+            for (dep, users) in unknown_depends(&pkgs, known) {
+                reporter.warn(format!(
+                    "{dep}, needed by {}, is neither in the workspace nor in an underlay and may \
+                    be missing",
+                    users.into_iter().collect::<Vec<_>>().join(", ")
+                ));
+            }
+        }
+
+        Ok(Some((pkgs, ignored)))
+    }
+
+    /// Compare the parsed dependencies with the ones of the reference parser.
+    fn audit_parse(&self, pkgs: &PackageMap) -> Result<()> {
+        let reporter = &self.reporter;
+
+        let (reference, theirs) =
+            reference_depends(pkgs, self.args.ros_distro, self.ros_version, self.runner)?;
+        reporter.step(format!("Comparing dependencies with {reference}"));
+
+        let mut names = pkgs.keys().collect::<Vec<_>>();
+        names.sort_unstable();
+
+        let mut differing = 0;
+        for name in names {
+            let (dir, package) = &pkgs[name];
+            let diff = match &theirs[dir] {
+                ReferenceParse::Depends(depends) => {
+                    parse_differences(&effective_depends(package), depends, reference)
+                }
+                ReferenceParse::Error(e) => vec![format!("{reference} failed: {e}")],
+            };
+
+            if !diff.is_empty() {
+                differing += 1;
+                reporter.warn(format!("{name} parses differently:"));
+                for line in diff {
+                    reporter.info(format!("  {line}"));
+                }
+            }
+        }
+
+        if differing == 0 {
+            reporter.ok(format!(
+                "All {} packages parse the same with {reference}",
+                pkgs.len()
+            ));
+        } else {
+            reporter.warn(format!(
+                "{differing} of {} packages parse differently with {reference}",
+                pkgs.len()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Leave the packages with their dependencies on each other, except for the broken edges.
+    fn prune_depends(&self, pkgs: &mut PackageMap, ignored: &BTreeSet<String>) -> Result<()> {
+        let args = self.args;
+        let reporter = &self.reporter;
+
+        // Skipped test dependencies neither order, break nor get generated into anything
+        if args.skip_test_depends {
+            for (_, package) in pkgs.values_mut() {
+                package.test_depend.clear();
+            }
+        }
+
+        // Ignoring a package breaks the builds of packages depending on it
+        let broken = ignored_depends(pkgs, ignored);
+
+        for (p, d) in &broken {
+            reporter.warn(format!("{p} depends on ignored package {d}"));
+        }
+
+        if args.strict_deps && !broken.is_empty() {
+            let deps = broken
+                .iter()
+                .map(|(p, d)| format!("{p} -> {d}"))
+                .collect::<Vec<_>>();
+            return Err(anyhow!(
+                "Found {} dependencies on ignored packages: {}",
+                deps.len(),
+                deps.join(", ")
+            ));
+        }
+
+        let workspace_pkgs = pkgs.keys().cloned().collect::<HashSet<_>>();
+        for (_, package) in pkgs.values_mut() {
+            package.retain(|v| workspace_pkgs.contains(&v.name));
+        }
+
+        // Break the edges given on the command line, usually to resolve cycles
+        let missing = remove_edges(pkgs, &args.break_edges);
+
+        for (from, to) in &args.break_edges {
+            if missing.contains(&(from, to)) {
+                reporter.warn(format!(
+                    "{from} does not depend on workspace package {to}, nothing to break"
+                ));
+            } else {
+                reporter.info(format!("Ignoring dependency of {from} on {to}"));
+            }
+        }
+
+        trace!("{pkgs:?}");
+
+        Ok(())
+    }
+
+    /// Sort the packages into layers, only build dependencies have to be built first.
+    fn order(&self, pkgs: &PackageMap) -> Result<BuildOrder> {
+        let args = self.args;
+        let reporter = &self.reporter;
+
+        let (layers, unordered) = order_packages(pkgs, args.ros_distro, &args.name_map);
+
+        trace!("{layers:?}");
+
+        let cycles = find_cycles(&unordered);
+
+        if !unordered.is_empty() {
+            for cycle in &cycles {
+                warn!("Found dependency cycle: {}", format_cycle(cycle));
+            }
+
+            let mut blocked = unordered
+                .iter()
+                .map(|(n, _, _)| n.as_str())
+                .filter(|n| !cycles.iter().flatten().any(|c| c == n))
+                .collect::<Vec<_>>();
+            blocked.sort_unstable();
+
+            if !blocked.is_empty() {
+                warn!("Packages depending on a cycle: {}", blocked.join(", "));
+            }
+
+            if args.strict_cycles {
+                return Err(BloomError::DependencyCycle(format!(
+                    "Found {} dependency cycle(s)",
+                    cycles.len()
+                ))
+                .into());
+            }
+        }
+
+        if let Some((min, max, mean)) = layer_sizes(&layers) {
+            reporter.info(format!(
+                "Ordered {} packages into {} layers of {min} to {max} packages, {mean:.1} on \
+                average",
+                pkgs.len(),
+                layers.len()
+            ));
+        }
+
+        // Many layers for few packages mean the builds hardly run in parallel
+        if let Some(percent) = args.warn_deep_graph {
+            if layers.len() * 100 > percent * pkgs.len() {
+                reporter.warn(format!(
+                    "{} layers for {} packages exceed {percent}%, the dependency graph is close \
+                    to a chain. Over-specified dependencies limit build parallelism",
+                    layers.len(),
+                    pkgs.len()
+                ));
+            }
+        }
+
+        Ok(BuildOrder {
+            layers,
+            unordered,
+            cycles,
+        })
+    }
+
+    /// Check, list or dump the dependency graph, `true` if that is all to do.
+    fn inspect(&self, pkgs: &PackageMap, order: &BuildOrder) -> Result<bool> {
+        let args = self.args;
+        let reporter = &self.reporter;
+
+        if args.check_graph {
+            if order.unordered.is_empty() {
+                reporter.ok(format!(
+                    "No dependency cycles among {} packages",
+                    pkgs.len()
+                ));
+                return Ok(true);
+            }
+
+            for cycle in &order.cycles {
+                reporter.error(format!("Dependency cycle: {}", format_cycle(cycle)));
+            }
+
+            return Err(BloomError::DependencyCycle(format!(
+                "Found {} dependency cycle(s), {} packages cannot be ordered",
+                order.cycles.len(),
+                order.unordered.len()
+            ))
+            .into());
+        }
+
+        if let Some(format) = args.list {
+            print!("{}", list_packages(pkgs, &order.layers, format)?);
+            return Ok(true);
+        }
+
+        if !args.rdeps.is_empty() {
+            for p in reverse_depends(pkgs, &order.layers, &args.rdeps)? {
+                println!("{p}");
+            }
+            return Ok(true);
+        }
+
+        if let Some(path) = args.dump_graph {
+            reporter.step(format!("Writing dependency graph to {path}"));
+            fs::write(path, graph_dot(&order.layers, pkgs)?)?;
+        }
+
+        Ok(false)
+    }
+
+    /// Select the packages to build from the command line, the changed sources and the upstream
+    /// releases.
+    fn select<'p>(
+        &self,
+        pkgs: &'p PackageMap,
+        changed_files: Option<&[PathBuf]>,
+    ) -> Result<Selection<'p>> {
+        let args = self.args;
+        let reporter = &self.reporter;
+
+        // Merge the packages selected on the command line with the ones listed in a file
+
+        let listed = args
+            .only_check_file
+            .map(|path| {
+                fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Failed to read only-check file {path}: {e}"))
+            })
+            .transpose()?;
+
+        let only_check = match (&args.only_check, &listed) {
+            (None, None) => None,
+            (only_check, listed) => Some(
+                only_check
+                    .iter()
+                    .flatten()
+                    .copied()
+                    .chain(listed.iter().flat_map(|l| parse_package_list(l)))
+                    .collect::<Vec<_>>(),
+            ),
+        };
+
+        // Selected packages pull in the workspace packages they need to build and install
+
+        let only_check = if args.selected_pkgs.is_empty() {
+            only_check
+        } else {
+            let roots = args
+                .selected_pkgs
+                .iter()
+                .map(|p| match pkgs.get_key_value(*p) {
+                    Some((name, _)) => Ok(name.as_str()),
+                    None => Err(anyhow!("Package {p} is not in the workspace")),
+                })
+                .collect::<Result<HashSet<_>>>()?;
+            let closure = forward_closure(pkgs, roots);
+
+            reporter.info(format!(
+                "Building {} packages for {}",
+                closure.len(),
+                args.selected_pkgs.join(", ")
+            ));
+
+            let mut selected = only_check.unwrap_or_default();
+            selected.extend(
+                closure
+                    .into_iter()
+                    .filter(|p| !selected.contains(p))
+                    .collect::<Vec<_>>(),
+            );
+            Some(selected)
+        };
+
+        // Restrict the build to changed packages and everything built on top of them
+
+        let changed = match (changed_files, args.changed_since) {
+            (Some(files), _) => Some((
+                owning_packages(files.iter().cloned(), pkgs),
+                "since the last build".to_string(),
+            )),
+            (None, Some(git_ref)) => Some((
+                args.srcs
+                    .iter()
+                    .map(|src| changed_packages(src, git_ref, pkgs, self.runner))
+                    .collect::<Result<Vec<_>>>()?
+                    .into_iter()
+                    .flatten()
+                    .collect::<HashSet<_>>(),
+                format!("since {git_ref}"),
+            )),
+            (None, None) => None,
+        };
+
+        let only_check = match changed {
+            Some((changed, since)) => {
+                let rebuild = reverse_closure(pkgs, changed.clone());
+
+                reporter.info(format!(
+                    "{} packages changed {since}, rebuilding {}",
+                    changed.len(),
+                    rebuild.len()
+                ));
+
+                Some(match only_check {
+                    Some(only_check) => only_check
+                        .into_iter()
+                        .filter(|p| rebuild.contains(p))
+                        .collect(),
+                    None => rebuild.into_iter().collect(),
+                })
+            }
+            None => only_check,
+        };
+
+        // Leave out the packages already released upstream, as long as nothing they build on
+        // changes
+
+        let mut released = HashSet::new();
+
+        let only_check = if args.skip_if_upstream_matches {
+            let matching = upstream_matches(
+                pkgs,
+                &args.srcs,
+                args.ros_distro,
+                &args.name_map,
+                self.runner,
+            )?;
+            let rebuild = reverse_closure(
+                pkgs,
+                pkgs.keys()
+                    .map(String::as_str)
+                    .filter(|p| !matching.contains(p))
+                    .collect(),
+            );
+
+            reporter.info(format!(
+                "{} packages match their upstream release, skipping {}",
+                matching.len(),
+                pkgs.len() - rebuild.len()
+            ));
+
+            released = matching
+                .into_iter()
+                .filter(|p| !rebuild.contains(p))
+                .collect();
+
+            Some(match only_check {
+                Some(only_check) => only_check
+                    .into_iter()
+                    .filter(|p| rebuild.contains(p))
+                    .collect(),
+                None => rebuild.into_iter().collect(),
+            })
+        } else {
+            only_check
+        };
+
+        // Names outside the workspace select nothing
+        let only_check = only_check.map(|only_check| {
+            only_check
+                .into_iter()
+                .filter_map(|p| pkgs.get_key_value(p))
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+        });
+
+        // Everything derived from the workspace covers the selected packages and what they need,
+        // except for the released packages, which rosdep resolves to the upstream ones
+        let scope = only_check.as_ref().map(|only_check| {
+            let mut scope = forward_closure(pkgs, only_check.iter().copied().collect());
+            scope.retain(|p| !released.contains(p));
+            scope
+        });
+
+        Ok(Selection { only_check, scope })
+    }
+
+    /// Write the rosdep definitions of `scoped_pkgs` and the sources of the repos.
+    fn register_sources(&self, scoped_pkgs: &[Vec<OrderedPkg>]) -> Result<()> {
+        let args = self.args;
+        let reporter = &self.reporter;
+        let (backend, dry_run) = (args.backend, args.dry_run);
+        let package_root = self.package_root;
+
+        if dry_run {
+            reporter.info(format!("Would create {}", package_root.display()));
+            if self.deb_root != package_root {
+                reporter.info(format!("Would create {}", self.deb_root.display()));
+            }
+        } else {
+            fs::create_dir_all(package_root)?;
+            fs::create_dir_all(self.deb_root)?;
+        }
+
+        // Generate a rosdep yaml file
+
+        let rosdistro = rosdep_yaml(
+            scoped_pkgs,
+            &args.rosdep_defs,
+            args.os_name,
+            args.os_version,
+            args.rosdep_layout,
+        )?;
+
+        if dry_run {
+            reporter.info(format!(
+                "Would write {}",
+                package_root.join("package.yaml").display()
+            ));
+            info!("package.yaml:\n{rosdistro}");
+        } else {
+            let mut rosdep = File::create(package_root.join("package.yaml"))?;
+            rosdep.write_all(rosdistro.as_bytes())?;
+        }
+
+        // rosdep only reads its sources from ROSDEP_SOURCE_PATH if set
+        let sources_dir = Path::new(args.sources_dir);
+        let rosdep_dir = std::env::var_os("ROSDEP_SOURCE_PATH")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| sources_dir.join("ros/rosdep/sources.list.d"));
+
+        let (pin_priority, snapshot_priority) =
+            pin_priorities(args.pin_priority, args.apt_snapshot.is_some());
+
+        // Resolve upstream dependencies from a fixed state of the upstream repo
+        if let Some(snapshot) = args.apt_snapshot {
+            let url = snapshot_url(snapshot, args.ros_distro, args.os_name);
+            let files = backend
+                .snapshot_sources(sources_dir, &url, args.os_version, snapshot_priority)
+                .ok_or_else(|| {
+                    anyhow!("--apt-snapshot is not supported by {}", backend.installer())
+                })?;
+
+            reporter.info(format!("Using upstream snapshot {url}"));
+
+            for (path, contents) in files {
+                if dry_run {
+                    reporter.info(format!("Would write {}", path.display()));
+                    info!("{}:\n{contents}", path.display());
+                } else {
+                    if let Some(dir) = path.parent() {
+                        fs::create_dir_all(dir)?;
+                    }
+                    fs::write(path, contents)?;
+                }
+            }
+        }
+
+        for (i, path) in std::iter::once(args.repo_path)
+            .chain(args.extra_repos.iter().copied())
+            .enumerate()
+        {
+            let package_root = Path::new(path);
+
+            let repo_path_name = package_root
+                .file_name()
+                .and_then(|p| p.to_str())
+                .unwrap_or("unknown");
+
+            let source_name = format!(
+                "{:02}-{}-{i}-{repo_path_name}",
+                args.sources_priority, args.sources_prefix
+            );
+            let rosdep_list = rosdep_dir.join(format!("{source_name}.list"));
+
+            if dry_run {
+                // The repo may not exist yet, thus fall back to the path as given
+                let root = package_root
+                    .canonicalize()
+                    .unwrap_or_else(|_| package_root.into());
+                reporter.info(format!(
+                    "Would write {}: yaml file://{}/package.yaml",
+                    rosdep_list.display(),
+                    root.display()
+                ));
+                let (list, contents) =
+                    backend.source_list(sources_dir, &source_name, &root, self.layout);
+                reporter.info(format!(
+                    "Would write {}: {}",
+                    list.display(),
+                    contents.trim()
+                ));
+                continue;
+            }
+
+            // Generate rosdep list file
+            fs::create_dir_all(&rosdep_dir)?;
+            let mut rosdep = File::create(rosdep_list)?;
+            writeln!(
+                rosdep,
+                "yaml file://{}/package.yaml",
+                package_root.canonicalize()?.display()
+            )?;
+
+            // Generate a package manager source file
+            let (list, contents) = backend.source_list(
+                sources_dir,
+                &source_name,
+                &package_root.canonicalize()?,
+                self.layout,
+            );
+            if let Some(dir) = list.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(list, contents)?;
+        }
+
+        // Prefer the built packages over the same ones of upstream repos
+
+        let with_release = args.gen_release || !args.flat_repo;
+
+        if let Some((path, contents)) =
+            pin_priority.and_then(|p| backend.preferences(sources_dir, p, with_release))
+        {
+            if dry_run {
+                reporter.info(format!("Would write {}", path.display()));
+                info!("{}:\n{contents}", path.display());
+            } else {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::write(path, contents)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Update rosdep, the sources written before are only picked up from an updated cache.
+    fn update_rosdep(&self) -> Result<()> {
+        let args = self.args;
+
+        if args.no_rosdep_update {
+            self.reporter.info("Skipping rosdep update");
+        } else if args.dry_run {
+            self.reporter.info("Would run rosdep update");
+        } else {
+            self.reporter.step("Run rosdep update");
+
+            run_logged(
+                self.runner,
+                privileged("rosdep", &[], &args.command_prefix).arg("update"),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Only reindex what an earlier run already built.
+    fn reindex(&self) -> Result<()> {
+        let args = self.args;
+        let backend = args.backend;
+
+        if args.dry_run {
+            self.reporter.info(format!(
+                "Would generate the package index of {}",
+                self.package_root.display()
+            ));
+            return Ok(());
+        }
+
+        let existing = fs::read_dir(self.deb_root)?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension() == Some(OsStr::new(backend.extension())))
+            .count();
+
+        self.reporter.step(format!(
+            "Generating package index of {existing} existing packages"
+        ));
+
+        self.index()
+    }
+
+    /// Generate the package index, along with the Release file apt requires unless the repo is
+    /// flat.
+    fn index(&self) -> Result<()> {
+        let args = self.args;
+
+        args.backend.index(
+            self.package_root,
+            self.deb_root,
+            self.layout,
+            args.compress_manifest,
+            self.runner,
+        )?;
+
+        if args.gen_release || !args.flat_repo {
+            self.reporter.step("Generating Release");
+            args.backend
+                .release(self.package_root, args.os_version, self.layout, self.runner)?;
+        }
+
+        Ok(())
+    }
+
+    /// Install the dependencies of the selected packages if enabled.
+    fn install_dependencies(&self, pkgs: &PackageMap, selection: &Selection) -> Result<()> {
+        let args = self.args;
+        let reporter = &self.reporter;
+        let (backend, command_prefix, runner) = (args.backend, &args.command_prefix, self.runner);
+
+        let dep_paths = selection.dep_paths(pkgs, &args.srcs);
+
+        // Generating the packaging metadata only resolves dependencies, without installing them
+        if args.noinstall_deps || args.only_generate.is_some() || dep_paths.is_empty() {
+            return Ok(());
+        }
+
+        if args.dry_run {
+            reporter.info(format!(
+                "Would install dependencies of {} through {} and rosdep",
+                match selection.scope {
+                    Some(_) => format!("{} selected packages", dep_paths.len()),
+                    None => args.srcs.join(", "),
+                },
+                backend.installer()
+            ));
+            return Ok(());
+        }
+
+        reporter.step("Installing dependencies");
+
+        info!("Run rosdep check");
+
+        // First install all system dependencies in an optimized way
+        let check = run_logged(
+            runner,
+            privileged("rosdep", &[], command_prefix)
+                .args(["check", "--from-paths"])
+                .args(&dep_paths)
+                .arg("--ignore-src"),
+        )?;
+
+        // Unresolvable keys only show up as a failed build later on
+        if args.strict_rosdep {
+            let stderr = String::from_utf8_lossy(&check.stderr);
+            let problems = rosdep_problems(&stderr);
+            if !problems.is_empty() {
+                return Err(anyhow!(
+                    "rosdep check reported {} problem(s):\n  {}",
+                    problems.len(),
+                    problems.join("\n  ")
+                ));
+            }
+        }
+
+        let check = String::from_utf8_lossy(&check.stdout);
+
+        run_with_retries(args.retries, || {
+            info!("Run {} update", backend.installer());
+            backend.update(command_prefix, runner)?;
+
+            info!("Run {} install", backend.installer());
+            backend.install_system(&check, !args.no_install_recommends, command_prefix, runner)
+        })
+        .map_err(|e| {
+            categorize(
+                e,
+                BloomError::Install,
+                "Failed to install the system dependencies".to_string(),
+            )
+        })?;
+
+        // Then install all other dependencies
+        info!("Run rosdep install");
+
+        let o = run_logged(
+            runner,
+            privileged("rosdep", &[NONINTERACTIVE], command_prefix)
+                .args(["install", "--from-paths"])
+                .args(&dep_paths)
+                .args(["--ignore-src", "-y"]),
+        )?;
+
+        if o.status.code().unwrap_or_default() != 0 {
+            return Err(BloomError::Install(format!(
+                "Failed to do rosdep install '{}' | '{}'",
+                String::from_utf8_lossy(&o.stdout),
+                String::from_utf8_lossy(&o.stderr)
+            ))
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Settings of the package builds, creating the log directory.
+    fn bloom_config(&self) -> Result<BloomConfig<'a>> {
+        let args = self.args;
+
+        if let Some(log_dir) = args.log_dir.filter(|_| !args.dry_run) {
+            fs::create_dir_all(log_dir)?;
+        }
+
+        Ok(BloomConfig {
+            package_dir: self.deb_root,
+            os_name: args.os_name,
+            os_version: args.os_version,
+            ros_distro: args.ros_distro,
+            cache_dir: args.cache_dir.map(Path::new),
+            build_timeout: args.build_timeout,
+            log_dir: args.log_dir.map(Path::new),
+            backend: args.backend,
+            build_jobs: args.build_jobs,
+            keep_build_dirs: args.keep_build_dirs.map(Path::new),
+            generator: args.generator.unwrap_or_else(|| args.backend.generator()),
+            bloom_args: &args.bloom_args,
+            testing: args.testing,
+            skip_test_depends: args.skip_test_depends,
+            provenance: args.add_provenance,
+            build_tmp: args.build_tmp.map(Path::new),
+            debug_packages: !args.no_debug_packages,
+            name_map: &args.name_map,
+            maintainer: args.deb_maintainer,
+            deb_arch: args.deb_arch,
+            artifacts: args.artifacts,
+            env: &args.build_env,
+            runner: self.runner,
+        })
+    }
+
+    /// The environment of each package build, which overrides the one of all builds.
+    fn package_envs<'p>(&self, pkgs: &'p PackageMap) -> HashMap<&'p str, HashMap<String, String>> {
+        let args = self.args;
+
+        for p in args.package_env.keys().filter(|p| !pkgs.contains_key(**p)) {
+            self.reporter.warn(format!(
+                "Ignoring package-env of {p}, which is not in the workspace"
+            ));
+        }
+
+        pkgs.keys()
+            .map(|p| {
+                let mut env = args.build_env.clone();
+                env.extend(
+                    args.package_env
+                        .get(p.as_str())
+                        .into_iter()
+                        .flatten()
+                        .map(|(k, v)| (k.clone(), v.clone())),
+                );
+                (p.as_str(), env)
+            })
+            .collect()
+    }
+
+    /// Generate the packaging metadata of the selected packages into `out`, without building
+    /// them.
+    fn export(
+        &self,
+        out: &Path,
+        ordered_pkgs: &[Vec<OrderedPkg>],
+        selection: &Selection,
+        config: &BloomConfig,
+    ) -> Result<()> {
+        let args = self.args;
+        let reporter = &self.reporter;
+
+        let metadata = args.backend.metadata_dir();
+        let pkgs = ordered_pkgs
+            .iter()
+            .flatten()
+            .filter(|(p, ..)| selection.contains(p))
+            .collect::<Vec<_>>();
+
+        if args.dry_run {
+            for (p, ..) in &pkgs {
+                reporter.info(format!(
+                    "Would generate {}",
+                    out.join(p).join(metadata).display()
+                ));
+            }
+            return Ok(());
+        }
+
+        reporter.step(format!(
+            "Generating packaging metadata of {} packages into {}",
+            pkgs.len(),
+            out.display()
+        ));
+
+        let failures = self.pool.install(|| {
+            pkgs.par_iter()
+                .filter_map(|(p, _, d, package)| {
+                    let res = run_with_retries(args.retries, || {
+                        export_metadata(p, d, package, &out.join(p), config)
+                    });
+                    res.err().map(|e| format!("\n  {p}: {e:#}"))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        if !failures.is_empty() {
+            return Err(anyhow!(
+                "Failed to generate {} package(s):{}",
+                failures.len(),
+                failures.concat()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The packages completed by an interrupted run, if resuming.
+    fn resume(
+        &self,
+        path: &Path,
+        cache_keys: &HashMap<String, String>,
+    ) -> Result<HashMap<String, Vec<PathBuf>>> {
+        if !self.args.resume || self.args.dry_run {
+            return Ok(HashMap::new());
+        }
+
+        let resumed = read_checkpoint(path, cache_keys, self.deb_root)?;
+        self.reporter.step(format!(
+            "Resuming with {} packages from {}",
+            resumed.len(),
+            path.display()
+        ));
+        Ok(resumed)
+    }
+
+    /// Check the space left for building `count` packages, running out of it fails every build
+    /// after it.
+    fn check_space(&self, count: u64) -> Result<()> {
+        let args = self.args;
+
+        let tmp = args
+            .build_tmp
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+
+        for dir in distinct_file_systems(&[&tmp, self.package_root, self.deb_root])? {
+            let available = available_space(dir)? / MIB;
+
+            if let Some(min) = args.min_free_space.filter(|min| available < *min) {
+                return Err(anyhow!(
+                    "Only {available} MiB free in {}, at least {min} MiB required",
+                    dir.display()
+                ));
+            }
+
+            let required = count * args.space_per_package;
+            if available < required {
+                self.reporter.warn(format!(
+                    "Only {available} MiB free in {}, building {count} packages may need about \
+                    {required} MiB",
+                    dir.display()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Generate the packaging metadata of everything needing a build in parallel.
+    fn generate<'p>(
+        &self,
+        ordered_pkgs: &'p [Vec<OrderedPkg>],
+        selection: &Selection,
+        resumed: &HashMap<String, Vec<PathBuf>>,
+        cache_keys: &HashMap<String, String>,
+        config: &BloomConfig,
+    ) -> HashMap<&'p str, Generated> {
+        self.reporter.step("Generating packaging metadata");
+
+        self.pool.install(|| {
+            ordered_pkgs
+                .par_iter()
+                .flatten()
+                .filter(|(p, ..)| {
+                    selection.contains(p)
+                        && !resumed.contains_key(p)
+                        && cached_build(cache_keys.get(p).map(String::as_str), config).is_none()
+                })
+                .map(|(p, _, d, _)| {
+                    let mut log = String::new();
+                    let mut timings = Timings::default();
+                    let res = run_with_retries(self.args.retries, || {
+                        generate_debian(p, d, config, &mut timings, &mut log)
+                    });
+                    (p.as_str(), (log, timings, res))
+                })
+                .collect()
+        })
+    }
+
+    /// Build the selected packages layer by layer, installing them as configured.
+    fn build(
+        &mut self,
+        pkgs: &PackageMap,
+        order: BuildOrder,
+        selection: &Selection,
+        config: &BloomConfig,
+        package_envs: &HashMap<&str, HashMap<String, String>>,
+    ) -> Result<RunReport> {
+        let args = self.args;
+        let (backend, dry_run, retries) = (args.backend, args.dry_run, args.retries);
+        let ordered_pkgs = &order.layers;
+
+        // Keys also validate checkpoint entries, so they are needed without a cache
+        let cache_keys = if dry_run {
+            HashMap::new()
+        } else {
+            self.reporter.step("Hashing package sources");
+            self.pool
+                .install(|| cache_keys(ordered_pkgs, package_envs, config))?
+        };
+
+        // Reuse packages completed by an interrupted run, then record completions of this one
+        let checkpoint_path = args
+            .checkpoint
+            .map(PathBuf::from)
+            .unwrap_or_else(|| self.package_root.join(CHECKPOINT_FILE));
+
+        let resumed = self.resume(&checkpoint_path, &cache_keys)?;
+
+        let checkpoint = if dry_run {
+            None
+        } else {
+            let mut file = File::create(&checkpoint_path)?;
+            for (p, files) in resumed.iter().collect::<BTreeMap<_, _>>() {
+                file.write_all(checkpoint_entry(p, &cache_keys[p], files).as_bytes())?;
+            }
+            Some(Mutex::new(file))
+        };
+
+        // Build packages one by one
+        let pkg_count = ordered_pkgs.iter().flatten().count();
+        self.reporter
+            .step(format!("Building packages ({pkg_count})"));
+
+        // Redrawing a bar floods logs, which get a line per finished package instead
+        let plain = match args.progress {
+            Progress::Auto => !console::user_attended_stderr(),
+            Progress::Bar => false,
+            Progress::Plain => true,
+        };
+        let finished = AtomicUsize::new(0);
+
+        let pb = if dry_run || args.quiet || plain {
+            indicatif::ProgressBar::hidden()
+        } else {
+            indicatif::ProgressBar::new(pkg_count as u64).with_style(
+                indicatif::ProgressStyle::default_bar().template(
+                    "{spinner} [{elapsed_precise}] {bar:40} {pos}/{len} {prefix}: {wide_msg}",
+                ),
+            )
+        };
+        pb.enable_steady_tick(100);
+        self.reporter.attach(&pb);
+
+        let mut report = RunReport {
+            total: pkgs.len(),
+            packages: pkgs
+                .iter()
+                .map(|(p, (_, package))| {
+                    let entry = PackageReport {
+                        maintainers: package.maintainers.clone(),
+                        licenses: package.licenses.clone(),
+                        ..Default::default()
+                    };
+                    (p.clone(), entry)
+                })
+                .collect(),
+            layers: ordered_pkgs
+                .iter()
+                .map(|l| l.iter().map(|(p, ..)| p.clone()).collect())
+                .collect(),
+            cycles: order.cycles,
+            budget_exceeded: false,
+            interrupted: false,
+            timings: Timings::default(),
+        };
+
+        if !dry_run {
+            let count = ordered_pkgs
+                .iter()
+                .flatten()
+                .filter(|(p, ..)| selection.contains(p) && !resumed.contains_key(p))
+                .count() as u64;
+            self.check_space(count)?;
+        }
+
+        let mut generated = if dry_run {
+            HashMap::new()
+        } else {
+            self.generate(ordered_pkgs, selection, &resumed, &cache_keys, config)
+        };
+
+        let mut built = vec![];
+
+        // Once the time budget runs out no new builds get started
+        let build_start = Instant::now();
+        let budget_exceeded = AtomicBool::new(false);
+        let within_budget = || match args.time_budget {
+            Some(budget) if build_start.elapsed() >= budget => {
+                budget_exceeded.store(true, Ordering::Relaxed);
+                false
+            }
+            _ => true,
+        };
+
+        let reporter = &self.reporter;
+
+        for (i, pkgs) in ordered_pkgs.iter().enumerate() {
+            if dry_run {
+                reporter.step(format!("Layer {i}"));
+                for (p, pkg, d, _) in pkgs {
+                    if selection.contains(p) {
+                        reporter.info(format!("  Would build {pkg} from {}", d.display()));
+                    }
+                }
+                continue;
+            }
+
+            reporter.step(format!("Layer {i}"));
+            pb.set_prefix(format!("Layer {i}"));
+
+            // Packages currently building, shown as the progress message
+            let in_flight = Mutex::new(BTreeSet::new());
+            let set_building = |p: &str, building: bool| {
+                let mut in_flight = in_flight.lock().unwrap();
+                if building {
+                    in_flight.insert(p.to_string());
+                } else {
+                    in_flight.remove(p);
+                }
+                pb.set_message(in_flight.iter().cloned().collect::<Vec<_>>().join(", "));
+            };
+
+            let layer = pkgs
+                .iter()
+                .map(|pkg| (pkg, generated.remove(pkg.0.as_str())))
+                .collect::<Vec<_>>();
+
+            let res = self.pool.install(|| {
+                let success = AtomicBool::new(true);
+
+                let results = layer
+                    .into_par_iter()
+                    .map(|((p, _, d, package), generated)| {
+                        // Unless keeping going, skip everything not started once a build failed
+                        if (args.keep_going || success.load(Ordering::Relaxed))
+                            && selection.contains(p)
+                            && within_budget()
+                            && !INTERRUPTED.load(Ordering::Relaxed)
+                        {
+                            if let Some(files) = resumed.get(p) {
+                                debug!("{p}: resuming with {} files", files.len());
+                                let built = Built {
+                                    debs: files.clone(),
+                                    cached: true,
+                                    timings: Timings::default(),
+                                };
+                                return (p, Some((Ok(built), Duration::ZERO)));
+                            }
+
+                            set_building(p, true);
+                            let start = Instant::now();
+                            // Retries have to generate the metadata again
+                            let mut generated = generated;
+                            let res = run_with_retries(retries, || {
+                                let key = cache_keys.get(p).map(String::as_str);
+                                let env = &package_envs[p.as_str()];
+                                bloom(p, d, package, key, generated.take(), env, config)
+                            });
+                            let duration = start.elapsed();
+                            set_building(p, false);
+                            match &res {
+                                Ok(b) => {
+                                    let key = cache_keys.get(p).map(String::as_str);
+                                    if let Some((file, key)) = checkpoint.as_ref().zip(key) {
+                                        let entry = checkpoint_entry(p, key, &b.debs);
+                                        if let Err(e) =
+                                            file.lock().unwrap().write_all(entry.as_bytes())
+                                        {
+                                            warn!("{p}: failed to write checkpoint: {e}");
+                                        }
+                                    }
+                                }
+                                Err(e) => {
+                                    error!("{p}: {e}");
+                                    success.store(false, Ordering::Relaxed);
+                                }
+                            }
+                            (p, Some((res, duration)))
+                        } else {
+                            (p, None)
+                        }
+                    })
+                    .inspect(|(p, res)| {
+                        pb.inc(1);
+                        let n = finished.fetch_add(1, Ordering::Relaxed) + 1;
+                        if plain {
+                            let status = match res {
+                                Some((Ok(b), _)) if b.cached => "cached",
+                                Some((Ok(_), d)) => &format!("built in {:.1}s", d.as_secs_f64()),
+                                Some((Err(_), _)) => "failed",
+                                None => "skipped",
+                            };
+                            reporter.info(format!("[{n}/{pkg_count}] {p}: {status}"));
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                let mut debs = vec![];
+                let mut failures: Vec<(String, anyhow::Error)> = vec![];
+
+                for (p, res) in results {
+                    if let Some((res, duration)) = res {
+                        let entry = report.packages.get_mut(p).unwrap();
+                        entry.duration_secs = Some(duration.as_secs_f64());
+                        entry.status = match res {
+                            Ok(b) => {
+                                entry.files = b.debs.clone();
+                                debs.extend(b.debs);
+                                if b.cached {
+                                    Status::Cached
+                                } else {
+                                    entry.timings = Some(b.timings);
+                                    report.timings.add(&b.timings);
+                                    Status::Built
+                                }
+                            }
+                            Err(e) => {
+                                failures.push((p.clone(), e));
+                                Status::Failed
+                            }
+                        };
+                    }
+                }
+
+                if failures.is_empty() {
+                    if args.install_mode == InstallMode::PerLayer {
+                        install_batched(
+                            backend,
+                            &debs,
+                            args.install_batch,
+                            args.layer_install,
+                            &args.command_prefix,
+                            self.runner,
+                        )?;
+                    }
+
+                    built.extend(debs);
+
+                    Ok(())
+                } else if let [_] = &failures[..] {
+                    // Keep the error itself, with its causes and type, for a single failure
+                    let (p, e) = failures.pop().unwrap();
+                    Err(categorize(
+                        e,
+                        BloomError::Build,
+                        format!("Failed to build {p} in layer {i}"),
+                    ))
+                } else {
+                    let mut msg = format!(
+                        "Failed to build {} package(s) in layer {i}:",
+                        failures.len()
+                    );
+                    for (p, e) in &failures {
+                        write!(msg, "\n  {p}: {e:#}")?;
+                    }
+                    Err(BloomError::Build(msg).into())
+                }
+            });
+
+            if res.is_err() {
+                if let Some(path) = args.report {
+                    reporter.step(format!("Writing report to {path}"));
+                    write_report(path, &report)?;
+                }
+            }
+
+            res?;
+
+            if budget_exceeded.load(Ordering::Relaxed) {
+                reporter.warn(format!(
+                    "Time budget of {}s exceeded, not building further packages",
+                    args.time_budget.unwrap_or_default().as_secs()
+                ));
+                report.budget_exceeded = true;
+                break;
+            }
+
+            if INTERRUPTED.load(Ordering::Relaxed) {
+                reporter.warn("Interrupted, not building further packages");
+                report.interrupted = true;
+                break;
+            }
+        }
+
+        pb.finish();
+        self.reporter.detach();
+
+        let timings = report
+            .packages
+            .values()
+            .filter_map(|e| e.timings.as_ref())
+            .collect::<Vec<_>>();
+        if !timings.is_empty() {
+            self.reporter.step("Build phase timings");
+            self.reporter.info(timing_table(&timings));
+        }
+
+        if args.install_mode == InstallMode::Once {
+            if dry_run {
+                self.reporter.info("Would install all built packages");
+            } else {
+                self.reporter
+                    .step(format!("Installing {} packages", built.len()));
+                install_batched(
+                    backend,
+                    &built,
+                    args.install_batch,
+                    args.layer_install,
+                    &args.command_prefix,
+                    self.runner,
+                )?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Index and verify the built packages, then write the report.
+    fn publish(
+        &self,
+        pkgs: &PackageMap,
+        selection: &Selection,
+        report: &mut RunReport,
+    ) -> Result<()> {
+        let args = self.args;
+        let reporter = &self.reporter;
+        let (backend, command_prefix, runner) = (args.backend, &args.command_prefix, self.runner);
+
+        if args.write_metadata {
+            let path = self.package_root.join("metadata.json");
+            if args.dry_run {
+                reporter.info(format!("Would write {}", path.display()));
+            } else {
+                reporter.step(format!("Writing {}", path.display()));
+                fs::write(path, package_metadata(pkgs)?)?;
+            }
+        }
+
+        if args.dry_run {
+            reporter.info(format!(
+                "Would generate the package index of {}",
+                self.package_root.display()
+            ));
+            return Ok(());
+        }
+
+        reporter.step("Generating package index");
+
+        self.index()?;
+
+        // Check whether the built packages install from the repo
+
+        let mut unverified = vec![];
+
+        if args.verify {
+            reporter.step("Verifying packages");
+
+            backend.update(command_prefix, runner)?;
+
+            for (p, entry) in report.packages.iter_mut() {
+                if entry.files.is_empty() {
+                    continue;
+                }
+
+                let res = entry
+                    .files
+                    .iter()
+                    .filter(|f| !backend.is_source_artifact(f))
+                    .try_for_each(|f| backend.verify(f, command_prefix, runner));
+
+                match &res {
+                    Ok(()) => reporter.ok(format!("  {p}: ok")),
+                    Err(e) => {
+                        reporter.error(format!("  {p}: FAILED"));
+                        error!("{p}: {e}");
+                        unverified.push(p.clone());
+                    }
+                }
+
+                entry.verified = Some(res.is_ok());
+            }
+        }
+
+        if let Some(path) = args.report {
+            reporter.step(format!("Writing report to {path}"));
+            write_report(path, report)?;
+        }
+
+        if !unverified.is_empty() {
+            return Err(anyhow!(
+                "Failed to verify {} packages: {}",
+                unverified.len(),
+                unverified.join(", ")
+            ));
+        }
+
+        // The run fails with the reason, the report listing what got left out may not be written
+        if report.budget_exceeded || report.interrupted {
+            let skipped = report
+                .packages
+                .iter()
+                .filter(|(p, entry)| selection.contains(p) && entry.status == Status::Skipped)
+                .map(|(p, _)| p.as_str())
+                .collect::<Vec<_>>();
+            reporter.warn(format!(
+                "{} packages were not attempted: {}",
+                skipped.len(),
+                skipped.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+}
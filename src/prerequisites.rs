@@ -0,0 +1,196 @@
+//! Checks of the tools and disk space a run needs.
+
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::config::RuntimeArgs;
+use crate::error::BloomError;
+use crate::runner::CommandRunner;
+
+/// Verify all external commands needed for the run are available.
+pub(crate) fn check_prerequisites(args: &RuntimeArgs, runner: &dyn CommandRunner) -> Result<()> {
+    let mut tools = vec![];
+
+    if args.changed_since.is_some()
+        || args.skip_if_upstream_matches
+        || (args.from_repos.is_some() && !args.dry_run)
+    {
+        tools.push("git");
+    }
+
+    if args.deb_dir.is_some() && args.backend.extension() != "deb" {
+        return Err(anyhow!("A separate --deb-dir is only supported for debs"));
+    }
+
+    if args.skip_if_upstream_matches {
+        if args.backend.extension() != "deb" {
+            return Err(anyhow!("Upstream versions can only be looked up for debs"));
+        }
+        tools.push("apt-cache");
+    }
+
+    if args.dry_run
+        || args.list.is_some()
+        || !args.rdeps.is_empty()
+        || args.check_graph
+        || args.audit_parse
+    {
+        // Nothing gets run
+    } else if args.manifest_only {
+        tools.push("rosdep");
+        tools.push(args.backend.index_tool());
+    } else if args.only_generate.is_some() {
+        tools.extend(["bloom-generate", "rosdep"]);
+    } else {
+        tools.extend(["bloom-generate", "rosdep"]);
+        tools.extend(args.backend.tools());
+        if args.deb_arch.is_some() && args.backend.extension() == "deb" {
+            tools.push("dpkg-architecture");
+        }
+        if args.artifacts.source() {
+            if args.backend.extension() != "deb" {
+                return Err(anyhow!("Source packages can only be built as debs"));
+            }
+            tools.extend(["tar", "dpkg-buildpackage", "dpkg-scansources"]);
+        }
+        tools.push(args.backend.index_tool());
+        if !args.noinstall_deps || args.verify {
+            tools.push(args.backend.installer());
+        }
+    }
+
+    // The wrapper has to exist, whatever it accepts as arguments
+    if let Some(wrapper) = args.command_prefix.first() {
+        let missing = missing_tools(&[wrapper], runner);
+        if !missing.is_empty() {
+            return Err(BloomError::MissingPrerequisites(format!(
+                "Missing command prefix: {wrapper}"
+            ))
+            .into());
+        }
+    }
+
+    let missing = missing_tools(&tools, runner);
+
+    if !missing.is_empty() {
+        return Err(BloomError::MissingPrerequisites(format!(
+            "Missing required tools: {}",
+            missing.join(", ")
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+pub(crate) const MIB: u64 = 1024 * 1024;
+
+/// Space available to unprivileged users on the file system of `path`, in bytes.
+pub(crate) fn available_space(path: &Path) -> Result<u64> {
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+
+    if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(anyhow!(
+            "Failed to query free space of {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    let stat = unsafe { stat.assume_init() };
+    Ok(stat.f_bavail * stat.f_frsize)
+}
+
+/// The paths of `dirs` on distinct file systems, keeping the first of each.
+pub(crate) fn distinct_file_systems<'a>(dirs: &[&'a Path]) -> Result<Vec<&'a Path>> {
+    let mut devices = HashSet::new();
+    let mut distinct = vec![];
+
+    for dir in dirs {
+        if devices.insert(fs::metadata(dir)?.dev()) {
+            distinct.push(*dir);
+        }
+    }
+
+    Ok(distinct)
+}
+
+/// Fail early unless build roots can be created in `dir`.
+pub(crate) fn check_build_tmp(dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        return Err(anyhow!("Build tmp {} is not a directory", dir.display()));
+    }
+
+    tempfile::tempfile_in(dir)
+        .map_err(|e| anyhow!("Build tmp {} is not writable: {e}", dir.display()))?;
+
+    Ok(())
+}
+
+fn missing_tools<'a>(tools: &[&'a str], runner: &dyn CommandRunner) -> Vec<&'a str> {
+    tools
+        .iter()
+        .copied()
+        .filter(|t| {
+            let o = runner.output(
+                Command::new(t)
+                    .arg("--version")
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null()),
+                None,
+            );
+            let kind = o
+                .err()
+                .and_then(|e| e.downcast_ref::<std::io::Error>().map(|e| e.kind()));
+            kind == Some(std::io::ErrorKind::NotFound)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::SystemRunner;
+    use tempfile::tempdir;
+
+    #[test]
+    fn build_tmp() {
+        let dir = tempdir().unwrap();
+        check_build_tmp(dir.path()).unwrap();
+
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert!(check_build_tmp(&file).is_err());
+        assert!(check_build_tmp(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn free_space() {
+        let dir = tempdir().unwrap();
+        assert!(available_space(dir.path()).unwrap() > 0);
+        assert!(available_space(&dir.path().join("missing")).is_err());
+
+        let nested = dir.path().join("nested");
+        fs::create_dir(&nested).unwrap();
+        assert_eq!(
+            distinct_file_systems(&[dir.path(), &nested]).unwrap(),
+            [dir.path()]
+        );
+    }
+
+    #[test]
+    fn prerequisites() {
+        assert!(missing_tools(&["sh"], &SystemRunner).is_empty());
+        assert_eq!(
+            missing_tools(&["sh", "catkin-bloom-missing-tool"], &SystemRunner),
+            ["catkin-bloom-missing-tool"]
+        );
+    }
+}
@@ -0,0 +1,256 @@
+//! Status messages and the report of a run.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write as FmtWrite};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// Severity of a status message, deciding its color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Step,
+    Info,
+    Ok,
+    Warn,
+    Error,
+}
+
+/// User facing status messages, kept separate from the log. While a progress bar is attached,
+/// messages are printed above it instead of drawing over it.
+#[derive(Debug)]
+pub(crate) struct Reporter {
+    color: bool,
+    stderr: bool,
+    quiet: bool,
+    pb: Option<indicatif::ProgressBar>,
+}
+
+impl Reporter {
+    pub(crate) fn new(color: bool) -> Self {
+        Self {
+            color,
+            stderr: false,
+            quiet: false,
+            pb: None,
+        }
+    }
+
+    /// Print every message to stderr, keeping stdout for machine readable output.
+    pub(crate) fn with_stderr(self, stderr: bool) -> Self {
+        Self { stderr, ..self }
+    }
+
+    /// Drop everything but warnings and errors.
+    pub(crate) fn with_quiet(self, quiet: bool) -> Self {
+        Self { quiet, ..self }
+    }
+
+    pub(crate) fn attach(&mut self, pb: &indicatif::ProgressBar) {
+        if !pb.is_hidden() {
+            self.pb = Some(pb.clone());
+        }
+    }
+
+    pub(crate) fn detach(&mut self) {
+        self.pb = None;
+    }
+
+    fn paint(&self, level: Level, msg: impl Display) -> String {
+        if !self.color {
+            return msg.to_string();
+        }
+
+        let style = console::Style::new().force_styling(true);
+        let style = match level {
+            Level::Step => style.bold(),
+            Level::Info => style,
+            Level::Ok => style.green(),
+            Level::Warn => style.yellow(),
+            Level::Error => style.red().bold(),
+        };
+
+        style.apply_to(msg).to_string()
+    }
+
+    fn print(&self, level: Level, msg: impl Display) {
+        if self.quiet && !matches!(level, Level::Warn | Level::Error) {
+            return;
+        }
+
+        let line = self.paint(level, msg);
+
+        match &self.pb {
+            Some(pb) => pb.println(line),
+            None if level == Level::Error || self.stderr => eprintln!("{line}"),
+            None => println!("{line}"),
+        }
+    }
+
+    pub(crate) fn step(&self, msg: impl Display) {
+        self.print(Level::Step, msg)
+    }
+
+    pub(crate) fn info(&self, msg: impl Display) {
+        self.print(Level::Info, msg)
+    }
+
+    pub(crate) fn ok(&self, msg: impl Display) {
+        self.print(Level::Ok, msg)
+    }
+
+    pub(crate) fn warn(&self, msg: impl Display) {
+        self.print(Level::Warn, msg)
+    }
+
+    pub(crate) fn error(&self, msg: impl Display) {
+        self.print(Level::Error, msg)
+    }
+}
+
+/// Summary of a run written by `--report`, empty for runs not building anything.
+#[derive(Serialize, Default, Debug)]
+pub struct RunReport {
+    pub total: usize,
+    pub packages: BTreeMap<String, PackageReport>,
+    pub layers: Vec<Vec<String>>,
+    pub cycles: Vec<Vec<String>>,
+    pub budget_exceeded: bool,
+    pub interrupted: bool,
+    /// Totals over all packages built from scratch.
+    pub timings: Timings,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct PackageReport {
+    pub status: Status,
+    pub duration_secs: Option<f64>,
+    pub timings: Option<Timings>,
+    pub files: Vec<PathBuf>,
+    pub verified: Option<bool>,
+    pub maintainers: Vec<String>,
+    pub licenses: Vec<String>,
+}
+
+/// Time spent in each phase of building a package.
+#[derive(Serialize, Default, Debug, Clone, Copy, PartialEq)]
+pub struct Timings {
+    pub generate_secs: f64,
+    pub patch_secs: f64,
+    pub binary_secs: f64,
+}
+
+impl Timings {
+    fn phases(&self) -> [(&'static str, f64); 3] {
+        [
+            ("generate", self.generate_secs),
+            ("patch", self.patch_secs),
+            ("binary", self.binary_secs),
+        ]
+    }
+
+    pub(crate) fn add(&mut self, other: &Timings) {
+        self.generate_secs += other.generate_secs;
+        self.patch_secs += other.patch_secs;
+        self.binary_secs += other.binary_secs;
+    }
+}
+
+/// Run `f`, adding the time it took to `secs`.
+pub(crate) fn timed<T>(secs: &mut f64, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let res = f();
+    *secs += start.elapsed().as_secs_f64();
+    res
+}
+
+/// Table of the total, mean and maximum time of each phase over the packages.
+pub(crate) fn timing_table(timings: &[&Timings]) -> String {
+    let mut table = format!(
+        "  {:<10} {:>10} {:>10} {:>10}",
+        "phase", "total", "mean", "max"
+    );
+
+    for i in 0..3 {
+        let secs = timings.iter().map(|t| t.phases()[i].1).collect::<Vec<_>>();
+        let total = secs.iter().sum::<f64>();
+        let max = secs.iter().copied().fold(0.0, f64::max);
+        let _ = write!(
+            table,
+            "\n  {:<10} {:>9.1}s {:>9.1}s {:>9.1}s",
+            Timings::default().phases()[i].0,
+            total,
+            total / secs.len().max(1) as f64,
+            max
+        );
+    }
+
+    table
+}
+
+#[derive(Serialize, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Built,
+    Cached,
+    #[default]
+    Skipped,
+    Failed,
+}
+
+pub(crate) fn write_report(path: &str, report: &RunReport) -> Result<()> {
+    fs::write(path, serde_json::to_string_pretty(report)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timings() {
+        let mut secs = 1.0;
+        assert_eq!(timed(&mut secs, || 42), 42);
+        assert!(secs >= 1.0);
+
+        let a = Timings {
+            generate_secs: 1.0,
+            patch_secs: 0.5,
+            binary_secs: 3.0,
+        };
+        let b = Timings {
+            generate_secs: 3.0,
+            patch_secs: 0.5,
+            binary_secs: 1.0,
+        };
+        let mut total = a;
+        total.add(&b);
+        assert_eq!(total.phases().map(|p| p.1), [4.0, 1.0, 4.0]);
+
+        let table = timing_table(&[&a, &b]);
+        let lines = table.lines().map(str::split_whitespace).map(Vec::from_iter);
+        assert_eq!(
+            lines.collect::<Vec<_>>(),
+            [
+                vec!["phase", "total", "mean", "max"],
+                vec!["generate", "4.0s", "2.0s", "3.0s"],
+                vec!["patch", "1.0s", "0.5s", "0.5s"],
+                vec!["binary", "4.0s", "2.0s", "3.0s"],
+            ]
+        );
+    }
+
+    #[test]
+    fn reporter_colors() {
+        let plain = Reporter::new(false);
+        assert_eq!(plain.paint(Level::Error, "  foo: FAILED"), "  foo: FAILED");
+
+        let color = Reporter::new(true);
+        let painted = color.paint(Level::Error, "  foo: FAILED");
+        assert!(painted.starts_with('\x1b'));
+        assert!(painted.contains("  foo: FAILED"));
+        assert_ne!(painted, color.paint(Level::Ok, "  foo: FAILED"));
+    }
+}
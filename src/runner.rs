@@ -0,0 +1,405 @@
+//! Running external commands.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use std::ffi::OsStr;
+use std::fmt::Write as FmtWrite;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::BloomError;
+
+/// Keeps apt and dpkg from prompting.
+pub(crate) const NONINTERACTIVE: (&str, &str) = ("DEBIAN_FRONTEND", "noninteractive");
+
+/// Command running `program` through the `--command-prefix` wrapper, if any. The environment
+/// is passed through `env`, as wrappers like sudo reset it.
+pub(crate) fn privileged(program: &str, envs: &[(&str, &str)], prefix: &[&str]) -> Command {
+    let Some((wrapper, args)) = prefix.split_first() else {
+        let mut cmd = Command::new(program);
+        cmd.envs(envs.iter().copied());
+        return cmd;
+    };
+
+    let mut cmd = Command::new(wrapper);
+    cmd.args(args);
+    if !envs.is_empty() {
+        cmd.arg("env")
+            .args(envs.iter().map(|(k, v)| format!("{k}={v}")));
+    }
+    cmd.arg(program);
+    cmd
+}
+
+/// Marker for failures caused by temporary conditions, such as unreachable mirrors.
+#[derive(Debug)]
+struct TransientError(String);
+
+impl std::fmt::Display for TransientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TransientError {}
+
+const TRANSIENT_SIGNATURES: &[&str] = &["Temporary failure", "Could not resolve"];
+
+fn is_transient(o: &Output) -> bool {
+    let stderr = String::from_utf8_lossy(&o.stderr);
+    TRANSIENT_SIGNATURES.iter().any(|s| stderr.contains(s))
+}
+
+/// Build the error for a failed command, marking it as retryable if the failure looks transient.
+pub(crate) fn command_error(o: &Output, msg: &str) -> anyhow::Error {
+    if is_transient(o) {
+        TransientError(msg.into()).into()
+    } else {
+        anyhow!("{msg}")
+    }
+}
+
+/// Run `f`, retrying up to `retries` times with exponential backoff while it fails with a
+/// [`TransientError`].
+pub(crate) fn run_with_retries<T>(retries: usize, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 0;
+
+    loop {
+        match f() {
+            Err(e) if attempt < retries && e.is::<TransientError>() => {
+                let delay = Duration::from_secs(1 << attempt.min(6));
+                warn!("{e}, retrying in {}s", delay.as_secs());
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Executes the external commands of a run.
+pub trait CommandRunner: Sync {
+    /// Run `cmd` to completion, killing its process group if it does not finish within
+    /// `timeout`.
+    fn output(&self, cmd: &mut Command, timeout: Option<Duration>) -> Result<Output>;
+}
+
+/// Runs commands as child processes.
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn output(&self, cmd: &mut Command, timeout: Option<Duration>) -> Result<Output> {
+        match timeout {
+            Some(timeout) => kill_after(cmd, timeout),
+            None => Ok(cmd.output()?),
+        }
+    }
+}
+
+/// A command run through a [`MockRunner`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    /// The program followed by its arguments.
+    pub args: Vec<String>,
+    pub dir: Option<PathBuf>,
+    /// Environment variables set for the command, in the order they were set.
+    pub envs: Vec<(String, String)>,
+}
+
+impl Invocation {
+    pub fn program(&self) -> &str {
+        &self.args[0]
+    }
+
+    /// The command line with arguments separated by spaces.
+    pub fn line(&self) -> String {
+        self.args.join(" ")
+    }
+}
+
+type MockHandler = Box<dyn Fn(&Invocation) -> Output + Send + Sync>;
+
+/// Records the commands instead of running them, answering with the output of a handler.
+pub struct MockRunner {
+    calls: Mutex<Vec<Invocation>>,
+    handler: MockHandler,
+}
+
+impl Default for MockRunner {
+    fn default() -> Self {
+        Self::new(|_| mock_output(0, ""))
+    }
+}
+
+impl MockRunner {
+    pub fn new(handler: impl Fn(&Invocation) -> Output + Send + Sync + 'static) -> Self {
+        Self {
+            calls: Mutex::new(vec![]),
+            handler: Box::new(handler),
+        }
+    }
+
+    /// Commands run so far, in the order they were run.
+    pub fn calls(&self) -> Vec<Invocation> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl CommandRunner for MockRunner {
+    fn output(&self, cmd: &mut Command, _timeout: Option<Duration>) -> Result<Output> {
+        let invocation = Invocation {
+            args: std::iter::once(cmd.get_program())
+                .chain(cmd.get_args())
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect(),
+            dir: cmd.get_current_dir().map(PathBuf::from),
+            envs: cmd
+                .get_envs()
+                .filter_map(|(k, v)| Some((k.to_str()?.to_string(), v?.to_str()?.to_string())))
+                .collect(),
+        };
+        let o = (self.handler)(&invocation);
+        self.calls.lock().unwrap().push(invocation);
+        Ok(o)
+    }
+}
+
+/// Output of a command exiting with `code` after printing `stdout`.
+pub fn mock_output(code: i32, stdout: &str) -> Output {
+    Output {
+        status: std::os::unix::process::ExitStatusExt::from_raw(code << 8),
+        stdout: stdout.as_bytes().to_vec(),
+        stderr: vec![],
+    }
+}
+
+/// Run a command to completion, logging what gets run and how it exits.
+pub(crate) fn run_logged(runner: &dyn CommandRunner, cmd: &mut Command) -> Result<Output> {
+    debug!("Running {}", describe_command(cmd));
+    let o = runner.output(cmd, None)?;
+    debug!(
+        "{} exited with {}",
+        cmd.get_program().to_string_lossy(),
+        o.status
+    );
+    Ok(o)
+}
+
+/// Command line of `cmd` along with its environment overrides and working directory.
+fn describe_command(cmd: &Command) -> String {
+    let quote = |s: &OsStr| {
+        let s = s.to_string_lossy();
+        if s.is_empty() || s.contains(char::is_whitespace) {
+            format!("{s:?}")
+        } else {
+            s.into_owned()
+        }
+    };
+
+    let mut line = cmd
+        .get_envs()
+        .filter_map(|(k, v)| Some(format!("{}={}", k.to_string_lossy(), quote(v?))))
+        .chain(std::iter::once(quote(cmd.get_program())))
+        .chain(cmd.get_args().map(quote))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if let Some(dir) = cmd.get_current_dir() {
+        let _ = write!(line, " (in {})", dir.display());
+    }
+
+    line
+}
+
+/// Run a command to completion, killing its entire process group if it does not finish within
+/// the timeout.
+///
+/// The command gets a process group of its own, so that a SIGINT from the terminal leaves it
+/// running to completion.
+pub(crate) fn output_with_timeout(
+    runner: &dyn CommandRunner,
+    cmd: &mut Command,
+    timeout: Option<Duration>,
+) -> Result<Output> {
+    cmd.process_group(0);
+
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return run_logged(runner, cmd),
+    };
+
+    debug!(
+        "Running {} with a timeout of {}s",
+        describe_command(cmd),
+        timeout.as_secs()
+    );
+
+    let o = runner.output(cmd, Some(timeout))?;
+
+    debug!(
+        "{} exited with {}",
+        cmd.get_program().to_string_lossy(),
+        o.status
+    );
+
+    Ok(o)
+}
+
+fn kill_after(cmd: &mut Command, timeout: Duration) -> Result<Output> {
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+
+    // Drain the pipes in the background so that the child never blocks on a full pipe
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let stdout = std::thread::spawn(move || {
+        let mut buf = vec![];
+        stdout.read_to_end(&mut buf).map(|_| buf)
+    });
+    let stderr = std::thread::spawn(move || {
+        let mut buf = vec![];
+        stderr.read_to_end(&mut buf).map(|_| buf)
+    });
+
+    let start = Instant::now();
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if start.elapsed() > timeout {
+            // SAFETY: kill has no memory safety requirements, the negative pid targets the
+            // process group created for the child.
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            child.wait()?;
+            return Err(BloomError::Timeout(format!(
+                "{:?} timed out after {}s",
+                cmd.get_program(),
+                timeout.as_secs()
+            ))
+            .into());
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap()?,
+        stderr: stderr.join().unwrap()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Backend, Deb, Rpm};
+
+    #[test]
+    fn command_prefix() {
+        let cmd = privileged("apt-get", &[NONINTERACTIVE], &[]);
+        assert_eq!(cmd.get_program(), "apt-get");
+        assert_eq!(cmd.get_args().count(), 0);
+        assert_eq!(
+            cmd.get_envs().collect::<Vec<_>>(),
+            [(
+                OsStr::new("DEBIAN_FRONTEND"),
+                Some(OsStr::new("noninteractive"))
+            )]
+        );
+
+        let cmd = privileged("apt-get", &[NONINTERACTIVE], &["sudo", "-n"]);
+        assert_eq!(cmd.get_program(), "sudo");
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            ["-n", "env", "DEBIAN_FRONTEND=noninteractive", "apt-get"]
+        );
+        assert_eq!(cmd.get_envs().count(), 0);
+
+        let cmd = privileged("dpkg", &[], &["sudo"]);
+        assert_eq!(cmd.get_args().collect::<Vec<_>>(), ["dpkg"]);
+    }
+
+    #[test]
+    fn command_description() {
+        let mut cmd = privileged("apt-get", &[NONINTERACTIVE], &[]);
+        cmd.args(["install", "a b", ""]).current_dir("/tmp");
+        assert_eq!(
+            describe_command(&cmd),
+            "DEBIAN_FRONTEND=noninteractive apt-get install \"a b\" \"\" (in /tmp)"
+        );
+
+        let o = run_logged(
+            &SystemRunner,
+            Command::new("sh").args(["-c", "echo out; exit 3"]),
+        )
+        .unwrap();
+        assert_eq!(o.status.code(), Some(3));
+        assert_eq!(o.stdout, b"out\n");
+    }
+
+    #[test]
+    fn command_timeout() {
+        let o = output_with_timeout(
+            &SystemRunner,
+            Command::new("sh").args(["-c", "echo out; echo err >&2"]),
+            Some(Duration::from_secs(10)),
+        )
+        .unwrap();
+        assert!(o.status.success());
+        assert_eq!(o.stdout, b"out\n");
+        assert_eq!(o.stderr, b"err\n");
+
+        let start = Instant::now();
+        let res = output_with_timeout(
+            &SystemRunner,
+            Command::new("sh").args(["-c", "sleep 10 & sleep 10"]),
+            Some(Duration::from_millis(200)),
+        );
+        assert!(res.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn retries() {
+        let mut attempts = 0;
+        let res = run_with_retries(1, || {
+            attempts += 1;
+            Err::<(), _>(TransientError("network".into()).into())
+        });
+        assert!(res.is_err());
+        assert_eq!(attempts, 2);
+
+        let mut attempts = 0;
+        let res = run_with_retries(3, || {
+            attempts += 1;
+            Err::<(), _>(anyhow!("compile error"))
+        });
+        assert!(res.is_err());
+        assert_eq!(attempts, 1);
+
+        let o = Command::new("sh")
+            .args([
+                "-c",
+                "echo 'Temporary failure resolving mirror' >&2; exit 1",
+            ])
+            .output()
+            .unwrap();
+        assert!(command_error(&o, "failed").is::<TransientError>());
+
+        // Broken sources fail the update without being retried
+        let runner = MockRunner::new(|_| mock_output(100, ""));
+        let err = Deb.update(&[], &runner).unwrap_err();
+        assert!(!err.is::<TransientError>());
+        assert!(Rpm.update(&[], &runner).is_err());
+    }
+}
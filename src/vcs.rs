@@ -0,0 +1,180 @@
+//! Fetching the repositories listed in `.repos` and `.rosinstall` files.
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+
+use crate::report::Reporter;
+use crate::runner::{run_logged, CommandRunner};
+
+/// A repository listed in a `.repos` or `.rosinstall` file.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct RepoSpec {
+    path: String,
+    url: String,
+    version: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RepoList {
+    Vcs {
+        repositories: BTreeMap<String, VcsRepo>,
+    },
+    Rosinstall(Vec<BTreeMap<String, RosinstallRepo>>),
+}
+
+#[derive(Deserialize)]
+struct VcsRepo {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    version: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RosinstallRepo {
+    #[serde(rename = "local-name")]
+    local_name: String,
+    uri: String,
+    version: Option<String>,
+}
+
+/// Parse a vcstool `.repos` or a `.rosinstall` file. Only git repositories are supported.
+pub(crate) fn parse_repos(contents: &str) -> Result<Vec<RepoSpec>> {
+    let specs = match serde_yaml::from_str(contents)? {
+        RepoList::Vcs { repositories } => repositories
+            .into_iter()
+            .map(|(path, r)| (r.kind, path, r.url, r.version))
+            .collect::<Vec<_>>(),
+        RepoList::Rosinstall(entries) => entries
+            .into_iter()
+            .flatten()
+            .map(|(kind, r)| (kind, r.local_name, r.uri, r.version))
+            .collect(),
+    };
+
+    specs
+        .into_iter()
+        .map(|(kind, path, url, version)| {
+            if kind != "git" {
+                return Err(anyhow!("Unsupported repository type {kind} of {path}"));
+            }
+
+            // Repositories must end up inside the workspace
+            if !Path::new(&path)
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_)))
+            {
+                return Err(anyhow!("Invalid repository path {path}"));
+            }
+
+            Ok(RepoSpec { path, url, version })
+        })
+        .collect()
+}
+
+/// Clone every repository not yet present under `src`, checking out its version if given.
+pub(crate) fn fetch_repos(
+    src: &str,
+    repos: &[RepoSpec],
+    dry_run: bool,
+    reporter: &Reporter,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    for repo in repos {
+        let dir = Path::new(src).join(&repo.path);
+
+        if dir.exists() {
+            reporter.info(format!("  Using existing {}", dir.display()));
+            continue;
+        }
+
+        if dry_run {
+            reporter.info(format!("  Would clone {} into {}", repo.url, dir.display()));
+            continue;
+        }
+
+        reporter.info(format!("  Cloning {} into {}", repo.url, dir.display()));
+
+        let o = run_logged(
+            runner,
+            Command::new("git")
+                .args(["clone", "--quiet", &repo.url])
+                .arg(&dir),
+        )?;
+
+        if !o.status.success() {
+            return Err(anyhow!(
+                "Failed to clone {}: {}",
+                repo.url,
+                String::from_utf8_lossy(&o.stderr).trim()
+            ));
+        }
+
+        if let Some(version) = &repo.version {
+            let o = run_logged(
+                runner,
+                Command::new("git")
+                    .args(["checkout", "--quiet", version])
+                    .current_dir(&dir),
+            )?;
+
+            if !o.status.success() {
+                return Err(anyhow!(
+                    "Failed to check out {version} of {}: {}",
+                    repo.url,
+                    String::from_utf8_lossy(&o.stderr).trim()
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repos_files() {
+        let vcs = "repositories:\n  \
+            core/foo:\n    type: git\n    url: https://example.com/foo.git\n    version: main\n  \
+            bar:\n    type: git\n    url: https://example.com/bar.git\n";
+        assert_eq!(
+            parse_repos(vcs).unwrap(),
+            [
+                RepoSpec {
+                    path: "bar".into(),
+                    url: "https://example.com/bar.git".into(),
+                    version: None,
+                },
+                RepoSpec {
+                    path: "core/foo".into(),
+                    url: "https://example.com/foo.git".into(),
+                    version: Some("main".into()),
+                },
+            ]
+        );
+
+        let rosinstall = "- git:\n    local-name: foo\n    uri: https://example.com/foo.git\n    \
+            version: v1\n";
+        assert_eq!(
+            parse_repos(rosinstall).unwrap(),
+            [RepoSpec {
+                path: "foo".into(),
+                url: "https://example.com/foo.git".into(),
+                version: Some("v1".into()),
+            }]
+        );
+
+        let svn = "- svn:\n    local-name: foo\n    uri: https://example.com/foo\n";
+        assert!(parse_repos(svn).is_err());
+
+        let escaping = "- git:\n    local-name: ../foo\n    uri: https://example.com/foo.git\n";
+        assert!(parse_repos(escaping).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use tempfile::{tempdir, TempDir};
+
+fn dry_run(src: &Path, args: &[&str]) -> String {
+    let o = Command::new(env!("CARGO_BIN_EXE_catkin-bloom"))
+        .env_remove("ROSDEP_SOURCE_PATH")
+        .args(["--dry-run", "--repo-path"])
+        .arg(src.join("repo"))
+        .args(args)
+        .arg(src)
+        .output()
+        .unwrap();
+
+    assert!(o.status.success(), "{o:?}");
+
+    String::from_utf8(o.stdout).unwrap()
+}
+
+fn workspace() -> TempDir {
+    let ws = tempdir().unwrap();
+    fs::create_dir(ws.path().join("foo")).unwrap();
+    fs::write(
+        ws.path().join("foo/package.xml"),
+        "<package format=\"3\"><name>foo</name></package>",
+    )
+    .unwrap();
+    ws
+}
+
+#[test]
+fn noinstall_deps() {
+    let ws = workspace();
+
+    let out = dry_run(ws.path(), &[]);
+    assert!(out.contains("Would install dependencies"), "{out}");
+
+    for flag in ["-n", "--noinstall-deps"] {
+        let out = dry_run(ws.path(), &[flag]);
+        assert!(!out.contains("Would install dependencies"), "{out}");
+        assert!(out.contains("Would build ros-melodic-foo"), "{out}");
+    }
+}
+
+#[test]
+fn empty_workspace() {
+    let ws = tempdir().unwrap();
+
+    let o = Command::new(env!("CARGO_BIN_EXE_catkin-bloom"))
+        .args(["--dry-run", "--repo-path"])
+        .arg(ws.path().join("repo"))
+        .arg(ws.path())
+        .output()
+        .unwrap();
+    assert!(!o.status.success());
+    let stderr = String::from_utf8(o.stderr).unwrap();
+    assert!(
+        stderr.contains(&format!("No packages found in {}", ws.path().display())),
+        "{stderr}"
+    );
+
+    let out = dry_run(ws.path(), &["--allow-empty"]);
+    assert!(out.contains("No packages found"), "{out}");
+    assert!(!out.contains("Would write"), "{out}");
+}
+
+#[test]
+fn quiet() {
+    let ws = workspace();
+
+    for flag in ["-q", "--quiet"] {
+        let out = dry_run(ws.path(), &[flag]);
+        assert_eq!(out, "");
+    }
+}
+
+#[test]
+fn ignored_dependency() {
+    let ws = workspace();
+    fs::create_dir(ws.path().join("bar")).unwrap();
+    fs::write(
+        ws.path().join("bar/package.xml"),
+        "<package format=\"3\"><name>bar</name><depend>foo</depend></package>",
+    )
+    .unwrap();
+
+    let out = dry_run(ws.path(), &["--ignore-pkgs=foo"]);
+    assert!(out.contains("bar depends on ignored package foo"), "{out}");
+}
+
+#[test]
+fn sources_dir() {
+    let ws = workspace();
+
+    let out = dry_run(ws.path(), &[]);
+    assert!(
+        out.contains("Would write /etc/ros/rosdep/sources.list.d/99-catkin-bloom-0-repo.list"),
+        "{out}"
+    );
+    assert!(
+        out.contains("Would write /etc/apt/sources.list.d/99-catkin-bloom-0-repo.list"),
+        "{out}"
+    );
+
+    let out = dry_run(ws.path(), &["--sources-dir", "/opt/prefix/etc"]);
+    assert!(
+        out.contains(
+            "Would write /opt/prefix/etc/ros/rosdep/sources.list.d/99-catkin-bloom-0-repo.list"
+        ),
+        "{out}"
+    );
+    assert!(
+        out.contains("Would write /opt/prefix/etc/apt/sources.list.d/99-catkin-bloom-0-repo.list"),
+        "{out}"
+    );
+    let out = dry_run(
+        ws.path(),
+        &["--sources-prefix", "ws", "--sources-priority", "5"],
+    );
+    assert!(
+        out.contains("Would write /etc/ros/rosdep/sources.list.d/05-ws-0-repo.list"),
+        "{out}"
+    );
+    assert!(
+        out.contains("Would write /etc/apt/sources.list.d/05-ws-0-repo.list"),
+        "{out}"
+    );
+}
+
+#[test]
+fn list_json() {
+    let ws = workspace();
+
+    let o = Command::new(env!("CARGO_BIN_EXE_catkin-bloom"))
+        .args(["--list", "--format", "json"])
+        .arg(ws.path())
+        .output()
+        .unwrap();
+
+    assert!(o.status.success(), "{o:?}");
+
+    let json: serde_json::Value = serde_json::from_slice(&o.stdout).unwrap();
+    assert_eq!(json["foo"]["layer"], 0);
+    assert!(!ws.path().join("repo").exists());
+}
+
+#[test]
+fn only_check_file() {
+    let ws = workspace();
+    fs::create_dir(ws.path().join("bar")).unwrap();
+    fs::write(
+        ws.path().join("bar/package.xml"),
+        "<package format=\"3\"><name>bar</name></package>",
+    )
+    .unwrap();
+    let list = ws.path().join("selected.txt");
+    fs::write(&list, "# only bar\nbar\n").unwrap();
+    let list = list.to_str().unwrap();
+
+    let out = dry_run(ws.path(), &["--only-check-file", list]);
+    assert!(out.contains("Would build ros-melodic-bar"), "{out}");
+    assert!(!out.contains("Would build ros-melodic-foo"), "{out}");
+
+    let out = dry_run(
+        ws.path(),
+        &["--only-check", "foo", "--only-check-file", list],
+    );
+    assert!(out.contains("Would build ros-melodic-bar"), "{out}");
+    assert!(out.contains("Would build ros-melodic-foo"), "{out}");
+}
+
+#[test]
+fn only_check_scope() {
+    let ws = workspace();
+    for (name, deps) in [("bar", "<depend>foo</depend>"), ("baz", "")] {
+        fs::create_dir(ws.path().join(name)).unwrap();
+        fs::write(
+            ws.path().join(name).join("package.xml"),
+            format!("<package format=\"3\"><name>{name}</name>{deps}</package>"),
+        )
+        .unwrap();
+    }
+
+    let out = dry_run(ws.path(), &["--only-check", "bar", "--jobs", "1"]);
+    assert!(
+        out.contains("Would install dependencies of 2 selected packages"),
+        "{out}"
+    );
+    assert!(!out.contains("Would build ros-melodic-baz"), "{out}");
+}
+
+#[test]
+fn check_graph() {
+    let ws = workspace();
+    let check = |ws: &TempDir| {
+        Command::new(env!("CARGO_BIN_EXE_catkin-bloom"))
+            .arg("--check-graph")
+            .arg(ws.path())
+            .output()
+            .unwrap()
+    };
+
+    let o = check(&ws);
+    assert!(o.status.success(), "{o:?}");
+    assert!(String::from_utf8_lossy(&o.stdout).contains("No dependency cycles among 1 packages"));
+
+    for (name, dep) in [("foo", "bar"), ("bar", "foo")] {
+        fs::create_dir_all(ws.path().join(name)).unwrap();
+        fs::write(
+            ws.path().join(name).join("package.xml"),
+            format!("<package format=\"3\"><name>{name}</name><depend>{dep}</depend></package>"),
+        )
+        .unwrap();
+    }
+
+    let o = check(&ws);
+    assert!(!o.status.success(), "{o:?}");
+    assert_eq!(o.status.code(), Some(catkin_bloom::EXIT_DEPENDENCY_CYCLE));
+    let err = String::from_utf8_lossy(&o.stderr);
+    assert!(err.contains("Dependency cycle: "), "{err}");
+    assert!(!ws.path().join("repo").exists());
+}
+
+#[test]
+fn library_run() {
+    let ws = workspace();
+    let repo = ws.path().join("repo");
+
+    let matches = catkin_bloom::cli().get_matches_from([
+        "catkin-bloom".as_ref(),
+        "--dry-run".as_ref(),
+        "--repo-path".as_ref(),
+        repo.as_os_str(),
+        ws.path().as_os_str(),
+    ]);
+    let config = catkin_bloom::Config::default();
+    let args = catkin_bloom::RuntimeArgs::new(&matches, &config).unwrap();
+
+    let report = catkin_bloom::run(args).unwrap();
+    assert_eq!(report.total, 1);
+    assert_eq!(report.layers, [["foo"]]);
+    assert_eq!(report.packages["foo"].status, catkin_bloom::Status::Skipped);
+}
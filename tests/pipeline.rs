@@ -0,0 +1,553 @@
+use std::fs;
+use std::path::Path;
+
+use catkin_bloom::{mock_output, BloomError, Invocation, MockRunner, RunReport, Status};
+use tempfile::{tempdir, TempDir};
+
+fn workspace() -> TempDir {
+    let ws = tempdir().unwrap();
+    for (name, deps) in [("a", ""), ("b", "<build_depend>a</build_depend>")] {
+        let dir = ws.path().join("src").join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("package.xml"),
+            format!("<package format=\"3\"><name>{name}</name>{deps}</package>"),
+        )
+        .unwrap();
+    }
+    ws
+}
+
+/// Stands in for the tools driven by a run, producing the files they would.
+fn handle(call: &Invocation) -> std::process::Output {
+    match call.program() {
+        _ if call.args[1] == "--version" => mock_output(0, ""),
+        "bloom-generate" => {
+            let name = Path::new(call.args.last().unwrap()).file_name().unwrap();
+            let debian = call.dir.as_ref().unwrap().join("debian");
+            fs::create_dir_all(&debian).unwrap();
+            fs::write(
+                debian.join("rules"),
+                "override_dh_auto_configure:\n\tdh_auto_configure -- $(BUILD_TESTING_ARG)\n",
+            )
+            .unwrap();
+            fs::write(
+                debian.join("control"),
+                format!(
+                    "Source: ros-melodic-{0}\n\nPackage: ros-melodic-{0}\nDepends: libc6\n",
+                    name.to_str().unwrap()
+                ),
+            )
+            .unwrap();
+            fs::write(
+                debian.join("changelog"),
+                format!(
+                    "ros-melodic-{} (1.0.0-0bionic) bionic; urgency=high\n",
+                    name.to_str().unwrap()
+                ),
+            )
+            .unwrap();
+            mock_output(0, "")
+        }
+        "tar" => {
+            fs::write(call.dir.as_ref().unwrap().join(&call.args[2]), "").unwrap();
+            mock_output(0, "")
+        }
+        "dpkg-buildpackage" => {
+            let dir = call.dir.as_ref().unwrap();
+            let changelog = fs::read_to_string(dir.join("debian/changelog")).unwrap();
+            let source = changelog.split_once(' ').unwrap().0;
+            for suffix in [".dsc", ".debian.tar.xz", "_source.changes"] {
+                fs::write(
+                    dir.parent()
+                        .unwrap()
+                        .join(format!("{source}_1.0.0-0bionic{suffix}")),
+                    "",
+                )
+                .unwrap();
+            }
+            mock_output(0, "")
+        }
+        "dpkg-scansources" => mock_output(0, "Package: ros-melodic-a\n"),
+        "fakeroot" | "dpkg-architecture" => {
+            let dir = call.dir.as_ref().unwrap();
+            let control = fs::read_to_string(dir.join("debian/control")).unwrap();
+            let pkg = control
+                .lines()
+                .next()
+                .unwrap()
+                .trim_start_matches("Source: ");
+            fs::write(
+                dir.parent()
+                    .unwrap()
+                    .join(format!("{pkg}_1.0.0-0bionic_amd64.deb")),
+                "",
+            )
+            .unwrap();
+            mock_output(0, "")
+        }
+        "dpkg" if call.args[1] == "--print-architecture" => mock_output(0, "amd64\n"),
+        _ => mock_output(0, ""),
+    }
+}
+
+/// Build the workspace against the mock, with the repo and sources under the workspace.
+fn build(ws: &Path, args: &[&str]) -> (RunReport, Vec<Invocation>) {
+    let (report, calls) = build_with(ws, args, handle);
+    (report.unwrap(), calls)
+}
+
+fn build_with(
+    ws: &Path,
+    args: &[&str],
+    handler: impl Fn(&Invocation) -> std::process::Output + Send + Sync + 'static,
+) -> (anyhow::Result<RunReport>, Vec<Invocation>) {
+    let repo = ws.join("repo");
+    fs::create_dir_all(&repo).unwrap();
+
+    let matches = catkin_bloom::cli().get_matches_from(
+        [
+            "catkin-bloom".as_ref(),
+            "--repo-path".as_ref(),
+            repo.as_os_str(),
+        ]
+        .into_iter()
+        .chain(["--sources-dir".as_ref(), ws.join("etc").as_os_str()])
+        .chain(args.iter().map(|a| a.as_ref()))
+        .chain([ws.join("src").as_os_str()]),
+    );
+    let config = catkin_bloom::load_config(&matches).unwrap();
+    let args = catkin_bloom::RuntimeArgs::new(&matches, &config).unwrap();
+
+    let runner = MockRunner::new(handler);
+    let report = catkin_bloom::run_with(args, &runner);
+    (report, runner.calls())
+}
+
+#[test]
+fn build_workspace() {
+    let ws = workspace();
+    let repo = ws.path().join("repo");
+    let etc = ws.path().join("etc");
+
+    let (report, calls) = build(ws.path(), &[]);
+
+    // Packages get built and installed in dependency order
+    assert_eq!(report.layers, [["a"], ["b"]]);
+    let installs = calls
+        .iter()
+        .filter(|c| c.args[..2] == ["dpkg", "-i"])
+        .map(|c| c.args[2].clone())
+        .collect::<Vec<_>>();
+    let debs = ["a", "b"].map(|p| repo.join(format!("ros-melodic-{p}_1.0.0-0bionic_amd64.deb")));
+    assert_eq!(installs, debs.each_ref().map(|d| d.to_str().unwrap()));
+
+    // The built debs get copied into the repo
+    for (p, deb) in ["a", "b"].iter().zip(&debs) {
+        assert!(deb.is_file(), "{}", deb.display());
+        assert_eq!(report.packages[*p].status, Status::Built);
+        assert_eq!(report.packages[*p].files, std::slice::from_ref(deb));
+    }
+
+    // rosdep resolves the workspace packages to the built debs
+    let yaml = fs::read_to_string(repo.join("package.yaml")).unwrap();
+    assert!(
+        yaml.contains("a:\n  ubuntu:\n  - ros-melodic-a\n"),
+        "{yaml}"
+    );
+    assert!(
+        yaml.contains("b:\n  ubuntu:\n  - ros-melodic-b\n"),
+        "{yaml}"
+    );
+    assert!(calls.iter().any(|c| c.line() == "rosdep update"));
+
+    let list =
+        fs::read_to_string(etc.join("apt/sources.list.d/99-catkin-bloom-0-repo.list")).unwrap();
+    assert!(list.contains(&format!(
+        "file://{}",
+        repo.canonicalize().unwrap().display()
+    )));
+}
+
+#[test]
+fn cross_build() {
+    let ws = workspace();
+
+    let (_, calls) = build(
+        ws.path(),
+        &[
+            "--deb-arch",
+            "arm64",
+            "--deb-maintainer",
+            "Jane Doe <jane@example.com>",
+        ],
+    );
+
+    let builds = calls
+        .iter()
+        .filter(|c| c.program() == "dpkg-architecture" && c.args[1] != "--version")
+        .map(Invocation::line)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        builds,
+        ["dpkg-architecture -aarm64 -c fakeroot debian/rules binary"; 2]
+    );
+    assert!(!calls
+        .iter()
+        .any(|c| c.line() == "dpkg --print-architecture"));
+    assert!(ws
+        .path()
+        .join("repo/dists/bionic/main/binary-arm64/Packages")
+        .is_file());
+}
+
+#[test]
+fn only_generate() {
+    let ws = workspace();
+    let out = ws.path().join("out");
+
+    let (_, calls) = build(ws.path(), &["--only-generate", out.to_str().unwrap()]);
+
+    for p in ["a", "b"] {
+        let rules = fs::read_to_string(out.join(p).join("debian/rules")).unwrap();
+        let src = ws.path().join("src").join(p);
+        assert!(
+            rules.contains(&format!("dh_auto_configure -- {}", src.display())),
+            "{rules}"
+        );
+        assert!(out.join(p).join("debian/control").is_file());
+    }
+
+    // Nothing gets built, installed or indexed
+    for program in ["fakeroot", "dpkg-scanpackages", "apt-get"] {
+        assert!(!calls.iter().any(|c| c.program() == program), "{program}");
+    }
+    assert!(!calls.iter().any(|c| c.args[..2] == ["dpkg", "-i"]));
+}
+
+#[test]
+fn layer_install_apt() {
+    let ws = workspace();
+    let repo = ws.path().join("repo");
+
+    let (_, calls) = build(ws.path(), &["--layer-install", "apt"]);
+
+    let installs = calls
+        .iter()
+        .filter(|c| c.program() == "apt-get")
+        .filter_map(|c| c.args.last().filter(|a| a.ends_with(".deb")).cloned())
+        .collect::<Vec<_>>();
+    let debs = ["a", "b"].map(|p| repo.join(format!("ros-melodic-{p}_1.0.0-0bionic_amd64.deb")));
+    assert_eq!(installs, debs.each_ref().map(|d| d.to_str().unwrap()));
+    assert!(!calls.iter().any(|c| c.args[..2] == ["dpkg", "-i"]));
+}
+
+#[test]
+fn apt_snapshot() {
+    let ws = workspace();
+    let preferences = ws.path().join("etc/apt/preferences.d");
+
+    // The built packages win over the ones of the snapshot with the same names
+    build(ws.path(), &["--apt-snapshot", "2024-01-15"]);
+    assert_eq!(
+        fs::read_to_string(preferences.join("99-catkin-bloom")).unwrap(),
+        "Package: *\nPin: release o=catkin-bloom\nPin-Priority: 1002\n"
+    );
+    assert_eq!(
+        fs::read_to_string(preferences.join("99-catkin-bloom-snapshot")).unwrap(),
+        "Package: *\nPin: origin snapshots.ros.org\nPin-Priority: 1001\n"
+    );
+
+    build(
+        ws.path(),
+        &["--apt-snapshot", "2024-01-15", "--pin-priority", "1001"],
+    );
+    assert_eq!(
+        fs::read_to_string(preferences.join("99-catkin-bloom")).unwrap(),
+        "Package: *\nPin: release o=catkin-bloom\nPin-Priority: 1001\n"
+    );
+    assert_eq!(
+        fs::read_to_string(preferences.join("99-catkin-bloom-snapshot")).unwrap(),
+        "Package: *\nPin: origin snapshots.ros.org\nPin-Priority: 1000\n"
+    );
+}
+
+#[test]
+fn build_failure() {
+    let ws = workspace();
+
+    let (report, _) = build_with(ws.path(), &[], |call| {
+        if call.program() == "bloom-generate" && call.args.last().unwrap().ends_with("/b") {
+            mock_output(1, "")
+        } else {
+            handle(call)
+        }
+    });
+
+    // The error of the failed package comes through with its cause
+    let err = report.unwrap_err();
+    assert_eq!(err.to_string(), "Failed to build b in layer 1");
+    assert_eq!(err.root_cause().to_string(), "bloom-generate failed!");
+    let code = err.downcast_ref::<BloomError>().map(BloomError::exit_code);
+    assert_eq!(code, Some(catkin_bloom::EXIT_BUILD_FAILED));
+}
+
+#[test]
+fn source_packages() {
+    let ws = workspace();
+    let repo = ws.path().join("repo");
+
+    let (report, calls) = build(ws.path(), &["--artifacts", "both"]);
+
+    let files = report.packages["a"]
+        .files
+        .iter()
+        .map(|f| f.file_name().unwrap().to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        files,
+        [
+            "ros-melodic-a_1.0.0-0bionic.debian.tar.xz",
+            "ros-melodic-a_1.0.0-0bionic.dsc",
+            "ros-melodic-a_1.0.0-0bionic_source.changes",
+            "ros-melodic-a_1.0.0.orig.tar.gz",
+            "ros-melodic-a_1.0.0-0bionic_amd64.deb",
+        ]
+    );
+
+    // Only the debs get installed
+    let installs = calls
+        .iter()
+        .filter(|c| c.args[..2] == ["dpkg", "-i"])
+        .map(|c| c.args[2..].len())
+        .collect::<Vec<_>>();
+    assert_eq!(installs, [1, 1]);
+
+    assert!(repo.join("ros-melodic-b_1.0.0-0bionic.dsc").is_file());
+    let suite = repo.join("dists/bionic");
+    assert_eq!(
+        fs::read_to_string(suite.join("main/source/Sources")).unwrap(),
+        "Package: ros-melodic-a\n"
+    );
+    let release = fs::read_to_string(suite.join("Release")).unwrap();
+    assert!(release.contains(" main/source/Sources\n"), "{release}");
+}
+
+#[test]
+fn package_env() {
+    let ws = workspace();
+    fs::write(
+        ws.path().join("src/catkin-bloom.toml"),
+        "[env]\nFOO = \"all\"\nBAR = \"all\"\n\n[package-env.b]\nBAR = \"b\"\n",
+    )
+    .unwrap();
+
+    let (_, calls) = build(ws.path(), &[]);
+
+    let envs = calls
+        .iter()
+        .filter(|c| c.program() == "fakeroot" && c.args[1] != "--version")
+        .map(|c| {
+            let var = |name: &str| {
+                let (_, v) = c.envs.iter().rfind(|(k, _)| k == name).unwrap();
+                v.as_str()
+            };
+            (var("FOO"), var("BAR"))
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(envs, [("all", "all"), ("all", "b")]);
+}
+
+#[test]
+fn skip_if_upstream_matches() {
+    let ws = workspace();
+    for p in ["a", "b"] {
+        let xml = ws.path().join("src").join(p).join("package.xml");
+        let contents = fs::read_to_string(&xml).unwrap();
+        fs::write(
+            &xml,
+            contents.replace("</name>", "</name><version>1.0.0</version>"),
+        )
+        .unwrap();
+    }
+
+    let upstream = |modified: &'static str| {
+        move |call: &Invocation| match call.program() {
+            "apt-cache" => mock_output(
+                0,
+                "ros-melodic-a:\n  Installed: (none)\n  Candidate: 1.0.0-1bionic.20200101\n\
+                 ros-melodic-b:\n  Installed: (none)\n  Candidate: 0.9.0-1bionic.20200101\n",
+            ),
+            "git" if call.args[1] == "ls-files" => mock_output(0, modified),
+            // Like rosdep, resolve a to the upstream package unless its sources are given
+            "rosdep" if call.args[1] == "check" => {
+                if call.args.iter().any(|a| a.ends_with("/src/a")) {
+                    mock_output(0, "")
+                } else {
+                    mock_output(0, "apt\tros-melodic-a\n")
+                }
+            }
+            _ => handle(call),
+        }
+    };
+
+    // Only the package with a newer version gets built, on top of the released one
+    let (report, calls) = build_with(ws.path(), &["--skip-if-upstream-matches"], upstream(""));
+    let report = report.unwrap();
+    assert_eq!(report.packages["a"].status, Status::Skipped);
+    assert_eq!(report.packages["b"].status, Status::Built);
+    let install = calls
+        .iter()
+        .position(|c| c.program() == "apt-get" && c.args.contains(&"ros-melodic-a".into()))
+        .unwrap();
+    let build = calls
+        .iter()
+        .position(|c| c.program() == "bloom-generate" && c.args.last().unwrap().ends_with("/src/b"))
+        .unwrap();
+    assert!(install < build);
+
+    // Local changes get built, along with everything on top of them
+    let (report, _) = build_with(
+        ws.path(),
+        &["--skip-if-upstream-matches"],
+        upstream("a/new.cpp\n"),
+    );
+    let report = report.unwrap();
+    assert_eq!(report.packages["a"].status, Status::Built);
+    assert_eq!(report.packages["b"].status, Status::Built);
+}
+
+#[test]
+fn time_budget() {
+    let ws = workspace();
+
+    let (report, calls) = build(ws.path(), &["--time-budget", "0"]);
+
+    // Nothing gets started, but the repo still gets indexed
+    assert!(report.budget_exceeded);
+    assert_eq!(report.packages["a"].status, Status::Skipped);
+    assert_eq!(report.packages["b"].status, Status::Skipped);
+    assert!(calls
+        .iter()
+        .any(|c| c.program() == "dpkg-scanpackages" && c.args[1] != "--version"));
+}
+
+#[test]
+fn deb_dir() {
+    let ws = workspace();
+    let debs = ws.path().join("debs");
+
+    let (report, calls) = build(ws.path(), &["--deb-dir", debs.to_str().unwrap()]);
+
+    let deb = debs.join("ros-melodic-a_1.0.0-0bionic_amd64.deb");
+    assert_eq!(report.packages["a"].files, std::slice::from_ref(&deb));
+    assert!(deb.is_file());
+    assert!(!ws
+        .path()
+        .join("repo/ros-melodic-a_1.0.0-0bionic_amd64.deb")
+        .exists());
+
+    // The index in the repo refers to the packages outside of it
+    let scan = calls
+        .iter()
+        .find(|c| c.program() == "dpkg-scanpackages" && c.args[1] != "--version")
+        .unwrap();
+    assert_eq!(scan.line(), "dpkg-scanpackages -m ../debs");
+    assert_eq!(scan.dir.as_deref(), Some(ws.path().join("repo").as_path()));
+    assert!(ws.path().join("repo/package.yaml").is_file());
+}
+
+#[test]
+fn removed_package() {
+    let ws = workspace();
+    let src = ws.path().join("src/a");
+    fs::create_dir_all(ws.path().join("src/c")).unwrap();
+    fs::write(
+        ws.path().join("src/c/package.xml"),
+        "<package format=\"3\"><name>c</name></package>",
+    )
+    .unwrap();
+
+    // The package goes away after the workspace got scanned
+    let (report, _) = build_with(ws.path(), &["--keep-going"], move |call| {
+        if call.line() == "rosdep update" {
+            fs::remove_dir_all(&src).unwrap();
+        }
+        handle(call)
+    });
+
+    let err = report.unwrap_err();
+    assert!(
+        format!("{err:#}").contains("/src/a no longer exists"),
+        "{err:#}"
+    );
+
+    // The rest of the layer still gets built
+    assert!(ws
+        .path()
+        .join("repo/ros-melodic-c_1.0.0-0bionic_amd64.deb")
+        .is_file());
+}
+
+#[test]
+fn no_rosdep_update() {
+    let ws = workspace();
+
+    let (_, calls) = build(ws.path(), &["--no-rosdep-update"]);
+
+    assert!(!calls.iter().any(|c| c.line() == "rosdep update"));
+    // The workspace packages still get registered with rosdep
+    assert!(ws.path().join("repo/package.yaml").is_file());
+    assert!(ws
+        .path()
+        .join("etc/ros/rosdep/sources.list.d")
+        .read_dir()
+        .unwrap()
+        .next()
+        .is_some());
+}
+
+#[test]
+fn skip_test_depends() {
+    let ws = workspace();
+    fs::write(
+        ws.path().join("src/b/package.xml"),
+        "<package format=\"3\"><name>b</name><test_depend>a</test_depend>\
+         <test_depend>c</test_depend></package>",
+    )
+    .unwrap();
+    let c = ws.path().join("src/c");
+    fs::create_dir_all(&c).unwrap();
+    fs::write(
+        c.join("package.xml"),
+        "<package format=\"3\"><name>c</name></package>",
+    )
+    .unwrap();
+
+    let manifests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let seen = manifests.clone();
+    let (report, _) = build_with(
+        ws.path(),
+        &["--skip-test-depends", "--strict-deps", "--ignore-pkgs=c"],
+        move |call| {
+            if call.program() == "bloom-generate" && call.args[1] != "--version" {
+                let dir = Path::new(call.args.last().unwrap());
+                seen.lock()
+                    .unwrap()
+                    .push(fs::read_to_string(dir.join("package.xml")).unwrap());
+            }
+            handle(call)
+        },
+    );
+
+    // The test dependencies neither order the packages nor break on ignored packages
+    assert_eq!(report.unwrap().layers, [["a", "b"]]);
+
+    // Nor do they end up in the generated metadata
+    let manifests = manifests.lock().unwrap();
+    assert_eq!(manifests.len(), 2);
+    assert!(
+        manifests.iter().all(|m| !m.contains("test_depend")),
+        "{manifests:?}"
+    );
+}